@@ -0,0 +1,470 @@
+//!Command line arguments, and how they override the config file
+use crate::cloud_client::BackendOptions;
+use crate::common::LOG as log;
+use crate::config::Config;
+use crate::drive_cli::DriveOptions;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::s3_cli::S3Options;
+use crate::sftp_cli::SftpOptions;
+use crate::throttle::Bandwidth;
+use crate::webdav_cli::WebDavOptions;
+use clap::{App, Arg, ArgMatches, SubCommand};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::sync::Arc;
+
+///Exit codes, so cron and systemd can tell what went wrong
+pub const EXIT_OK: i32 = 0;
+///Anything not covered below
+pub const EXIT_FAILED: i32 = 1;
+///Bad config or arguments, or something the backend does not support
+pub const EXIT_CONFIG: i32 = 2;
+///No credentials, or the backend turned them down
+pub const EXIT_AUTH: i32 = 3;
+///The backend could not be reached or failed a call
+pub const EXIT_BACKEND: i32 = 4;
+pub const EXIT_STATE_DB: i32 = 5;
+///Finished, but some files are not on the backend yet
+pub const EXIT_INCOMPLETE: i32 = 6;
+
+pub fn exit_code(e: &SyncerErrors) -> i32 {
+    match e {
+        SyncerErrors::InvalidConfig | SyncerErrors::UnknownBackend | SyncerErrors::NotSupported => {
+            EXIT_CONFIG
+        }
+        SyncerErrors::NoAppSecret => EXIT_AUTH,
+        SyncerErrors::ProviderError => EXIT_BACKEND,
+        SyncerErrors::StateDbError => EXIT_STATE_DB,
+        SyncerErrors::InvalidPathError | SyncerErrors::SyncerNoneError => EXIT_FAILED,
+    }
+}
+
+pub fn app() -> App<'static, 'static> {
+    App::new("Rusty Cam Syncer")
+        .version("1.0")
+        .author("Alan R. <alan@alanryan.name>")
+        .version("1.0")
+        .about("Will Sync a Dir recursvively with the smarts of a sheep")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("config")
+                .help("TOML config file, the options below override it")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("watch_dir")
+                .short("w")
+                .long("watch_dir")
+                .value_name("watch_dir")
+                .help("Local dir to watch, defaults to /var/www/RpiCamera, replaces any [[roots]] in the config")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("remote_root")
+                .short("r")
+                .long("remote_root")
+                .value_name("remote_root")
+                .help("What the watched dir is called on the backend, defaults to RpiCamera/RpiCamera, the base of every pi_sync_id")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("token_file")
+                .long("token_file")
+                .value_name("token_file")
+                .help("Where Google Drive OAuth tokens are cached")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state_db")
+                .long("state_db")
+                .value_name("state_db")
+                .help("SQLite file recording what has been synced, defaults to /var/lib/pi_drive_sync/state.db")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("rescan_secs")
+                .long("rescan_secs")
+                .value_name("rescan_secs")
+                .help("Also look for files the watcher missed this often, not just at startup")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("quiet_secs")
+                .long("quiet_secs")
+                .value_name("quiet_secs")
+                .help("Upload new files once unchanged this long, or when closed, default 5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry_run")
+                .long("dry_run")
+                .visible_alias("dry-run")
+                .global(true)
+                .help("Log the uploads, folders, moves and removals a run would make, changing nothing"),
+        )
+        .arg(
+            Arg::with_name("on_delete")
+                .long("on_delete")
+                .value_name("on_delete")
+                .help("What a local delete does remotely: ignore, trash or delete, default ignore")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("secret_file")
+                .short("s")
+                .long("secret_file")
+                .value_name("secret_file")
+                .help("Where to find Google Drive API JSON secrets")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drive_auth")
+                .long("drive_auth")
+                .value_name("drive_auth")
+                .help("How Drive tokens are got: installed, service_account or device_code, default installed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drive_api_url")
+                .long("drive_api_url")
+                .value_name("drive_api_url")
+                .help("Drive API root, defaults to https://www.googleapis.com/")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drive_token_uri")
+                .long("drive_token_uri")
+                .value_name("drive_token_uri")
+                .help("OAuth token endpoint, defaults to the token_uri in the secret file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("drive_device_code_uri")
+                .long("drive_device_code_uri")
+                .value_name("drive_device_code_uri")
+                .help("OAuth device authorization endpoint for drive_auth = \"device_code\", defaults to Google's")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backend")
+                .short("b")
+                .long("backend")
+                .value_name("backend")
+                .help("Where to sync to: drive, local, s3, webdav or sftp")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("target_dir")
+                .short("t")
+                .long("target_dir")
+                .value_name("target_dir")
+                .help("Directory the local backend copies files into, e.g. a NAS mount")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_endpoint")
+                .long("s3_endpoint")
+                .value_name("s3_endpoint")
+                .help("S3 compatible endpoint, e.g. http://localhost:9000 for MinIO")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_bucket")
+                .long("s3_bucket")
+                .value_name("s3_bucket")
+                .help("Bucket the s3 backend uploads into, keys are RpiCamera/...")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_region")
+                .long("s3_region")
+                .value_name("s3_region")
+                .help("Region to sign s3 requests for, defaults to us-east-1")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("s3_part_size_mb")
+                .long("s3_part_size_mb")
+                .value_name("s3_part_size_mb")
+                .help("Files bigger than this many MiB use multipart uploads, default 16, min 5")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("webdav_url")
+                .long("webdav_url")
+                .value_name("webdav_url")
+                .help("WebDAV root, e.g. https://host/remote.php/dav/files/<user>/ for Nextcloud")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("webdav_user")
+                .long("webdav_user")
+                .value_name("webdav_user")
+                .help("WebDAV user, the password is read from WEBDAV_PASSWORD")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sftp_host")
+                .long("sftp_host")
+                .value_name("sftp_host")
+                .help("SSH host the sftp backend pushes to, as host or host:port")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sftp_user")
+                .long("sftp_user")
+                .value_name("sftp_user")
+                .help("SSH user, defaults to pi")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sftp_key_file")
+                .long("sftp_key_file")
+                .value_name("sftp_key_file")
+                .help("Private key to log in with, a passphrase is read from SFTP_KEY_PASSPHRASE")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sftp_remote_root")
+                .long("sftp_remote_root")
+                .value_name("sftp_remote_root")
+                .help("Dir on the SSH host the RpiCamera tree is created in")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("regexp_filter")
+                .short("f")
+                .long("regexp_filter")
+                .value_name("regexp_filter")
+                .help("Command separated list of filters, e.g. ^im.*jpg$ or ^vi.*mp4$ ")
+                .takes_value(true),
+        )
+        .subcommand(
+            SubCommand::with_name("daemon")
+                .about("Watch the roots and sync changes until stopped, the default"),
+        )
+        .subcommand(
+            SubCommand::with_name("sync-once")
+                .about("Upload what the backend is missing and whatever retries are due, then exit. Exits 6 if anything was not uploaded"),
+        )
+        .subcommand(
+            SubCommand::with_name("status")
+                .about("Show what is synced and queued. Exits 6 if any upload is dead lettered")
+                .arg(
+                    Arg::with_name("dead_letters")
+                        .long("dead_letters")
+                        .help("List the uploads that ran out of retries too"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("requeue")
+                .about("Give a dead lettered upload, or all of them, fresh retries")
+                .arg(
+                    Arg::with_name("path")
+                        .value_name("path|all")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("ls-remote")
+                .about("List the synced files the backend holds"),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Compare the roots with the backend. Exits 6 if they differ")
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the report as JSON rather than a table"),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Upload again the files missing from the backend or not matching it"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("auth")
+                .about("Log in if there is no token yet, then check the backend accepts us"),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Trash what remote_retention says should go from the backend")
+        )
+}
+
+///The value of a numeric argument, InvalidConfig if it is there but not a number
+fn parsed<T>(matches: &ArgMatches, name: &str) -> PiSyncResult<Option<T>>
+where
+    T: FromStr,
+    T::Err: Display,
+{
+    match matches.value_of(name) {
+        Some(value) => value.parse().map(Some).map_err(|e| {
+            error!(log, "{} {}: {}", name, value, e);
+            SyncerErrors::InvalidConfig
+        }),
+        None => Ok(None),
+    }
+}
+
+///The config file, if any, with the command line options applied over it
+pub fn config(matches: &ArgMatches) -> PiSyncResult<Config> {
+    let mut config = match matches.value_of("config") {
+        Some(path) => match Config::load(std::path::Path::new(path)) {
+            Ok(config) => config,
+            Err(e) => {
+                error!(log, "Config {}: {}", path, e);
+                return Err(e);
+            }
+        },
+        None => Config::default(),
+    };
+    if let Some(watch_dir) = matches.value_of("watch_dir") {
+        config.watch_dir = watch_dir.to_owned();
+        config.roots.clear();
+    }
+    if let Some(remote_root) = matches.value_of("remote_root") {
+        config.remote_root = remote_root.to_owned();
+    }
+    if let Some(filters) = matches.value_of("regexp_filter") {
+        config.filters = filters
+            .split(',')
+            .filter(|f| !f.is_empty())
+            .map(|f| f.to_owned())
+            .collect();
+        config.check_filters()?;
+    }
+    if let Some(secret_file) = matches.value_of("secret_file") {
+        config.secret_file = secret_file.to_owned();
+    }
+    if let Some(token_file) = matches.value_of("token_file") {
+        config.token_file = token_file.to_owned();
+    }
+    if let Some(state_db) = matches.value_of("state_db") {
+        config.state_db = state_db.to_owned();
+    }
+    if let Some(secs) = parsed(matches, "rescan_secs")? {
+        config.rescan_secs = Some(secs);
+    }
+    if let Some(on_delete) = matches.value_of("on_delete") {
+        match on_delete.parse() {
+            Ok(policy) => config.on_delete = policy,
+            Err(e) => {
+                error!(log, "on_delete {}: {}", on_delete, e);
+                return Err(e);
+            }
+        }
+    }
+    if let Some(drive_auth) = matches.value_of("drive_auth") {
+        match drive_auth.parse() {
+            Ok(flow) => config.drive_auth = flow,
+            Err(e) => {
+                error!(log, "drive_auth {}: {}", drive_auth, e);
+                return Err(e);
+            }
+        }
+    }
+    if let Some(secs) = parsed(matches, "quiet_secs")? {
+        config.quiet_secs = secs;
+    }
+    if matches.is_present("dry_run") {
+        config.dry_run = true;
+    }
+    debug!(log, "Using config {:?}", config);
+    Ok(config)
+}
+
+///Everything the backend selected on the command line needs
+pub fn backend_options<'a>(
+    matches: &'a ArgMatches,
+    config: &'a Config,
+    bandwidth: &Arc<Bandwidth>,
+) -> PiSyncResult<BackendOptions<'a>> {
+    let drive_options = DriveOptions::new(
+        config.auth_options(),
+        matches.value_of("drive_api_url").map(|u| u.to_owned()),
+        matches.value_of("drive_token_uri").map(|u| u.to_owned()),
+        matches
+            .value_of("drive_device_code_uri")
+            .map(|u| u.to_owned()),
+        Arc::clone(bandwidth),
+    );
+
+    let backend_kind = matches
+        .value_of("backend")
+        .unwrap_or("drive")
+        .parse()
+        .map_err(|e| {
+            error!(log, "Backend {:?}: {}", matches.value_of("backend"), e);
+            e
+        })?;
+
+    let s3_options = match (
+        matches.value_of("s3_endpoint"),
+        matches.value_of("s3_bucket"),
+    ) {
+        (Some(endpoint), Some(bucket)) => Some(S3Options::new(
+            endpoint.to_owned(),
+            bucket.to_owned(),
+            matches
+                .value_of("s3_region")
+                .unwrap_or("us-east-1")
+                .to_owned(),
+            parsed(matches, "s3_part_size_mb")?,
+            Arc::clone(bandwidth),
+        )),
+        _ => None,
+    };
+
+    let webdav_options = matches.value_of("webdav_url").map(|url| {
+        WebDavOptions::new(
+            url.to_owned(),
+            matches.value_of("webdav_user").unwrap_or("").to_owned(),
+            Arc::clone(bandwidth),
+        )
+    });
+
+    let home = std::env::var("HOME").unwrap_or_default();
+    //host or host:port
+    let sftp_host = match matches.value_of("sftp_host") {
+        Some(host_port) => {
+            let (host, port) = host_port.split_once(':').unwrap_or((host_port, "22"));
+            let port = port.parse().map_err(|e| {
+                error!(log, "sftp_host port {}: {}", port, e);
+                SyncerErrors::InvalidConfig
+            })?;
+            Some((host, port))
+        }
+        None => None,
+    };
+    let sftp_options = sftp_host.map(|(host, port)| {
+        SftpOptions::new(
+            host.to_owned(),
+            port,
+            matches.value_of("sftp_user").unwrap_or("pi").to_owned(),
+            matches
+                .value_of("sftp_key_file")
+                .map(|k| k.to_owned())
+                .unwrap_or(format!("{}/.ssh/id_rsa", home)),
+            matches
+                .value_of("sftp_remote_root")
+                .unwrap_or(".")
+                .to_owned(),
+            format!("{}/.ssh/known_hosts", home),
+            Arc::clone(bandwidth),
+        )
+    });
+
+    Ok(BackendOptions::new(
+        backend_kind,
+        config,
+        Some(drive_options),
+        matches.value_of("target_dir"),
+        s3_options,
+        webdav_options,
+        sftp_options,
+    ))
+}
@@ -0,0 +1,194 @@
+use crate::common::LOG as log;
+use crate::config::Config;
+use crate::drive_cli::{Drive3Client, DriveOptions};
+use crate::dry_run::DryRun;
+use crate::local_backend::LocalDirBackend;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::s3_cli::{S3Client, S3Options};
+use crate::sftp_cli::{SftpClient, SftpOptions};
+use crate::state_db::StateDb;
+use crate::webdav_cli::{WebDavClient, WebDavOptions};
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+///Where backends without a trash of their own keep trashed files, under their root
+pub const TRASH_DIR: &str = ".pi_sync_trash";
+
+///The operations every storage backend must support, all paths are local fs paths
+///and are mapped to the remote side via SyncableFile::cloud_path. Each upload worker has its own
+pub trait CloudClient: Send {
+    ///Upload a local file, building any missing parent folders, returning the backend's id for it
+    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>>;
+    ///Create the remote folder for a local dir, under parent_id if the backend uses ids
+    fn create_dir(
+        &self,
+        local_fs_path: &str,
+        parent_id: Option<&str>,
+    ) -> PiSyncResult<Option<String>>;
+    ///Look up the backend id for a local path, None if it does not exist remotely
+    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>>; //should this be cloud path
+    ///Remove what a local path was synced to, a folder along with everything in it. With trash
+    ///it is kept recoverable, in the backend's own trash or a .pi_sync_trash folder
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()>;
+    ///Move what from was synced to so it matches to, a folder along with everything in it, and
+    ///return its new id. None if from was never synced
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>>;
+    ///The md5 of what the backend holds for a local path, to confirm an upload before the local
+    ///copy is removed. None if it is not there, or the backend cannot tell us
+    fn remote_md5(&self, _local_path: &str) -> PiSyncResult<Option<String>> {
+        Ok(None)
+    }
+    ///Every file we synced that the backend holds under the remote roots, folders left out
+    fn list_remote(&self) -> PiSyncResult<Vec<RemoteFile>> {
+        Err(SyncerErrors::NotSupported)
+    }
+    ///Trash a file by its backend id. Our record of it is kept, so it is not uploaded again
+    fn trash_remote(&self, _remote_id: &str) -> PiSyncResult<()> {
+        Err(SyncerErrors::NotSupported)
+    }
+    ///Is the backend configured and usable
+    fn check_ready(&self) -> PiSyncResult<()>;
+    ///A cheap call over the network, to tell whether the backend can be reached right now
+    fn probe(&self) -> PiSyncResult<()> {
+        self.check_ready()
+    }
+}
+
+///A file as the backend holds it
+#[derive(new, Debug, Clone, PartialEq)]
+pub struct RemoteFile {
+    ///The backend's id, as in the state db
+    pub remote_id: String,
+    pub pi_sync_id: String,
+    ///Where it is under the remote roots, e.g. RpiCamera/a/im1.jpg
+    pub cloud_path: PathBuf,
+    pub size: u64,
+    ///Hex md5, None if the backend does not keep one
+    pub md5: Option<String>,
+    ///When it was uploaded
+    pub created: DateTime<Utc>,
+}
+
+///The storage backends main can be configured with
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum BackendKind {
+    Drive,
+    LocalDir,
+    S3,
+    WebDav,
+    Sftp,
+}
+
+impl FromStr for BackendKind {
+    type Err = SyncerErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drive" => Ok(BackendKind::Drive),
+            "local" => Ok(BackendKind::LocalDir),
+            "s3" => Ok(BackendKind::S3),
+            "webdav" => Ok(BackendKind::WebDav),
+            "sftp" => Ok(BackendKind::Sftp),
+            _ => Err(SyncerErrors::UnknownBackend),
+        }
+    }
+}
+
+///What to do on the backend when a synced file or folder is deleted locally
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum DeletePolicy {
+    ///Leave the remote copy alone
+    Ignore,
+    Trash,
+    Delete,
+}
+
+impl FromStr for DeletePolicy {
+    type Err = SyncerErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ignore" => Ok(DeletePolicy::Ignore),
+            "trash" => Ok(DeletePolicy::Trash),
+            "delete" => Ok(DeletePolicy::Delete),
+            _ => Err(SyncerErrors::InvalidConfig),
+        }
+    }
+}
+
+///Everything needed to build any of the backends
+#[derive(new, Clone)]
+pub struct BackendOptions<'a> {
+    kind: BackendKind,
+    ///Supplies the root mappings
+    config: &'a Config,
+    drive: Option<DriveOptions>,
+    target_dir: Option<&'a str>,
+    s3: Option<S3Options>,
+    webdav: Option<WebDavOptions>,
+    sftp: Option<SftpOptions>,
+}
+
+///Build the backend selected at runtime, recording what it syncs in state. With dry_run it only
+///logs what it would change
+pub fn new_backend(
+    opts: BackendOptions,
+    state: Arc<StateDb>,
+) -> PiSyncResult<Box<dyn CloudClient>> {
+    debug!(log, "Using {:?} backend", opts.kind);
+    let roots = opts.config.roots();
+    let client: Box<dyn CloudClient> = match opts.kind {
+        BackendKind::Drive => {
+            let drive = opts.drive.ok_or(SyncerErrors::SyncerNoneError)?;
+            Box::new(Drive3Client::new(drive, roots.clone(), state))
+        }
+        BackendKind::LocalDir => {
+            let target_dir = opts.target_dir.ok_or(SyncerErrors::SyncerNoneError)?;
+            Box::new(LocalDirBackend::new(target_dir, roots.clone(), state))
+        }
+        BackendKind::S3 => {
+            let s3 = opts.s3.ok_or(SyncerErrors::SyncerNoneError)?;
+            Box::new(S3Client::new(s3, roots.clone(), state)?)
+        }
+        BackendKind::WebDav => {
+            let webdav = opts.webdav.ok_or(SyncerErrors::SyncerNoneError)?;
+            Box::new(WebDavClient::new(webdav, roots.clone(), state))
+        }
+        BackendKind::Sftp => {
+            let sftp = opts.sftp.ok_or(SyncerErrors::SyncerNoneError)?;
+            Box::new(SftpClient::new(sftp, roots.clone(), state))
+        }
+    };
+    if opts.config.dry_run {
+        Ok(Box::new(DryRun::new(client, roots)))
+    } else {
+        Ok(client)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_client::*;
+
+    #[test]
+    fn test_backend_kind_from_str() {
+        assert_eq!(BackendKind::Drive, "drive".parse().unwrap());
+        assert_eq!(BackendKind::LocalDir, "local".parse().unwrap());
+        assert_eq!(BackendKind::S3, "s3".parse().unwrap());
+        assert_eq!(BackendKind::WebDav, "webdav".parse().unwrap());
+        assert_eq!(BackendKind::Sftp, "sftp".parse().unwrap());
+        assert!("s4".parse::<BackendKind>().is_err());
+    }
+
+    #[test]
+    fn test_delete_policy_from_str() {
+        assert_eq!(DeletePolicy::Ignore, "ignore".parse().unwrap());
+        assert_eq!(DeletePolicy::Trash, "trash".parse().unwrap());
+        assert_eq!(DeletePolicy::Delete, "delete".parse().unwrap());
+        assert!("shred".parse::<DeletePolicy>().is_err());
+    }
+}
@@ -1,9 +1,13 @@
 use slog::Drain;
 use slog::*;
+use std::sync::Mutex;
 
 lazy_static::lazy_static! {
     ///Configure the global logger
-    pub static ref LOG :Logger = {
+    pub static ref LOG :Logger = ASYNC_LOG.0.clone();
+
+    ///The logger and what flushes it, statics are never dropped so flush_log has to
+    static ref ASYNC_LOG :(Logger, Mutex<Option<slog_async::AsyncGuard>>) = {
 
         let debug = "debug".to_string();
         let info = "info".to_string();
@@ -37,15 +41,34 @@ lazy_static::lazy_static! {
 
         let decorator = slog_term::TermDecorator::new().build();
         let drain = slog_term::FullFormat::new(decorator).build();
-        let drain = slog_async::Async::new( LevelFilter::new(drain, log_level).fuse() ).build().fuse(); //Lossy logger - chanel size dependent, will warn and drop
+        let (drain, guard) = slog_async::Async::new( LevelFilter::new(drain, log_level).fuse() ).build_with_guard(); //Lossy logger - chanel size dependent, will warn and drop
 
-        slog::Logger::root(
-            drain,
+        (slog::Logger::root(
+            drain.ignore_res(),
             o!(
                 "version" => env!("CARGO_PKG_VERSION"),
                 "service" => env!("CARGO_PKG_NAME")
 
-            ))
+            )), Mutex::new(Some(guard)))
     };
 
 }
+
+///Write out whatever is still queued for the log, before the process exits. Anything logged
+///after this is lost
+pub fn flush_log() {
+    ASYNC_LOG.1.lock().unwrap().take();
+}
+
+///Percent encode everything but the unreserved characters, and '/' too unless it is a key path
+pub fn uri_encode(s: &str, encode_slash: bool) -> String {
+    s.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            b'/' if !encode_slash => "/".to_owned(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
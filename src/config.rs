@@ -0,0 +1,465 @@
+use crate::cloud_client::DeletePolicy;
+use crate::common::LOG as log;
+use crate::disk_space::Watermarks;
+use crate::drive_auth::{AuthFlow, AuthOptions};
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::remote_retention::{Pruner, RemoteRetention};
+use crate::retention::{AfterUpload, RetentionPolicy};
+use crate::retry_queue::RetryPolicy;
+use crate::throttle::{RateSchedule, RateWindow};
+use crate::upload_handler::{RootMapping, Roots};
+use regex::Regex;
+use serde::Deserialize;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+///Settings read from a TOML file, anything left out takes the default, e.g.
+///
+///secret_file = "/etc/pi_drive_sync/drive3-secret.json"
+///token_file = "/var/lib/pi_drive_sync/token.json"
+///drive_auth = "service_account"
+///service_account_key = "/etc/pi_drive_sync/service-account.json"
+///impersonate = "camera@example.com"
+///state_db = "/var/lib/pi_drive_sync/state.db"
+///rescan_secs = 3600
+///quiet_secs = 10
+///on_delete = "trash"
+///max_attempts = 10
+///retry_secs = 60
+///max_retry_secs = 7200
+///probe_secs = 60
+///max_queued = 50000
+///workers = 4
+///upload_kib_per_sec = 256
+///after_upload = "archive"
+///archive_dir = "/mnt/usb/archive"
+///keep_days = 7
+///verify_md5 = true
+///disk_high_pct = 90
+///disk_low_pct = 75
+///
+///[remote_retention]
+///max_age_days = 30
+///max_total_mb = 10000
+///
+///[[remote_retention.rules]]
+///filter = "^im.*jpg$"
+///max_age_days = 90
+///
+///[[remote_retention.rules]]
+///filter = "^vi.*mp4$"
+///max_age_days = 14
+///
+///[[rate_windows]]
+///from = "00:00"
+///to = "06:00"
+///
+///[[roots]]
+///watch_dir = "/var/www/media"
+///remote_root = "RpiCamera"
+///filters = ["^im.*jpg$", "^vi.*mp4$"]
+///
+///[[roots]]
+///watch_dir = "/var/log/motion"
+///remote_root = "logs/motion"
+///
+///With no [[roots]] the top level watch_dir, remote_root and filters are the only root
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    ///Local dir watched for new files
+    pub watch_dir: String,
+    ///What watch_dir is called on the backend, a folder on Drive or a key prefix on S3. Every
+    ///pi_sync_id is based on it, so changing it uploads everything again. The default keeps the
+    ///ids from before there was a config, when /var/www mapped onto RpiCamera
+    pub remote_root: String,
+    ///Regexes file names must match, empty means everything
+    pub filters: Vec<String>,
+    ///Several dirs to watch, each with its own remote folder and filters
+    pub roots: Vec<RootConfig>,
+    ///Google Drive API JSON secrets
+    pub secret_file: String,
+    ///Where Drive OAuth tokens are cached
+    pub token_file: String,
+    ///How Drive tokens are got, installed, service_account or device_code
+    pub drive_auth: AuthFlow,
+    ///Key JSON for drive_auth = "service_account"
+    pub service_account_key: Option<String>,
+    ///The user a service account acts as, by domain-wide delegation
+    pub impersonate: Option<String>,
+    ///SQLite db of what has been synced, and the backend id of each file and folder
+    pub state_db: String,
+    ///Walk the roots for anything the watcher missed this often, as well as at startup
+    pub rescan_secs: Option<u64>,
+    ///How long a new file must go unchanged before it is uploaded, unless it is closed first
+    pub quiet_secs: u64,
+    ///Mirror local deletions as ignore, trash or delete
+    pub on_delete: DeletePolicy,
+    ///Tries at an upload before it is dead lettered
+    pub max_attempts: u32,
+    ///Wait before the first retry of a failed upload, doubling for each one after
+    pub retry_secs: u64,
+    ///Longest wait between retries
+    pub max_retry_secs: u64,
+    ///How often to check whether an unreachable backend is back
+    pub probe_secs: u64,
+    ///Most uploads held in the queue, e.g. during an outage. Past that they wait for a scan
+    pub max_queued: usize,
+    ///Uploads run at once, each worker has its own connection to the backend
+    pub workers: usize,
+    ///Cap on all uploads together, None for no limit
+    pub upload_kib_per_sec: Option<u64>,
+    ///Times of day with their own cap, e.g. none overnight
+    pub rate_windows: Vec<RateWindow>,
+    ///What becomes of a local file once it is confirmed uploaded, keep, delete or archive
+    pub after_upload: AfterUpload,
+    ///Where archived files go, outside every watched dir
+    pub archive_dir: Option<String>,
+    ///Days an uploaded file stays where it is before it is deleted or archived
+    pub keep_days: Option<u64>,
+    ///Check the backend's md5 before a local file is removed, not just our own records
+    pub verify_md5: bool,
+    ///Evict synced files once the disk holding a watch dir is fuller than this percent
+    pub disk_high_pct: Option<u8>,
+    ///Evict until the disk is this full, 10 under disk_high_pct if left out
+    pub disk_low_pct: Option<u8>,
+    ///Trash old files on the backend, see remote_retention
+    pub remote_retention: Option<RemoteRetention>,
+    ///Go through everything and log what would be done, changing nothing on the backend or disk
+    pub dry_run: bool,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RootConfig {
+    pub watch_dir: String,
+    pub remote_root: String,
+    #[serde(default)]
+    pub filters: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            watch_dir: "/var/www/RpiCamera".to_owned(),
+            remote_root: "RpiCamera/RpiCamera".to_owned(),
+            filters: vec![],
+            roots: vec![],
+            secret_file: "/home/alan/.google-service-cli/drive3-secret.json".to_owned(),
+            token_file: "temp_token".to_owned(),
+            drive_auth: AuthFlow::Installed,
+            service_account_key: None,
+            impersonate: None,
+            state_db: "/var/lib/pi_drive_sync/state.db".to_owned(),
+            rescan_secs: None,
+            quiet_secs: 5,
+            on_delete: DeletePolicy::Ignore,
+            max_attempts: 8,
+            retry_secs: 30,
+            max_retry_secs: 3600,
+            probe_secs: 30,
+            max_queued: 10_000,
+            workers: 2,
+            upload_kib_per_sec: None,
+            rate_windows: vec![],
+            after_upload: AfterUpload::Keep,
+            archive_dir: None,
+            keep_days: None,
+            verify_md5: false,
+            disk_high_pct: None,
+            disk_low_pct: None,
+            remote_retention: None,
+            dry_run: false,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> PiSyncResult<Config> {
+        debug!(log, "Reading config from {:?}", path);
+        let raw = std::fs::read_to_string(path).map_err(|e| {
+            error!(log, "Cannot read config {:?} {}", path, e);
+            SyncerErrors::InvalidConfig
+        })?;
+        Config::parse(&raw)
+    }
+
+    pub fn parse(raw: &str) -> PiSyncResult<Config> {
+        let config: Config = toml::from_str(raw).map_err(|e| {
+            error!(log, "Bad config {}", e);
+            SyncerErrors::InvalidConfig
+        })?;
+        if config.drive_auth == AuthFlow::ServiceAccount && config.service_account_key.is_none() {
+            error!(
+                log,
+                "drive_auth = \"service_account\" needs a service_account_key"
+            );
+            return Err(SyncerErrors::InvalidConfig);
+        }
+        config.check_filters()?;
+        config.rate_schedule()?;
+        config.retention_policy()?;
+        config.watermarks()?;
+        config.pruner()?;
+        Ok(config)
+    }
+
+    ///A filter that is not a regex would never match, so nothing would be uploaded
+    pub fn check_filters(&self) -> PiSyncResult<()> {
+        let filters = self.roots.iter().flat_map(|r| &r.filters);
+        for filter in self.filters.iter().chain(filters) {
+            Regex::new(filter).map_err(|e| {
+                error!(log, "Bad filter {} {}", filter, e);
+                SyncerErrors::InvalidConfig
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn rate_schedule(&self) -> PiSyncResult<RateSchedule> {
+        RateSchedule::new(self.upload_kib_per_sec, &self.rate_windows)
+    }
+
+    pub fn retention_policy(&self) -> PiSyncResult<RetentionPolicy> {
+        let policy = RetentionPolicy::new(
+            self.after_upload,
+            self.archive_dir.as_ref().map(PathBuf::from),
+            self.keep_days
+                .map(|days| Duration::from_secs(days * 24 * 3600)),
+            self.verify_md5,
+            self.dry_run,
+        );
+        policy.check(&self.roots())?;
+        Ok(policy)
+    }
+
+    ///None when eviction is off
+    pub fn watermarks(&self) -> PiSyncResult<Option<Watermarks>> {
+        self.disk_high_pct
+            .map(|high| {
+                let watermarks =
+                    Watermarks::new(high, self.disk_low_pct.unwrap_or(high.saturating_sub(10)));
+                watermarks.check().map(|_| watermarks)
+            })
+            .transpose()
+    }
+
+    ///None when the backend is left alone
+    pub fn pruner(&self) -> PiSyncResult<Option<Pruner>> {
+        self.remote_retention.as_ref().map(Pruner::new).transpose()
+    }
+
+    pub fn auth_options(&self) -> AuthOptions {
+        AuthOptions::new(
+            self.drive_auth,
+            self.secret_file.clone(),
+            self.token_file.clone(),
+            self.service_account_key.clone(),
+            self.impersonate.clone(),
+        )
+    }
+
+    pub fn retry_policy(&self) -> RetryPolicy {
+        RetryPolicy::new(
+            self.max_attempts,
+            Duration::from_secs(self.retry_secs),
+            Duration::from_secs(self.max_retry_secs),
+        )
+    }
+
+    pub fn roots(&self) -> Roots {
+        if self.roots.is_empty() {
+            Roots::from(RootMapping::new(
+                self.watch_dir.clone().into(),
+                self.remote_root.clone().into(),
+                self.filters.clone(),
+            ))
+        } else {
+            Roots::new(
+                self.roots
+                    .iter()
+                    .map(|r| {
+                        RootMapping::new(
+                            r.watch_dir.clone().into(),
+                            r.remote_root.clone().into(),
+                            r.filters.clone(),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::config::*;
+    use crate::upload_handler::FileOperations;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_config_defaults() {
+        assert_eq!(Config::default(), Config::parse("").unwrap());
+        assert_eq!(
+            Roots::new(vec![RootMapping::new(
+                "/var/www/RpiCamera".into(),
+                "RpiCamera/RpiCamera".into(),
+                vec![]
+            )]),
+            Config::default().roots()
+        );
+        //the ids synced files had before there was a config
+        assert_eq!(
+            base64::encode("RpiCamera/RpiCamera/a/im1.jpg"),
+            Config::default()
+                .roots()
+                .file("/var/www/RpiCamera/a/im1.jpg")
+                .get_unique_id()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_config_load() {
+        let dir = tempdir().unwrap();
+        let file = dir.path().join("pi_sync.toml");
+        std::fs::write(
+            &file,
+            "watch_dir = \"/home/pi/cam\"\nfilters = [\"^im.*jpg$\"]\ntoken_file = \"/tmp/t.json\"\nrescan_secs = 600\non_delete = \"trash\"\nmax_attempts = 3\nafter_upload = \"delete\"\nkeep_days = 2\n",
+        )
+        .unwrap();
+
+        let config = Config::load(&file).unwrap();
+        assert_eq!("/home/pi/cam", config.watch_dir);
+        assert_eq!("RpiCamera/RpiCamera", config.remote_root);
+        assert_eq!(vec!["^im.*jpg$"], config.filters);
+        assert_eq!("/tmp/t.json", config.token_file);
+        assert_eq!(Some(600), config.rescan_secs);
+        assert_eq!(DeletePolicy::Trash, config.on_delete);
+        assert_eq!(
+            RetryPolicy::new(3, Duration::from_secs(30), Duration::from_secs(3600)),
+            config.retry_policy()
+        );
+        assert_eq!(
+            RetentionPolicy::new(
+                AfterUpload::Delete,
+                None,
+                Some(Duration::from_secs(2 * 24 * 3600)),
+                false,
+                false
+            ),
+            config.retention_policy().unwrap()
+        );
+        assert_eq!(None, config.watermarks().unwrap());
+        assert_eq!(
+            Some(Watermarks::new(90, 80)),
+            Config::parse("disk_high_pct = 90")
+                .unwrap()
+                .watermarks()
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_config_multiple_roots() {
+        let config = Config::parse(
+            r#"
+            [[roots]]
+            watch_dir = "/var/www/media"
+            remote_root = "RpiCamera"
+            filters = ["^im.*jpg$"]
+
+            [[roots]]
+            watch_dir = "/var/log/motion"
+            remote_root = "logs/motion"
+            "#,
+        )
+        .unwrap();
+
+        let roots = config.roots();
+        let dirs: Vec<_> = roots.iter().map(|r| r.local_root()).collect();
+        assert_eq!(
+            vec![Path::new("/var/www/media"), Path::new("/var/log/motion")],
+            dirs
+        );
+        assert!(roots.passes_filter("/var/www/media/im1.jpg"));
+        assert!(!roots.passes_filter("/var/www/media/vi1.mp4"));
+        assert!(roots.passes_filter("/var/log/motion/motion.log"));
+        assert_eq!(None, config.remote_retention);
+    }
+
+    #[test]
+    fn test_config_remote_retention() {
+        let config = Config::parse(
+            r#"
+            [remote_retention]
+            max_total_mb = 10000
+
+            [[remote_retention.rules]]
+            filter = "^vi.*mp4$"
+            max_age_days = 14
+            "#,
+        )
+        .unwrap();
+        let retention = config.remote_retention.clone().unwrap();
+        assert_eq!(Some(10000), retention.max_total_mb);
+        assert_eq!(None, retention.max_age_days);
+        assert_eq!(24, retention.every_hours);
+        assert_eq!(Some(14), retention.rules[0].max_age_days);
+        assert!(config.pruner().unwrap().is_some());
+    }
+
+    #[test]
+    fn test_config_drive_auth() {
+        assert_eq!(AuthFlow::Installed, Config::default().drive_auth);
+        let config = Config::parse(
+            r#"
+            drive_auth = "service_account"
+            service_account_key = "/etc/pi_drive_sync/sa.json"
+            impersonate = "camera@example.com"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(AuthFlow::ServiceAccount, config.drive_auth);
+        assert_eq!(
+            AuthFlow::DeviceCode,
+            Config::parse("drive_auth = \"device_code\"")
+                .unwrap()
+                .drive_auth
+        );
+        assert_eq!(
+            AuthOptions::new(
+                AuthFlow::ServiceAccount,
+                config.secret_file.clone(),
+                config.token_file.clone(),
+                Some("/etc/pi_drive_sync/sa.json".into()),
+                Some("camera@example.com".into()),
+            ),
+            config.auth_options()
+        );
+    }
+
+    #[test]
+    fn test_config_invalid() {
+        assert!(Config::parse("watch_dir = 3").is_err());
+        assert!(Config::parse("wacth_dir = \"/tmp\"").is_err());
+        assert!(Config::parse("[[roots]]\nwatch_dir = \"/tmp\"").is_err());
+        assert!(Config::parse("on_delete = \"shred\"").is_err());
+        assert!(Config::parse("[[rate_windows]]\nfrom = \"6am\"\nto = \"07:00\"").is_err());
+        assert!(Config::parse("after_upload = \"archive\"").is_err());
+        assert!(Config::parse(
+            "watch_dir = \"/home/pi/cam\"\nafter_upload = \"archive\"\narchive_dir = \"/home/pi/cam/old\""
+        )
+        .is_err());
+        assert!(Config::parse("disk_high_pct = 80\ndisk_low_pct = 90").is_err());
+        assert!(Config::parse("[[remote_retention.rules]]\nfilter = \"(\"").is_err());
+        assert!(Config::parse("filters = [\"jpg$\", \"(\"]").is_err());
+        assert!(Config::parse(
+            "[[roots]]\nwatch_dir = \"/tmp\"\nremote_root = \"tmp\"\nfilters = [\"*.jpg\"]"
+        )
+        .is_err());
+        assert!(Config::parse("drive_auth = \"service_account\"").is_err());
+        assert!(Config::parse("drive_auth = \"password\"").is_err());
+        assert!(Config::load(Path::new("/not/a/config.toml")).is_err());
+    }
+}
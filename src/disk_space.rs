@@ -0,0 +1,270 @@
+//!Keeps the disk holding the watched dirs from filling during a long outage. Once it is fuller
+//!than the high watermark the oldest files already on the backend are deleted, until it is back
+//!under the low one. Only files retention can confirm uploaded are ever evicted
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::retention::LocalRetention;
+use crate::state_db::StateDb;
+use crate::upload_handler::Roots;
+use std::ffi::CString;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::{Duration, SystemTime};
+
+///How often the disk is checked
+pub const CHECK_EVERY: Duration = Duration::from_secs(60);
+
+///Percent of the disk used, as df reports it
+#[derive(new, Debug, Clone, Copy, PartialEq)]
+pub struct Watermarks {
+    ///Start evicting above this
+    high: u8,
+    ///Stop once at or under this
+    low: u8,
+}
+
+impl Watermarks {
+    pub fn check(&self) -> PiSyncResult<()> {
+        if self.low >= self.high || self.high > 100 {
+            error!(
+                log,
+                "Disk watermarks need low < high <= 100, not {} and {}", self.low, self.high
+            );
+            return Err(SyncerErrors::InvalidConfig);
+        }
+        Ok(())
+    }
+}
+
+///Bytes in use and free to us, reserved blocks are in neither
+#[derive(new, Debug, Clone, Copy, PartialEq)]
+pub struct DiskUsage {
+    used: u64,
+    available: u64,
+}
+
+impl DiskUsage {
+    pub fn used_pct(&self) -> f64 {
+        match self.used + self.available {
+            0 => 0.0,
+            total => self.used as f64 * 100.0 / total as f64,
+        }
+    }
+
+    ///As it would be with bytes more deleted
+    pub fn freeing(&self, bytes: u64) -> DiskUsage {
+        let bytes = bytes.min(self.used);
+        DiskUsage::new(self.used - bytes, self.available + bytes)
+    }
+}
+
+///How full the filesystem holding path is
+pub type Usage = fn(&Path) -> PiSyncResult<DiskUsage>;
+
+pub struct DiskWatch {
+    watermarks: Watermarks,
+    roots: Roots,
+    state: Arc<StateDb>,
+    retention: Arc<LocalRetention>,
+    usage: Usage,
+}
+
+impl DiskWatch {
+    pub fn new(
+        watermarks: Watermarks,
+        roots: Roots,
+        state: Arc<StateDb>,
+        retention: Arc<LocalRetention>,
+    ) -> Self {
+        DiskWatch {
+            watermarks,
+            roots,
+            state,
+            retention,
+            usage: statvfs_usage,
+        }
+    }
+
+    ///Check every CHECK_EVERY on a thread of its own, with its own client
+    pub fn spawn(self, client: Box<dyn CloudClient>) -> JoinHandle<()> {
+        std::thread::Builder::new()
+            .name("disk-watch".into())
+            .spawn(move || loop {
+                self.check(client.as_ref());
+                std::thread::sleep(CHECK_EVERY);
+            })
+            .expect("cannot start disk watch")
+    }
+
+    ///Evict from every root's disk that is over the high watermark, returns how many were
+    pub fn check(&self, client: &dyn CloudClient) -> usize {
+        self.roots
+            .iter()
+            .map(|root| match self.check_disk(client, root.local_root()) {
+                Ok(evicted) => evicted,
+                Err(e) => {
+                    warn!(log, "Cannot free space for {:?} {}", root.local_root(), e);
+                    0
+                }
+            })
+            .sum()
+    }
+
+    ///Roots sharing a disk are all evicted from, the first one checked frees the space for both
+    fn check_disk(&self, client: &dyn CloudClient, root: &Path) -> PiSyncResult<usize> {
+        let mut used = (self.usage)(root)?.used_pct();
+        if used <= f64::from(self.watermarks.high) {
+            trace!(log, "{:?} is on a disk {:.1}% used", root, used);
+            return Ok(0);
+        }
+        warn!(
+            log,
+            "Disk holding {:?} is {:.1}% used, evicting synced files", root, used
+        );
+        let device = dev(root)?;
+        let mut candidates = self
+            .state
+            .synced_files()?
+            .into_iter()
+            .filter_map(|record| {
+                let meta = std::fs::metadata(&record.local_path).ok()?;
+                if !meta.is_file() || meta.dev() != device {
+                    return None;
+                }
+                Some((meta.modified().ok()?, record.local_path, meta.len()))
+            })
+            .collect::<Vec<(SystemTime, String, u64)>>();
+        candidates.sort();
+
+        let mut evicted = 0;
+        //a dry run frees nothing, so the disk is taken to be as it would be
+        let mut would_free = 0;
+        for (_mtime, path, size) in candidates {
+            if used <= f64::from(self.watermarks.low) {
+                break;
+            }
+            if self.retention.evict(client, &path)? {
+                evicted += 1;
+                if self.retention.dry_run() {
+                    would_free += size;
+                }
+                used = (self.usage)(root)?.freeing(would_free).used_pct();
+                info!(log, "Evicted {}, disk now {:.1}% used", path, used);
+            }
+        }
+        if used > f64::from(self.watermarks.low) {
+            warn!(
+                log,
+                "Disk holding {:?} still {:.1}% used, nothing else is synced", root, used
+            );
+        }
+        Ok(evicted)
+    }
+}
+
+fn dev(path: &Path) -> PiSyncResult<u64> {
+    std::fs::metadata(path).map(|meta| meta.dev()).map_err(|e| {
+        error!(log, "Cannot stat {:?} {}", path, e);
+        SyncerErrors::InvalidPathError
+    })
+}
+
+fn statvfs_usage(path: &Path) -> PiSyncResult<DiskUsage> {
+    let c_path =
+        CString::new(path.as_os_str().as_bytes()).map_err(|_e| SyncerErrors::InvalidPathError)?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(c_path.as_ptr(), &mut stat) } != 0 {
+        error!(
+            log,
+            "Cannot statvfs {:?} {}",
+            path,
+            std::io::Error::last_os_error()
+        );
+        return Err(SyncerErrors::InvalidPathError);
+    }
+    let frsize = stat.f_frsize as u64;
+    Ok(DiskUsage::new(
+        (stat.f_blocks as u64 - stat.f_bfree as u64) * frsize,
+        stat.f_bavail as u64 * frsize,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::disk_space::*;
+    use crate::local_fixture::LocalFixture;
+    use crate::retention::{AfterUpload, RetentionPolicy};
+    use std::time::UNIX_EPOCH;
+    use tempfile::tempdir;
+
+    ///A 100 byte disk holding just the files under path
+    fn tiny_disk(path: &Path) -> PiSyncResult<DiskUsage> {
+        let used = walkdir::WalkDir::new(path)
+            .into_iter()
+            .filter_map(|e| e.ok()?.metadata().ok())
+            .filter(|meta| meta.is_file())
+            .map(|meta| meta.len())
+            .sum::<u64>();
+        Ok(DiskUsage::new(used, 100 - used))
+    }
+
+    #[test]
+    fn test_disk_space_evicts_oldest_synced() {
+        let LocalFixture {
+            local,
+            target: _target,
+            roots,
+            state,
+            lb,
+        } = LocalFixture::new();
+        //im0 is the oldest but was never uploaded
+        let files = (0..4)
+            .map(|n| {
+                let path = local.path().join(format!("im{}.jpg", n));
+                std::fs::write(&path, [0u8; 20]).unwrap();
+                std::fs::File::options()
+                    .write(true)
+                    .open(&path)
+                    .unwrap()
+                    .set_modified(UNIX_EPOCH + Duration::from_secs(1_600_000_000 + n))
+                    .unwrap();
+                if n > 0 {
+                    lb.upload_file(path.to_str().unwrap()).unwrap();
+                }
+                path
+            })
+            .collect::<Vec<_>>();
+        let retention = Arc::new(LocalRetention::new(
+            RetentionPolicy::new(AfterUpload::Keep, None, None, false, false),
+            roots.clone(),
+            Arc::clone(&state),
+        ));
+        let mut watch = DiskWatch::new(Watermarks::new(70, 50), roots, state, retention);
+        watch.usage = tiny_disk;
+
+        //80% used, down to 40%
+        assert_eq!(2, watch.check(&lb));
+        let left = files.iter().map(|f| f.exists()).collect::<Vec<_>>();
+        assert_eq!(vec![true, false, false, true], left);
+        assert_eq!(0, watch.check(&lb));
+
+        let usage = DiskUsage::new(80, 20);
+        assert_eq!(50.0, usage.freeing(30).used_pct());
+        assert_eq!(0.0, usage.freeing(200).used_pct());
+    }
+
+    #[test]
+    fn test_disk_space_usage() {
+        let dir = tempdir().unwrap();
+        let used = statvfs_usage(dir.path()).unwrap().used_pct();
+        assert!((0.0..=100.0).contains(&used), "{}", used);
+        assert!(statvfs_usage(Path::new("/not/a/dir")).is_err());
+        assert!(Watermarks::new(90, 80).check().is_ok());
+        assert!(Watermarks::new(80, 90).check().is_err());
+        assert!(Watermarks::new(101, 90).check().is_err());
+    }
+}
@@ -0,0 +1,365 @@
+//!How the Drive client gets its tokens. The installed flow needs someone to open a URL and paste
+//!a code back once, a service account signs its own JWTs so a headless Pi never needs anyone.
+//!With domain-wide delegation a service account acts as a user, and syncs into their Drive.
+//!The device code flow logs in once from any phone or laptop, with a short code the Pi prints,
+//!after which its tokens are refreshed as the installed flow's are
+use crate::common::{uri_encode, LOG as log};
+use crate::drive_cli::DRIVE_SCOPES;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use hyper::header::ContentType;
+use serde::Deserialize;
+use std::collections::hash_map::DefaultHasher;
+use std::error::Error;
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+use yup_oauth2::{
+    read_application_secret, service_account_key_from_file, ApplicationSecret, Authenticator,
+    DefaultAuthenticatorDelegate, DiskTokenStorage, FlowType, GetToken, ServiceAccountAccess,
+    Token, TokenStorage,
+};
+
+///Google's device authorization endpoint
+pub const GOOGLE_DEVICE_CODE_URI: &str = "https://oauth2.googleapis.com/device/code";
+const DEVICE_GRANT: &str = "urn:ietf:params:oauth:grant-type:device_code";
+
+///Which OAuth flow gets Drive tokens
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum AuthFlow {
+    ///Log in once from a browser, with the client secret in secret_file
+    Installed,
+    ///JWTs signed with the key in service_account_key
+    ServiceAccount,
+    ///Log in once from another device, with the client secret in secret_file
+    DeviceCode,
+}
+
+impl FromStr for AuthFlow {
+    type Err = SyncerErrors;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "installed" => Ok(AuthFlow::Installed),
+            "service_account" => Ok(AuthFlow::ServiceAccount),
+            "device_code" => Ok(AuthFlow::DeviceCode),
+            _ => Err(SyncerErrors::InvalidConfig),
+        }
+    }
+}
+
+#[derive(new, Clone, Debug, PartialEq)]
+pub struct AuthOptions {
+    flow: AuthFlow,
+    ///Client secret for the installed and device code flows
+    secret_file: String,
+    ///Where those flows cache their tokens, refreshed in place
+    token_file: String,
+    ///Service account key JSON, as downloaded from the cloud console
+    service_account_key: Option<String>,
+    ///The user a service account acts as, needs domain-wide delegation
+    subject: Option<String>,
+}
+
+type Installed = Authenticator<DefaultAuthenticatorDelegate, DiskTokenStorage, hyper::Client>;
+
+///Whichever flow the config picked, as the one token source DriveHub takes
+pub enum DriveAuth {
+    Installed(Installed),
+    ServiceAccount(ServiceAccountAccess<hyper::Client>),
+}
+
+impl DriveAuth {
+    ///token_uri and device_code_uri override the endpoints in the secret or key file, and
+    ///Google's. NoAppSecret if the file the flow needs cannot be read, or a device login fails
+    pub fn new(
+        opts: &AuthOptions,
+        token_uri: Option<&str>,
+        device_code_uri: Option<&str>,
+    ) -> PiSyncResult<DriveAuth> {
+        match opts.flow {
+            AuthFlow::Installed | AuthFlow::DeviceCode => {
+                let mut secret = read_application_secret(std::path::Path::new(&opts.secret_file))
+                    .map_err(|e| {
+                    error!(log, "Cannot read client secret {} {}", opts.secret_file, e);
+                    SyncerErrors::NoAppSecret
+                })?;
+                if let Some(token_uri) = token_uri {
+                    debug!(log, "Using {} as OAuth token endpoint", token_uri);
+                    secret.token_uri = token_uri.to_owned();
+                }
+                let mut token_storage = DiskTokenStorage::new(&opts.token_file).map_err(|e| {
+                    error!(log, "Cannot read token file {} {}", opts.token_file, e);
+                    SyncerErrors::NoAppSecret
+                })?;
+                if opts.flow == AuthFlow::DeviceCode {
+                    let device_code_uri = device_code_uri.unwrap_or(GOOGLE_DEVICE_CODE_URI);
+                    device_login(&secret, device_code_uri, &mut token_storage)?;
+                }
+                Ok(DriveAuth::Installed(Authenticator::new(
+                    &secret,
+                    DefaultAuthenticatorDelegate,
+                    https_client(),
+                    token_storage,
+                    Some(FlowType::InstalledInteractive),
+                )))
+            }
+            AuthFlow::ServiceAccount => {
+                let key_file = opts.service_account_key.as_ref().ok_or_else(|| {
+                    error!(
+                        log,
+                        "drive_auth = \"service_account\" needs a service_account_key"
+                    );
+                    SyncerErrors::NoAppSecret
+                })?;
+                let mut key = service_account_key_from_file(key_file).map_err(|e| {
+                    error!(log, "Cannot read service account key {} {}", key_file, e);
+                    SyncerErrors::NoAppSecret
+                })?;
+                if key.private_key.is_none() || key.client_email.is_none() {
+                    error!(log, "{} is not a service account key", key_file);
+                    return Err(SyncerErrors::NoAppSecret);
+                }
+                if let Some(token_uri) = token_uri {
+                    debug!(log, "Using {} as OAuth token endpoint", token_uri);
+                    key.token_uri = Some(token_uri.to_owned());
+                }
+                Ok(DriveAuth::ServiceAccount(match &opts.subject {
+                    Some(subject) => {
+                        debug!(log, "Acting as {} by domain-wide delegation", subject);
+                        ServiceAccountAccess::with_sub(key, https_client(), subject.clone())
+                    }
+                    None => ServiceAccountAccess::new(key, https_client()),
+                }))
+            }
+        }
+    }
+}
+
+///What the device authorization endpoint answers
+#[derive(Deserialize, Debug)]
+struct DeviceCode {
+    device_code: String,
+    user_code: String,
+    ///Google still calls it verification_url
+    #[serde(alias = "verification_url")]
+    verification_uri: String,
+    expires_in: u64,
+    #[serde(default = "default_interval")]
+    interval: u64,
+}
+
+fn default_interval() -> u64 {
+    5
+}
+
+#[derive(Deserialize, Debug)]
+struct TokenError {
+    error: String,
+}
+
+///Unless storage already has a token, print a code and where to enter it, poll until someone
+///has, and store the token issued
+fn device_login(
+    secret: &ApplicationSecret,
+    device_code_uri: &str,
+    storage: &mut DiskTokenStorage,
+) -> PiSyncResult<()> {
+    let mut scopes = DRIVE_SCOPES.to_vec();
+    scopes.sort();
+    let mut hasher = DefaultHasher::new();
+    scopes.hash(&mut hasher);
+    let scope_hash = hasher.finish();
+    if let Ok(Some(_token)) = storage.get(scope_hash, &scopes) {
+        debug!(log, "Already logged in, not starting a device login");
+        return Ok(());
+    }
+
+    let code: DeviceCode = post_form(
+        device_code_uri,
+        &[
+            ("client_id", &secret.client_id),
+            ("scope", &scopes.join(" ")),
+        ],
+    )
+    .and_then(|body| serde_json::from_str(&body).map_err(|e| e.to_string()))
+    .map_err(|e| {
+        error!(
+            log,
+            "Cannot start a device login at {} {}", device_code_uri, e
+        );
+        SyncerErrors::NoAppSecret
+    })?;
+    println!(
+        "To let pi_drive_sync use Google Drive, go to {} and enter the code {}",
+        code.verification_uri, code.user_code
+    );
+    info!(
+        log,
+        "Waiting for code {} to be entered at {}", code.user_code, code.verification_uri
+    );
+
+    let expires = Instant::now() + Duration::from_secs(code.expires_in);
+    let mut interval = Duration::from_secs(code.interval);
+    while Instant::now() < expires {
+        std::thread::sleep(interval);
+        let body = match post_form(
+            &secret.token_uri,
+            &[
+                ("client_id", &secret.client_id),
+                ("client_secret", &secret.client_secret),
+                ("device_code", &code.device_code),
+                ("grant_type", DEVICE_GRANT),
+            ],
+        ) {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(log, "Device login poll failed, trying again {}", e);
+                continue;
+            }
+        };
+        if let Ok(TokenError { error }) = serde_json::from_str(&body) {
+            match error.as_str() {
+                "authorization_pending" => continue,
+                "slow_down" => {
+                    interval += Duration::from_secs(5);
+                    continue;
+                }
+                _ => {
+                    error!(log, "Device login refused {}", error);
+                    return Err(SyncerErrors::NoAppSecret);
+                }
+            }
+        }
+        let mut token: Token = serde_json::from_str(&body).map_err(|e| {
+            error!(
+                log,
+                "Device login gave a token we cannot read {} {}", e, body
+            );
+            SyncerErrors::NoAppSecret
+        })?;
+        token.set_expiry_absolute();
+        storage.set(scope_hash, &scopes, Some(token)).map_err(|e| {
+            error!(log, "Cannot store the device login token {}", e);
+            SyncerErrors::NoAppSecret
+        })?;
+        println!("Logged in, the token is stored");
+        return Ok(());
+    }
+    error!(log, "Code {} was not entered in time", code.user_code);
+    Err(SyncerErrors::NoAppSecret)
+}
+
+///POST a form, returning the body whatever the status, OAuth errors come back as JSON
+fn post_form(uri: &str, form: &[(&str, &str)]) -> Result<String, String> {
+    let form = form
+        .iter()
+        .map(|(k, v)| format!("{}={}", k, uri_encode(v, true)))
+        .collect::<Vec<_>>()
+        .join("&");
+    let mut body = String::new();
+    https_client()
+        .post(uri)
+        .header(ContentType(
+            "application/x-www-form-urlencoded".parse().unwrap(),
+        ))
+        .body(&form)
+        .send()
+        .map_err(|e| e.to_string())?
+        .read_to_string(&mut body)
+        .map_err(|e| e.to_string())?;
+    Ok(body)
+}
+
+impl GetToken for DriveAuth {
+    fn token<'b, I, T>(&mut self, scopes: I) -> Result<Token, Box<dyn Error>>
+    where
+        T: AsRef<str> + Ord + 'b,
+        I: IntoIterator<Item = &'b T>,
+    {
+        match self {
+            DriveAuth::Installed(auth) => auth.token(scopes),
+            DriveAuth::ServiceAccount(auth) => auth.token(scopes),
+        }
+    }
+
+    fn api_key(&mut self) -> Option<String> {
+        match self {
+            DriveAuth::Installed(auth) => auth.api_key(),
+            DriveAuth::ServiceAccount(auth) => auth.api_key(),
+        }
+    }
+}
+
+pub fn https_client() -> hyper::Client {
+    hyper::Client::with_connector(hyper::net::HttpsConnector::new(
+        hyper_rustls::TlsClient::new(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::drive_auth::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_drive_auth_flow_from_str() {
+        assert_eq!(AuthFlow::Installed, "installed".parse().unwrap());
+        assert_eq!(AuthFlow::ServiceAccount, "service_account".parse().unwrap());
+        assert_eq!(AuthFlow::DeviceCode, "device_code".parse().unwrap());
+        assert!("password".parse::<AuthFlow>().is_err());
+    }
+
+    #[test]
+    fn test_drive_auth_bad_credentials() {
+        let dir = tempdir().unwrap();
+        let secret = dir.path().join("drive3-secret.json");
+        std::fs::write(&secret, "{\"installed\":{\"client_id\":\"pi\"}}").unwrap();
+        let secret = secret.to_str().unwrap().to_owned();
+        let sa = |key: Option<String>| {
+            AuthOptions::new(
+                AuthFlow::ServiceAccount,
+                secret.clone(),
+                String::new(),
+                key,
+                None,
+            )
+        };
+
+        assert!(DriveAuth::new(&sa(None), None, None).is_err());
+        assert!(DriveAuth::new(&sa(Some(secret.clone())), None, None).is_err());
+        assert!(DriveAuth::new(&sa(Some("/not/a/key.json".into())), None, None).is_err());
+        let installed = AuthOptions::new(
+            AuthFlow::Installed,
+            "/not/a/secret.json".into(),
+            String::new(),
+            None,
+            None,
+        );
+        assert!(DriveAuth::new(&installed, None, None).is_err());
+    }
+
+    #[test]
+    fn test_drive_auth_bad_token_file() {
+        let dir = tempdir().unwrap();
+        let secret = dir.path().join("drive3-secret.json");
+        std::fs::write(
+            &secret,
+            "{\"installed\":{\"client_id\":\"pi\",\"client_secret\":\"sync\",\"auth_uri\":\"https://oauth2.example.invalid/auth\",\"token_uri\":\"https://oauth2.example.invalid/token\",\"redirect_uris\":[\"urn:ietf:wg:oauth:2.0:oob\"]}}",
+        )
+        .unwrap();
+        let token = dir.path().join("token.json");
+        let installed = AuthOptions::new(
+            AuthFlow::Installed,
+            secret.to_str().unwrap().into(),
+            token.to_str().unwrap().into(),
+            None,
+            None,
+        );
+        //no token yet is fine, one we cannot read is an error rather than a panic
+        assert!(DriveAuth::new(&installed, None, None).is_ok());
+        std::fs::write(&token, b"not json").unwrap();
+        assert!(DriveAuth::new(&installed, None, None).is_err());
+    }
+}
@@ -1,102 +1,97 @@
+use crate::cloud_client::{CloudClient, RemoteFile};
 use crate::common::LOG as log;
+use crate::drive_auth::{https_client, AuthOptions, DriveAuth};
 use crate::pi_err::{PiSyncResult, SyncerErrors};
-use crate::upload_handler::{FileOperations, SyncableFile};
+use crate::scanner::moved_children;
+use crate::state_db::{md5_hex, StateDb};
+use crate::throttle::{Bandwidth, Throttled};
+use crate::upload_handler::{FileOperations, Roots, SyncableFile};
+use base64::encode;
+use chrono::{DateTime, Utc};
 use drive3::{DriveHub, Error};
-use yup_oauth2::{
-    read_application_secret, ApplicationSecret, Authenticator, DefaultAuthenticatorDelegate,
-    DiskTokenStorage, GetToken, MemoryStorage,
-};
-//use crate::oauth2::GetToken;`
+use yup_oauth2::GetToken;
 
-use regex::Regex;
 use std::collections::HashMap;
 use std::default::Default;
-use std::sync::{Arc, RwLock};
-use std::time::Duration;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 use tempfile::tempfile;
-use ttl_cache::TtlCache;
 
 lazy_static::lazy_static! {
-    static ref CACHE_TTL: std::time::Duration = Duration::new(86400, 0);
+    ///Held while folders are looked up and created, so two workers uploading into the same new
+    ///folder do not both create it. Drive would happily keep both
+    static ref CREATE_PATH: Mutex<()> = Mutex::new(());
 }
 
 const PI_DRIVE_SYNC_PROPS_KEY: &str = "pi_sync_id";
-pub type Hub = drive3::DriveHub<
-    hyper::Client,
-    oauth2::Authenticator<
-        oauth2::DefaultAuthenticatorDelegate,
-        oauth2::DiskTokenStorage,
-        hyper::Client,
-    >,
->;
+const FOLDER_MIME: &str = "application/vnd.google-apps.folder";
+///What list_remote needs of each file
+const LIST_FIELDS: &str =
+    "nextPageToken,files(id,name,mimeType,size,md5Checksum,createdTime,appProperties)";
+///What we ask the user to grant us on first auth
+pub const DRIVE_SCOPES: [&str; 2] = [
+    "https://www.googleapis.com/auth/drive",
+    "https://www.googleapis.com/auth/drive.metadata.readonly",
+];
+pub type Hub = drive3::DriveHub<hyper::Client, DriveAuth>;
 
-pub struct Drive3Client {
-    hub: std::result::Result<Hub, SyncerErrors>,
-    filters: Vec<String>,
-    cache: Arc<RwLock<TtlCache<String, String>>>,
+///Where the Drive API and its OAuth token endpoint live, defaults are Google's own
+#[derive(new, Clone, Debug)]
+pub struct DriveOptions {
+    ///Which OAuth flow, and its credentials
+    auth: AuthOptions,
+    ///Root of the Drive API, e.g. http://localhost:8080/, uploads go to upload/drive/v3/ under it
+    api_root: Option<String>,
+    ///Overrides the token_uri from the secret or key file
+    token_uri: Option<String>,
+    ///Overrides Google's device authorization endpoint, for drive_auth = "device_code"
+    device_code_uri: Option<String>,
+    ///Shared by every upload, see throttle
+    bandwidth: Arc<Bandwidth>,
 }
 
-pub trait CloudClient {
-    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>>;
-    fn create_dir(
-        &self,
-        local_fs_path: &str,
-        parent_id: Option<&str>,
-    ) -> PiSyncResult<Option<String>>;
-    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>>; //should this be cloud path
-    fn app_props_map(&self, id: &str) -> Option<HashMap<String, String>>;
-    fn passes_filter(&self, local_fs_path: &str) -> bool;
+pub struct Drive3Client {
+    hub: std::result::Result<Hub, SyncerErrors>,
+    roots: Roots,
+    ///Folder ids we already know, so a restart does not list every folder again
+    state: Arc<StateDb>,
+    bandwidth: Arc<Bandwidth>,
 }
 
 impl Drive3Client {
-    pub fn new(secret_file: String, filters: Vec<&str>) -> Self {
-        let cache = Arc::new(RwLock::new(TtlCache::new(100)));
-
-        match Drive3Client::read_client_secret(secret_file) {
-            Some(secret) => {
-                let token_storage = DiskTokenStorage::new(&String::from("temp_token"))
-                    .expect("Cannot create temp storage token - write permissions?");
-
-                let mut auth = Authenticator::new(
-                    &secret,
-                    DefaultAuthenticatorDelegate,
-                    hyper::Client::with_connector(hyper::net::HttpsConnector::new(
-                        hyper_rustls::TlsClient::new(),
-                    )),
-                    token_storage,
-                    Some(yup_oauth2::FlowType::InstalledInteractive),
-                );
-
-                let scopes = &[
-                    "https://www.googleapis.com/auth/drive",
-                    "https://www.googleapis.com/auth/drive.metadata.readonly",
-                ];
-
-                match auth.token(scopes) {
-                    Err(e) => println!("error: {:?}", e),
-                    Ok(t) => println!("The token is {:?}", t),
+    pub fn new(opts: DriveOptions, roots: Roots, state: Arc<StateDb>) -> Self {
+        match DriveAuth::new(
+            &opts.auth,
+            opts.token_uri.as_deref(),
+            opts.device_code_uri.as_deref(),
+        ) {
+            Ok(mut auth) => {
+                match auth.token(&DRIVE_SCOPES) {
+                    Err(e) => error!(log, "Cannot get a Drive token {}", e),
+                    Ok(_token) => debug!(log, "Got a Drive token"),
                 };
 
-                let hub = DriveHub::new(
-                    hyper::Client::with_connector(hyper::net::HttpsConnector::new(
-                        hyper_rustls::TlsClient::new(),
-                    )),
-                    auth,
-                );
+                let mut hub = DriveHub::new(https_client(), auth);
+
+                if let Some(api_root) = opts.api_root {
+                    let api_root = format!("{}/", api_root.trim_end_matches('/'));
+                    debug!(log, "Using {} as Drive API root", api_root);
+                    hub.base_url(format!("{}drive/v3/", api_root));
+                    hub.root_url(api_root);
+                }
 
                 Drive3Client {
                     hub: Ok(hub),
-                    filters: filters
-                        .iter()
-                        .map(|x| x.to_string())
-                        .collect::<Vec<String>>(),
-                    cache: cache,
+                    roots,
+                    state,
+                    bandwidth: opts.bandwidth,
                 }
             }
-            None => Drive3Client {
-                hub: Err(SyncerErrors::NoAppSecret),
-                filters: vec![],
-                cache: cache,
+            Err(e) => Drive3Client {
+                hub: Err(e),
+                roots,
+                state,
+                bandwidth: opts.bandwidth,
             },
         }
     }
@@ -105,140 +100,340 @@ impl Drive3Client {
         self.hub.as_ref().map_err(|_e| SyncerErrors::NoAppSecret)
     }
 
-    fn read_client_secret(file: String) -> Option<ApplicationSecret> {
-        read_application_secret(std::path::Path::new(&file)).ok()
+    ///Tag every file we create with its pi_sync_id so we can find it again
+    fn app_props_map(&self, id: &str) -> Option<HashMap<String, String>> {
+        let mut app_props = HashMap::new();
+        app_props.insert(PI_DRIVE_SYNC_PROPS_KEY.into(), id.to_owned());
+        Some(app_props)
+    }
+
+    ///Size and md5Checksum of the file Drive holds, None if it is gone or trashed
+    fn remote_stamp(&self, drive_id: &str) -> PiSyncResult<Option<(u64, String)>> {
+        let result = self
+            .get_hub()?
+            .files()
+            .get(drive_id)
+            .param("fields", "id,size,md5Checksum,trashed")
+            .supports_all_drives(true)
+            .doit();
+        match result {
+            Ok((_, file)) if file.trashed == Some(true) => Ok(None),
+            Ok((_, file)) => Ok(Some((
+                file.size.and_then(|s| s.parse().ok()).unwrap_or(0),
+                file.md5_checksum.unwrap_or_default(),
+            ))),
+            Err(Error::BadRequest(ref e)) if e.error.code == 404 => Ok(None),
+            Err(e) => {
+                error!(log, "Failed to get {} {}", drive_id, e);
+                Err(SyncerErrors::ProviderError)
+            }
+        }
+    }
+
+    ///Replace the content of a file Drive already has, it keeps its id
+    fn update_content(&self, s: &SyncableFile, drive_id: &str) -> PiSyncResult<Option<String>> {
+        let file = std::fs::File::open(s.local_path()).map_err(|_e| {
+            error!(log, "File deleted before we got to it");
+            SyncerErrors::ProviderError
+        })?;
+        self.get_hub()?
+            .files()
+            .update(drive3::File::default(), drive_id)
+            .supports_all_drives(true)
+            .keep_revision_forever(false)
+            .upload_resumable(
+                Throttled::new(file, &self.bandwidth),
+                "application/octet-stream".parse().unwrap(),
+            )
+            .map_err(|e| {
+                error!(
+                    log,
+                    "Failed to update {:?} ({}) {}",
+                    s.local_path(),
+                    drive_id,
+                    e
+                );
+                SyncerErrors::ProviderError
+            })?;
+        debug!(log, "Updated {:?} ({})", s.local_path(), drive_id);
+        Ok(self.state.recorded(s, Some(drive_id.to_owned())))
     }
 
-    ///TODO: Panic Central
+    ///Ask Drive for the file tagged with this pi_sync_id, the state db is not consulted
+    fn find(&self, b64_id: &str) -> PiSyncResult<Option<String>> {
+        let q = &format!(
+            "{} {{ key='{}' and value='{}' }} and {} = {}",
+            "appProperties has ", PI_DRIVE_SYNC_PROPS_KEY, b64_id, "trashed", "false"
+        );
+
+        trace!(log, "Query {:?}", q);
+
+        let h = &self.get_hub()?;
+
+        let result = h.files().list().q(q).doit();
+
+        match result {
+            Err(e) => match e {
+                Error::HttpError(_)
+                | Error::MissingAPIKey
+                | Error::MissingToken(_)
+                | Error::Cancelled
+                | Error::UploadSizeLimitExceeded(_, _)
+                | Error::Failure(_)
+                | Error::BadRequest(_)
+                | Error::FieldClash(_)
+                | Error::JsonDecodeError(_, _) => {
+                    error!(log, "Failed to invoke upload api {}", e);
+                    Err(SyncerErrors::ProviderError)
+                }
+            },
+            Ok(res) => {
+                trace!(log, "Id Query Success {:?}", res);
+                let drive_id = res.1.files.and_then(|mut fv| {
+                    let drive_id = fv.pop()?.id.unwrap();
+
+                    if fv.len() > 1 {
+                        warn!(
+                            log,
+                            "More than one file returned when searching for pi_sync_id = {:?}",
+                            b64_id
+                        );
+                    }
+                    Some(drive_id)
+                });
+                Ok(drive_id)
+            }
+        }
+    }
+
+    ///files.create a folder tagged with uid, returning its drive id
+    fn mkdir(&self, name: &str, parent_id: Option<&str>, uid: &str) -> PiSyncResult<String> {
+        let temp_file = tempfile().map_err(|_e| {
+            error!(log, "Cannot create temp file");
+            SyncerErrors::InvalidPathError
+        })?;
+
+        let req = drive3::File {
+            name: Some(name.to_owned()),
+            parents: parent_id.map(|p| vec![p.to_owned()]),
+            app_properties: self.app_props_map(uid),
+            mime_type: Some(FOLDER_MIME.to_string()),
+            ..Default::default()
+        };
+
+        trace!(log, "Sending Request {:?}", req);
+
+        let result = self
+            .get_hub()?
+            .files()
+            .create(req)
+            .upload(temp_file, FOLDER_MIME.parse().unwrap());
+
+        match result {
+            Err(e) => match e {
+                // The Error enum provides details about what exactly happened.
+                // You can also just use its `trace`, `Display` or `Error` traits
+                Error::HttpError(_)
+                | Error::MissingAPIKey
+                | Error::MissingToken(_)
+                | Error::Cancelled
+                | Error::UploadSizeLimitExceeded(_, _)
+                | Error::Failure(_)
+                | Error::BadRequest(_)
+                | Error::FieldClash(_)
+                | Error::JsonDecodeError(_, _) => {
+                    error!(log, "Failed to invoke mkdir API {:?}", e);
+                    Err(SyncerErrors::ProviderError)
+                }
+            },
+            Ok(res) => {
+                trace!(log, "Success, dir  created: {:?}", res);
+                res.1.id.ok_or_else(|| {
+                    error!(log, "Drive gave no id for new dir {}", name);
+                    SyncerErrors::ProviderError
+                })
+            }
+        }
+    }
+
+    ///The folders above a root's own, e.g. logs for a remote_root of logs/motion, each tagged
+    ///with the pi_sync_id of its remote path. Returns the id of the last, None if there are none
+    fn create_remote_parents(&self, cloud_path: &Path) -> PiSyncResult<Option<String>> {
+        let mut parent_id = None;
+        let mut remote = PathBuf::new();
+        for dir in cloud_path.parent().into_iter().flat_map(|p| p.iter()) {
+            remote.push(dir);
+            let name = dir.to_str().ok_or(SyncerErrors::InvalidPathError)?;
+            let uid = encode(remote.to_str().ok_or(SyncerErrors::InvalidPathError)?);
+            parent_id = match self.find(&uid)? {
+                Some(drive_id) => Some(drive_id),
+                None => Some(self.mkdir(name, parent_id.as_deref(), &uid)?),
+            };
+        }
+        Ok(parent_id)
+    }
+
+    ///Create the folders above syncable that Drive does not have yet, top down. Fails if a
+    ///parent cannot be found or a folder cannot be created, rather than uploading to the wrong place
     fn create_path(&self, syncable: &SyncableFile) -> PiSyncResult<bool> {
         debug!(log, "create path for {:?}", syncable.local_path());
+        //a worker that panicked while holding this left no half made folder behind, carry on
+        let _creating = CREATE_PATH.lock().unwrap_or_else(|e| e.into_inner());
 
-        let mut rel_path = syncable
+        let rel_path = syncable
             .local_path()
-            .strip_prefix("/var/www/RpiCamera")
-            .unwrap();
+            .strip_prefix(syncable.root()?.local_root())?;
 
         let components: Vec<_> = rel_path.components().map(|comp| comp.as_os_str()).collect();
 
         debug!(log, "components {:?}", components);
 
-        let file_name_index = components.len() - 1;
-        let mut last_dir = String::from("/var/www/RpiCamera/");
+        let file_name_index = components.len().saturating_sub(1);
+        let mut last_dir = format!(
+            "{}/",
+            syncable
+                .root()?
+                .local_root()
+                .to_str()
+                .ok_or(SyncerErrors::InvalidPathError)?
+                .trim_end_matches('/')
+        );
         for (path_index, dir) in components.iter().enumerate() {
+            let d = dir.to_str().ok_or(SyncerErrors::InvalidPathError)?;
             if path_index != file_name_index {
-                let d = dir.to_str().unwrap();
                 let dir_to_create = format!("{}{}", last_dir, d);
-                let b64_id = SyncableFile::new(dir_to_create.clone()).get_unique_id()?;
-                debug!(log, "Cache check for {:?}", dir_to_create.clone());
-                let c_lock = Arc::clone(&self.cache);
-                //if not in cache
-                if !c_lock.read().unwrap().contains_key(
-                    &SyncableFile::new(dir_to_create.clone())
-                        .get_unique_id()
-                        .unwrap(),
-                ) {
-                    //if does exist on disk
-                    let drive_id = self.id(&dir_to_create).ok().and_then(|id| id);
-                    if drive_id.is_some() {
-                        trace!(
+                //id() answers from the state db when it can, and records what Drive tells it
+                if let Some(drive_id) = self.id(&dir_to_create)? {
+                    trace!(
+                        log,
+                        "create_path: {} exists with drive_id {:?}, not creating",
+                        dir_to_create,
+                        drive_id
+                    );
+                } else {
+                    let parent_id = self.id(&last_dir)?.ok_or_else(|| {
+                        error!(log, "create_path: no drive id for parent {}", last_dir);
+                        SyncerErrors::ProviderError
+                    })?;
+                    match self.create_dir(&dir_to_create, Some(&parent_id))? {
+                        Some(drive_id) => debug!(
                             log,
-                            "create_path: Adding Cache Entry for Existing dir {} with drive_id {:?}",
+                            "create_path: created dir = {} , drive_id={:?}",
                             dir_to_create,
                             drive_id
-                        );
-                        // let c_lock = Arc::clone(&self.cache);
-                        let mut c_lock = c_lock.write().unwrap();
-                        c_lock.insert(b64_id.clone(), drive_id.unwrap(), *CACHE_TTL);
-                    } else {
-                        //create it now, then cache it
-                        let parent_id = self //check_cache
-                            .id(&last_dir)
-                            .ok()
-                            .and_then(|o| o)
-                            .and_then(|s| Some(s))
-                            .unwrap();
-
-                        match self
-                            .create_dir(&dir_to_create.to_owned(), Some(&parent_id.to_owned()))
-                        {
-                            Ok(did) => match did {
-                                Some(drive_id) => {
-                                    let c_lock = Arc::clone(&self.cache);
-                                    let mut cached_w = c_lock.write().unwrap();
-                                    let x = cached_w.insert(
-                                        b64_id.clone(),
-                                        drive_id.clone(),
-                                        *CACHE_TTL,
-                                    );
-                                    debug!(
-                            log,
-                            "create_path: Cache Entry Added for new dir = {} , uid={}, drive_id={:?}",
-                            dir_to_create,
-                            b64_id,
-                            drive_id.to_owned()
-                        );
-                                }
-                                _ => warn!(
-                                    log,
-                                    "invalid drive id returned from call to create dir: {:?}",
-                                    drive_id
-                                ),
-                            },
-                            Err(e) => error!(
+                        ),
+                        None => {
+                            error!(
                                 log,
-                                "invalid drive id returned from call to create dir: {:?}", drive_id
-                            ),
+                                "create_path: no drive id for new dir {}", dir_to_create
+                            );
+                            return Err(SyncerErrors::ProviderError);
                         }
                     }
-                } else {
-                    debug!(log, "Cache hit for {:?}, not creating dir", dir_to_create);
                 }
             }
             //build up the parent path hierarchy with root and last created dir concats
-            last_dir.push_str(format!("{}/", dir.to_str().unwrap().to_string()).as_str());
+            last_dir.push_str(d);
+            last_dir.push('/');
         }
-        Ok(true) //TODO: can panic
+        Ok(true)
     }
 }
 
 impl CloudClient for Drive3Client {
-    fn app_props_map(&self, id: &str) -> Option<HashMap<String, String>> {
-        let mut app_props = HashMap::new();
-        app_props.insert(PI_DRIVE_SYNC_PROPS_KEY.into(), id.to_owned());
-        Some(app_props)
+    fn check_ready(&self) -> PiSyncResult<()> {
+        self.get_hub().map(|_| ())
     }
 
-    fn passes_filter(&self, local_fs_path: &str) -> bool {
-        let s = SyncableFile::new(local_fs_path.to_owned());
-        let filename = s.get_filename().unwrap(); //TODO: p!
-        trace!(log, "Check Filter for {}", filename);
+    ///check_ready never leaves the Pi, about.get is the cheapest call that does
+    fn probe(&self) -> PiSyncResult<()> {
+        self.get_hub()?
+            .about()
+            .get()
+            .param("fields", "kind")
+            .doit()
+            .map(|_| ())
+            .map_err(|e| {
+                debug!(log, "Drive probe failed {}", e);
+                SyncerErrors::ProviderError
+            })
+    }
 
-        if self.filters.len() == 0 {
-            trace!(log, "No filters enabled, {:?} allowed", &s.get_filename());
-            true
-        } else {
-            let matched = self
-                .filters
-                .iter()
-                .map(|val| {
-                    Regex::new(&val).ok().and_then(|re: Regex| {
-                        trace!(log, "Checking {:} against {:?} ", &filename, re);
-                        re.captures(&filename)
-                    })
-                })
-                .map(|captures| captures.is_some())
-                .fold(false, |ordd, regexp_find| {
-                    trace!(log, "{:} || {:?} ", ordd, regexp_find);
-                    ordd || regexp_find
-                });
-            debug!(log, "Passes Filter = {}", matched);
-            matched
+    ///Walks down from each root folder, a files.list per folder
+    fn list_remote(&self) -> PiSyncResult<Vec<RemoteFile>> {
+        let hub = self.get_hub()?;
+        let mut folders = vec![];
+        for root in self.roots.iter() {
+            let root = root
+                .local_root()
+                .to_str()
+                .ok_or(SyncerErrors::InvalidPathError)?;
+            folders.extend(self.id(root)?);
+        }
+        let mut files = vec![];
+        while let Some(folder) = folders.pop() {
+            let q = format!("'{}' in parents and trashed = false", folder);
+            let mut page_token: Option<String> = None;
+            loop {
+                let mut call = hub
+                    .files()
+                    .list()
+                    .q(&q)
+                    .page_size(1000)
+                    .supports_all_drives(true)
+                    .include_items_from_all_drives(true)
+                    .param("fields", LIST_FIELDS);
+                if let Some(token) = &page_token {
+                    call = call.page_token(token);
+                }
+                let (_, list) = call.doit().map_err(|e| {
+                    error!(log, "Failed to list folder {} {}", folder, e);
+                    SyncerErrors::ProviderError
+                })?;
+                for file in list.files.unwrap_or_default() {
+                    if file.mime_type.as_deref() == Some(FOLDER_MIME) {
+                        folders.extend(file.id);
+                    } else if let Some(remote) = remote_file(file) {
+                        files.push(remote);
+                    }
+                }
+                page_token = list.next_page_token;
+                if page_token.is_none() {
+                    break;
+                }
+            }
+        }
+        debug!(log, "Listed {} files on Drive", files.len());
+        Ok(files)
+    }
+
+    fn trash_remote(&self, remote_id: &str) -> PiSyncResult<()> {
+        let req = drive3::File {
+            trashed: Some(true),
+            ..Default::default()
+        };
+        self.get_hub()?
+            .files()
+            .update(req, remote_id)
+            .supports_all_drives(true)
+            .doit_without_upload()
+            .map(|_| ())
+            .map_err(|e| {
+                error!(log, "Failed to trash {} {}", remote_id, e);
+                SyncerErrors::ProviderError
+            })
+    }
+
+    fn remote_md5(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        match self.id(local_path)? {
+            Some(drive_id) => Ok(self.remote_stamp(&drive_id)?.map(|(_size, md5)| md5)),
+            None => Ok(None),
         }
     }
 
     ///Create a remote file, assigned a parent folder - and then return the Storage Service File Id
     fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>> {
-        let s = SyncableFile::new(local_fs_path.to_owned());
+        let s = self.roots.file(local_fs_path);
 
         trace!(
             log,
@@ -247,30 +442,42 @@ impl CloudClient for Drive3Client {
             s.local_path(),
         );
 
-        //build the ancestor file tree on provider if we don't have it
-        self.create_path(&s);
-
-        let parent_path = s.parent_path().unwrap();
-        let parent_path = parent_path.to_str().unwrap();
+        //an upload of a file Drive already has is an update, or nothing if it has not changed
+        if let Some(drive_id) = self.id(local_fs_path)? {
+            match self.remote_stamp(&drive_id)? {
+                Some(remote) if remote == local_stamp(local_fs_path)? => {
+                    debug!(
+                        log,
+                        "{} unchanged since upload ({})", local_fs_path, drive_id
+                    );
+                    return Ok(self.state.recorded(&s, Some(drive_id)));
+                }
+                Some(_) => return self.update_content(&s, &drive_id),
+                None => {
+                    debug!(log, "{} is gone from Drive, uploading again", local_fs_path);
+                    self.state.forget(local_fs_path)?;
+                }
+            }
+        }
 
-        let mut req = drive3::File::default();
-        req.name = Some(
-            s.local_path()
-                .file_name()
-                .unwrap()
-                .to_str()
-                .unwrap()
-                .to_owned(),
-        );
+        //build the ancestor file tree on provider if we don't have it
+        self.create_path(&s)?;
 
-        let parent_path = s.parent_path().unwrap(); //TODO p!
-        let parent_path = parent_path.to_str().unwrap(); //TODO: p!
+        let parent_path = s.parent_path()?;
+        let parent_path = parent_path.to_str().ok_or(SyncerErrors::InvalidPathError)?;
 
-        let parent_id = self.id(parent_path).ok(); // TODO: p!
+        let parent_id = self.id(parent_path)?.ok_or_else(|| {
+            error!(log, "No drive id for {}, not uploading", parent_path);
+            SyncerErrors::ProviderError
+        })?;
         trace!(log, "Parent Id for {:?}=  {:?}", parent_path, parent_id);
 
-        req.parents = parent_id.unwrap().and_then(|p| Some(vec![p.to_owned()])); //TODO P!
-        req.app_properties = self.app_props_map(&s.get_unique_id()?);
+        let req = drive3::File {
+            name: s.get_filename().map(|f| f.to_owned()),
+            parents: Some(vec![parent_id]),
+            app_properties: self.app_props_map(&s.get_unique_id()?),
+            ..Default::default()
+        };
         trace!(log, "Upload Req {:?}", req);
 
         // Values shown here are possibly random and not representative !
@@ -287,7 +494,7 @@ impl CloudClient for Drive3Client {
                 .ignore_default_visibility(true)
                 .enforce_single_parent(true)
                 .upload_resumable(
-                    std::fs::File::open(local_fs_path).unwrap(),
+                    Throttled::new(file, &self.bandwidth),
                     "application/octet-stream".parse().unwrap(),
                 );
 
@@ -310,9 +517,11 @@ impl CloudClient for Drive3Client {
                 },
                 Ok(res) => {
                     trace!(log, "Upload Call Success: {:?}", res);
-                    let drive_id = res.1.id.clone();
-                    let drive_id = drive_id.unwrap();
-                    Ok(Some(drive_id.into()))
+                    let drive_id = res.1.id.clone().ok_or_else(|| {
+                        error!(log, "Drive gave no id for {}", local_fs_path);
+                        SyncerErrors::ProviderError
+                    })?;
+                    Ok(self.state.recorded(&s, Some(drive_id)))
                 }
             }
         } else {
@@ -322,200 +531,696 @@ impl CloudClient for Drive3Client {
     }
 
     ///Create a remote dir in root offset relative, from target-dir
-    ///and then return the Storage Service File Id
+    ///and then return the Storage Service File Id. A root's folder has no parent_id, it goes
+    ///under the folders for the rest of its remote_root, which are created if need be
     fn create_dir(
         &self,
         local_fs_path: &str,
         parent_id: Option<&str>,
     ) -> PiSyncResult<Option<String>> {
-        let s = SyncableFile::new(local_fs_path.to_owned());
+        let s = self.roots.file(local_fs_path);
+        let cloud_path = s.cloud_path()?;
         trace!(
             log,
             "Create dir:: Remote Dir to create {:?} from local {:?}",
-            s.cloud_path(),
+            cloud_path,
             s.local_path()
         );
 
-        let temp_file = tempfile().or_else(|_e| {
-            error!(log, "Cannot create temp file");
-            Err(SyncerErrors::InvalidPathError)
-        });
-
-        let mut req = drive3::File::default();
-        req.name = s
-            .local_path()
+        let name = cloud_path
             .file_name()
-            .and_then(|p| Some(p.to_str().unwrap().to_owned()));
+            .and_then(|p| p.to_str())
+            .ok_or(SyncerErrors::InvalidPathError)?;
+        let parent_id = match parent_id {
+            Some(parent_id) => Some(parent_id.to_owned()),
+            None => self.create_remote_parents(&cloud_path)?,
+        };
+        let drive_id = self.mkdir(name, parent_id.as_deref(), &s.get_unique_id()?)?;
+        trace!(
+            log,
+            "localpath = {:?} ,uid = {:?}, drive id ={}",
+            s.local_path(),
+            s.get_unique_id(),
+            drive_id
+        );
 
-        req.parents = parent_id.and_then(|p| Some(vec![p.to_owned()]));
-        req.app_properties = self.app_props_map(&s.get_unique_id()?);
-        req.mime_type = Some("application/vnd.google-apps.folder".to_string());
+        Ok(self.state.recorded(&s, Some(drive_id)))
+    }
 
-        trace!(log, "Sending Request {:?}", req);
+    ///Drive trashes or deletes a folder's contents along with it
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()> {
+        let drive_id = match self.id(local_path)? {
+            Some(drive_id) => drive_id,
+            None => {
+                debug!(log, "{} was never synced, nothing to delete", local_path);
+                return Ok(());
+            }
+        };
 
-        let result = self.get_hub()?.files().create(req).upload(
-            temp_file.unwrap(),
-            "application/vnd.google-apps.folder".parse().unwrap(),
+        let hub = self.get_hub()?;
+        let result = if trash {
+            let req = drive3::File {
+                trashed: Some(true),
+                ..Default::default()
+            };
+            hub.files()
+                .update(req, &drive_id)
+                .supports_all_drives(true)
+                .doit_without_upload()
+                .map(|_| ())
+        } else {
+            hub.files()
+                .delete(&drive_id)
+                .supports_all_drives(true)
+                .doit()
+                .map(|_| ())
+        };
+        result.map_err(|e| {
+            error!(log, "Failed to remove {} ({}) {}", local_path, drive_id, e);
+            SyncerErrors::ProviderError
+        })?;
+        debug!(
+            log,
+            "{} {} ({})",
+            if trash { "Trashed" } else { "Deleted" },
+            local_path,
+            drive_id
         );
+        self.state.forget(local_path)
+    }
 
-        match result {
-            Err(e) => match e {
-                // The Error enum provides details about what exactly happened.
-                // You can also just use its `trace`, `Display` or `Error` traits
-                Error::HttpError(_)
-                | Error::MissingAPIKey
-                | Error::MissingToken(_)
-                | Error::Cancelled
-                | Error::UploadSizeLimitExceeded(_, _)
-                | Error::Failure(_)
-                | Error::BadRequest(_)
-                | Error::FieldClash(_)
-                | Error::JsonDecodeError(_, _) => {
-                    error!(log, "Failed to invoke mkdir API {:?}", e);
-                    Err(SyncerErrors::ProviderError)
-                }
-            },
-            Ok(res) => {
-                trace!(log, "Success, dir  created: {:?}", res);
-                let uid = s.get_unique_id().unwrap();
-
-                let drive_id = res.1.id.clone();
-                let drive_id = drive_id.unwrap();
-                trace!(
-                    log,
-                    "localpath = {} ,uid = {:?}, drive id ={}",
-                    s.local_path().to_str().unwrap(),
-                    uid,
-                    drive_id.clone()
-                );
+    ///A rename or move is a files.update of the existing file, reparented if its folder changed.
+    ///Everything under a renamed folder gets its new pi_sync_id
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>> {
+        let drive_id = match self.id(from)? {
+            Some(drive_id) => drive_id,
+            None => {
+                debug!(log, "{} was never synced, nothing to rename", from);
+                return Ok(None);
+            }
+        };
+        let f = self.roots.file(from);
+        let t = self.roots.file(to);
+        //the children keep their drive ids, find them while their old pi_sync_ids still work
+        let mut children = vec![];
+        for (old, new) in moved_children(from, to) {
+            if let Some(child_id) = self.id(&old)? {
+                children.push((new, child_id));
+            }
+        }
 
-                let c_lock = Arc::clone(&self.cache);
-                let mut cached_w = c_lock.write().unwrap();
-                let x = cached_w.insert(uid.clone(), drive_id.clone(), *CACHE_TTL);
-                debug!(
-                    log,
-                    "Cache Entry Added for uid={}, dir={}, drive_id={}",
-                    uid,
-                    local_fs_path,
-                    &drive_id
-                );
+        self.create_path(&t)?;
+        let old_parent = self.id(f
+            .parent_path()?
+            .to_str()
+            .ok_or(SyncerErrors::InvalidPathError)?)?;
+        let new_parent = self.id(t
+            .parent_path()?
+            .to_str()
+            .ok_or(SyncerErrors::InvalidPathError)?)?;
 
-                Ok(Some(drive_id.into()))
+        let req = drive3::File {
+            name: t.get_filename().map(|f| f.to_owned()),
+            app_properties: self.app_props_map(&t.get_unique_id()?),
+            ..Default::default()
+        };
+        let hub = self.get_hub()?;
+        let mut call = hub.files().update(req, &drive_id).supports_all_drives(true);
+        if old_parent != new_parent {
+            if let Some(old_parent) = &old_parent {
+                call = call.remove_parents(old_parent);
+            }
+            if let Some(new_parent) = &new_parent {
+                call = call.add_parents(new_parent);
             }
         }
+        call.doit_without_upload().map_err(|e| {
+            error!(
+                log,
+                "Failed to rename {} to {} ({}) {}", from, to, drive_id, e
+            );
+            SyncerErrors::ProviderError
+        })?;
+        debug!(log, "Renamed {} to {} ({})", from, to, drive_id);
+
+        for (new, child_id) in &children {
+            let req = drive3::File {
+                app_properties: self.app_props_map(&self.roots.file(new).get_unique_id()?),
+                ..Default::default()
+            };
+            hub.files()
+                .update(req, child_id)
+                .supports_all_drives(true)
+                .doit_without_upload()
+                .map_err(|e| {
+                    error!(log, "Failed to retag {} ({}) {}", new, child_id, e);
+                    SyncerErrors::ProviderError
+                })?;
+        }
+
+        self.state.forget(from)?;
+        for (new, child_id) in children {
+            self.state.recorded(&self.roots.file(&new), Some(child_id));
+        }
+        Ok(self.state.recorded(&t, Some(drive_id)))
     }
 
     ///Query Google for the pi-sync-id, validating if this dir exists or not
     fn id(&self, local_path: &str) -> PiSyncResult<Option<String>> {
         trace!(log, "Search for Google Drive Id for {}", local_path);
-        let s = SyncableFile::new(local_path.to_owned());
+        let s = self.roots.file(local_path);
         let b64_id = s.get_unique_id()?;
         debug!(
             log,
             "Base64 Unique ID = {} for file {:?}", b64_id, local_path
         );
-        //{
-        /*let c_lock = Arc::clone(&self.cache);
-            //check cache
-            if c_lock.read().unwrap().contains_key(&b64_id) {
-                trace!(log, "Get Drive ID, cache hit for {}", local_path);
-                return Ok(Some(
-                    c_lock.read().unwrap().get(&b64_id).unwrap().to_owned(),
-                ));
-            }
-        }*/
-
-        let q = &format!(
-            "{} {{ key='{}' and value='{}' }} and {} = {}",
-            "appProperties has ", PI_DRIVE_SYNC_PROPS_KEY, b64_id, "trashed", "false"
-        );
-
-        trace!(log, "Query {:?}", q);
-
-        let h = &self.get_hub()?;
+        if let Some(drive_id) = self.state.remote_id(&s)? {
+            return Ok(Some(drive_id));
+        }
 
-        let result = h.files().list().q(q).doit();
+        Ok(self.state.recorded(&s, self.find(&b64_id)?))
+    }
+}
 
-        match result {
-            Err(e) => match e {
-                Error::HttpError(_)
-                | Error::MissingAPIKey
-                | Error::MissingToken(_)
-                | Error::Cancelled
-                | Error::UploadSizeLimitExceeded(_, _)
-                | Error::Failure(_)
-                | Error::BadRequest(_)
-                | Error::FieldClash(_)
-                | Error::JsonDecodeError(_, _) => {
-                    error!(log, "Failed to invoke upload api {}", e);
-                    Err(SyncerErrors::ProviderError)
-                }
-            },
-            Ok(res) => {
-                trace!(log, "Id Query Success {:?}", res);
-                Ok(res.1.files.and_then(|mut fv| {
-                    let drive_id = fv.pop()?.id.unwrap();
+///A listed file, None unless it carries a pi_sync_id
+fn remote_file(file: drive3::File) -> Option<RemoteFile> {
+    let pi_sync_id = file.app_properties?.remove(PI_DRIVE_SYNC_PROPS_KEY)?;
+    let cloud_path = String::from_utf8(base64::decode(&pi_sync_id).ok()?).ok()?;
+    let created = file
+        .created_time
+        .and_then(|t| DateTime::parse_from_rfc3339(&t).ok())
+        .map(|t| t.with_timezone(&Utc))
+        .unwrap_or_else(Utc::now);
+    Some(RemoteFile::new(
+        file.id?,
+        pi_sync_id,
+        cloud_path.into(),
+        file.size.and_then(|s| s.parse().ok()).unwrap_or(0),
+        file.md5_checksum,
+        created,
+    ))
+}
 
-                    if fv.len() > 1 {
-                        warn!(
-                            log,
-                            "More than one file returned when searching for pi_sync_id = {:?}",
-                            b64_id
-                        );
-                    }
-                    Some(drive_id)
-                }))
-            }
-        }
-    }
+///Size and md5 of the local file, to compare with remote_stamp
+fn local_stamp(local_path: &str) -> PiSyncResult<(u64, String)> {
+    let size = std::fs::metadata(local_path)
+        .map_err(|_e| {
+            error!(log, "File deleted before we got to it");
+            SyncerErrors::ProviderError
+        })?
+        .len();
+    Ok((size, md5_hex(local_path)?))
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::drive_auth::AuthFlow;
     use crate::drive_cli::*;
-    use crate::upload_handler::{FileOperations, SyncableFile};
+    use crate::fake_drive::{fake_drive_server, service_account_key, REFRESH_TOKEN};
+    use crate::upload_handler::FileOperations;
+    use crate::upload_handler::RootMapping;
+    use std::path::Path;
+    use tempfile::tempdir;
+
+    ///The watched dir, inside the test's tempdir
+    fn root_dir(dir: &Path) -> String {
+        format!("{}/{}", dir.to_str().unwrap(), "RpiCamera")
+    }
+
+    fn roots(dir: &Path) -> Roots {
+        Roots::from(RootMapping::new(
+            root_dir(dir).into(),
+            "RpiCamera".into(),
+            vec![],
+        ))
+    }
+
+    fn state_db() -> Arc<StateDb> {
+        Arc::new(StateDb::open_in_memory().unwrap())
+    }
+
+    #[test]
+    fn test_drive_cli_refreshes_token() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        assert!(dc.check_ready().is_ok());
+        //the seeded token is expired, so the client must have gone to our token endpoint
+        assert_eq!(1, state.lock().unwrap().token_refreshes);
+    }
+
+    #[test]
+    fn test_drive_cli_probe() {
+        let dir = tempdir().unwrap();
+        let (opts, _state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts.clone(), roots(dir.path()), state_db());
+        assert!(dc.probe().is_ok());
+
+        //nothing listening, as when the link is down
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut down = opts;
+        down.api_root = Some(format!("http://127.0.0.1:{}/", port));
+        let dc = Drive3Client::new(down, roots(dir.path()), state_db());
+        assert!(dc.check_ready().is_ok());
+        assert!(dc.probe().is_err());
+    }
+
+    #[test]
+    fn test_drive_cli_no_secret() {
+        let dir = tempdir().unwrap();
+        let opts = DriveOptions::new(
+            AuthOptions::new(
+                AuthFlow::Installed,
+                dir.path().join("missing.json").to_str().unwrap().to_owned(),
+                dir.path().join("token.json").to_str().unwrap().to_owned(),
+                None,
+                None,
+            ),
+            None,
+            None,
+            None,
+            Arc::default(),
+        );
+        assert!(Drive3Client::new(opts, roots(dir.path()), state_db())
+            .check_ready()
+            .is_err());
+    }
+
+    #[test]
+    fn test_drive_cli_service_account() {
+        let dir = tempdir().unwrap();
+        let (mut opts, state) = fake_drive_server(dir.path());
+        opts.auth = AuthOptions::new(
+            AuthFlow::ServiceAccount,
+            String::new(),
+            String::new(),
+            Some(service_account_key(dir.path())),
+            Some("cam@example.com".into()),
+        );
+        let dc = Drive3Client::new(opts.clone(), roots(dir.path()), state_db());
+
+        assert!(dc.probe().is_ok());
+        assert!(dc
+            .create_dir(&root_dir(dir.path()), None)
+            .unwrap()
+            .is_some());
+        let drive = state.lock().unwrap();
+        assert_eq!(0, drive.token_refreshes);
+        assert_eq!(1, drive.jwt_claims.len());
+        let claims = &drive.jwt_claims[0];
+        assert_eq!("pi-sync@example.iam.gserviceaccount.com", claims["iss"]);
+        assert_eq!("cam@example.com", claims["sub"]);
+        assert_eq!(DRIVE_SCOPES.join(" "), claims["scope"]);
+        assert_eq!(opts.token_uri.as_deref().unwrap(), claims["aud"]);
+    }
+
+    #[test]
+    fn test_drive_cli_device_code() {
+        let dir = tempdir().unwrap();
+        let (mut opts, state) = fake_drive_server(dir.path());
+        let token_file = dir.path().join("token.json");
+        std::fs::remove_file(&token_file).unwrap();
+        opts.auth = AuthOptions::new(
+            AuthFlow::DeviceCode,
+            dir.path()
+                .join("drive3-secret.json")
+                .to_str()
+                .unwrap()
+                .into(),
+            token_file.to_str().unwrap().into(),
+            None,
+            None,
+        );
+        let dc = Drive3Client::new(opts.clone(), roots(dir.path()), state_db());
+
+        assert!(dc.probe().is_ok());
+        {
+            let drive = state.lock().unwrap();
+            assert_eq!((1, 2), (drive.device_codes, drive.device_polls));
+        }
+        let stored = std::fs::read_to_string(&token_file).unwrap();
+        assert!(stored.contains(REFRESH_TOKEN));
+
+        //logged in, so a restart uses the stored token
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        assert!(dc.probe().is_ok());
+        let drive = state.lock().unwrap();
+        assert_eq!(
+            (1, 2, 0),
+            (
+                drive.device_codes,
+                drive.device_polls,
+                drive.token_refreshes
+            )
+        );
+    }
 
     #[test]
     fn test_drive_cli_create_dir() {
-        let dc = Drive3Client::new(
-            "/home/alan/.google-service-cli/drive3-secret.json".to_owned(),
-            vec![],
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+
+        let id = dc.create_dir(&root_dir(dir.path()), None).unwrap().unwrap();
+        let state = state.lock().unwrap();
+        let folder = state.by_name("RpiCamera").unwrap();
+        assert_eq!(Some(id), folder.id);
+        assert_eq!(
+            Some("application/vnd.google-apps.folder"),
+            folder.mime_type.as_deref()
+        );
+        assert_eq!(
+            Some(
+                &dc.roots
+                    .file(&root_dir(dir.path()))
+                    .get_unique_id()
+                    .unwrap()
+            ),
+            folder.app_properties.as_ref().unwrap().get("pi_sync_id")
         );
-        let d = "/tmp/pi_sync/images/new_dir";
-        let r = dc.create_dir(d, None);
-        println!("Id of new Dir {:?}", r);
-        assert_eq!(r.is_ok(), true);
     }
 
     #[test]
-    fn test_drive_cli_upload_file() {
-        todo!()
+    fn test_drive_cli_create_dir_nested_remote_root() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let roots = |remote_root: &str| {
+            Roots::from(RootMapping::new(
+                root_dir(dir.path()).into(),
+                remote_root.into(),
+                vec![],
+            ))
+        };
+        let dc = Drive3Client::new(opts.clone(), roots("logs/motion"), state_db());
+
+        //named from the remote path, not the watched dir, and nested under logs
+        let id = dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let drive = state.lock().unwrap();
+        let logs = drive.by_name("logs").unwrap();
+        assert_eq!(None, logs.parents);
+        assert_eq!(
+            Some(&encode("logs")),
+            logs.app_properties.as_ref().unwrap().get("pi_sync_id")
+        );
+        let motion = drive.by_name("motion").unwrap();
+        assert_eq!(id, motion.id);
+        assert_eq!(Some(vec![logs.id.clone().unwrap()]), motion.parents);
+        assert!(drive.by_name("RpiCamera").is_none());
+        drop(drive);
+
+        //another root under logs reuses it
+        let dc = Drive3Client::new(opts, roots("logs/stills"), state_db());
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let drive = state.lock().unwrap();
+        assert_eq!(3, drive.files.len());
+        assert_eq!(
+            drive.by_name("logs").unwrap().id.clone().map(|p| vec![p]),
+            drive.by_name("stills").unwrap().parents
+        );
     }
 
-    ///TODO: this is leaveing state on provider, will fail on second run
     #[test]
     fn test_drive_cli_id() {
-        let dc = Drive3Client::new(
-            "/home/alan/.google-service-cli/drive3-secret.json".to_owned(),
-            vec![],
+        let dir = tempdir().unwrap();
+        let (opts, _state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+
+        assert_eq!(None, dc.id(&root_dir(dir.path())).unwrap());
+        let created = dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        assert!(created.is_some());
+        assert_eq!(created, dc.id(&root_dir(dir.path())).unwrap());
+    }
+
+    #[test]
+    fn test_drive_cli_id_from_state_db() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(root_dir(dir.path())).unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let db = state_db();
+        let dc = Drive3Client::new(opts.clone(), roots(dir.path()), Arc::clone(&db));
+        let created = dc.create_dir(&root_dir(dir.path()), None).unwrap();
+
+        //a restarted client asks the state db, Drive's own listing is not consulted
+        state.lock().unwrap().files.clear();
+        let dc = Drive3Client::new(opts, roots(dir.path()), db);
+        assert_eq!(created, dc.id(&root_dir(dir.path())).unwrap());
+    }
+
+    #[test]
+    fn test_drive_cli_delete() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let local_dir = format!("{}/a", root_dir(dir.path()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+        let mut uploaded = vec![];
+        for f in &["im1.jpg", "im2.jpg"] {
+            let local = format!("{}/{}", local_dir, f);
+            std::fs::write(&local, b"jpeg").unwrap();
+            uploaded.push(local.clone());
+            dc.upload_file(&local).unwrap();
+        }
+
+        //trashing the folder takes what is in it too
+        dc.delete(&local_dir, true).unwrap();
+        assert_eq!(None, dc.id(&local_dir).unwrap());
+        assert_eq!(None, dc.id(&uploaded[0]).unwrap());
+        assert_eq!(
+            Some(true),
+            state.lock().unwrap().by_name("im2.jpg").unwrap().trashed
         );
-        let d = "/tmp/pi_sync/images/new_dir";
 
-        let drive_id_for_parent = dc.id(SyncableFile::new(d.to_owned())
-            .parent_path()
-            .unwrap()
-            .to_str()
-            .unwrap());
+        let local = format!("{}/im3.jpg", root_dir(dir.path()));
+        std::fs::write(&local, b"jpeg").unwrap();
+        dc.upload_file(&local).unwrap();
+        dc.delete(&local, false).unwrap();
+        assert!(state.lock().unwrap().by_name("im3.jpg").is_none());
+
+        //never synced is not an error
+        dc.delete(&local, false).unwrap();
+    }
 
-        println!(" parent gdrive id = {:?}", drive_id_for_parent);
-        assert_eq!(1, 2);
+    #[test]
+    fn test_drive_cli_rename() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let from = format!("{}/a", root_dir(dir.path()));
+        std::fs::create_dir_all(&from).unwrap();
+        let old_file = format!("{}/im1.jpg", from);
+        std::fs::write(&old_file, b"jpeg").unwrap();
+        let file_id = dc.upload_file(&old_file).unwrap();
+
+        //a folder moved under another keeps its id, and its file keeps its id too
+        let to = format!("{}/b/c", root_dir(dir.path()));
+        std::fs::create_dir_all(format!("{}/b", root_dir(dir.path()))).unwrap();
+        std::fs::rename(&from, &to).unwrap();
+        let folder_id = dc.rename(&from, &to).unwrap();
+        let new_file = format!("{}/im1.jpg", to);
+        assert_eq!(file_id, dc.id(&new_file).unwrap());
+        assert_eq!(None, dc.id(&old_file).unwrap());
+
+        let drive = state.lock().unwrap();
+        let folder = drive.by_name("c").unwrap();
+        assert_eq!(folder_id, folder.id);
+        assert_eq!(
+            Some(vec![drive.by_name("b").unwrap().id.clone().unwrap()]),
+            folder.parents
+        );
+        assert!(drive.by_name("a").is_none());
+        //found on Drive by its new pi_sync_id, not just the state db
+        let moved = drive.by_name("im1.jpg").unwrap();
+        assert_eq!(
+            Some(&dc.roots.file(&new_file).get_unique_id().unwrap()),
+            moved.app_properties.as_ref().unwrap().get("pi_sync_id")
+        );
+        drop(drive);
+
+        //never synced
+        assert_eq!(None, dc.rename(&old_file, &new_file).unwrap());
     }
 
     #[test]
     fn test_create_path() {
-        let s = Drive3Client::create_path(&SyncableFile::new(
-            "/var/www/RpiCamera/1/2/3/4/im1.jpg".to_string(),
-        ));
-        assert_eq!(true, s.is_ok());
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        let root_id = dc.create_dir(&root_dir(dir.path()), None).unwrap();
+
+        let s = dc
+            .roots
+            .file(&format!("{}/1/2/3/4/im1.jpg", root_dir(dir.path())));
+        assert!(dc.create_path(&s).is_ok());
+
+        //each folder is created under the one before it
+        let drive = state.lock().unwrap();
+        let mut parent = root_id;
+        for name in &["1", "2", "3", "4"] {
+            let folder = drive.by_name(name).unwrap();
+            assert_eq!(parent.map(|p| vec![p]), folder.parents);
+            parent = folder.id.clone();
+        }
+        assert_eq!(5, drive.files.len());
+        drop(drive);
+
+        //a second file in the same dir is a state db hit, nothing more is created
+        let s = dc
+            .roots
+            .file(&format!("{}/1/2/3/4/im2.jpg", root_dir(dir.path())));
+        assert!(dc.create_path(&s).is_ok());
+        assert_eq!(5, state.lock().unwrap().files.len());
+    }
+
+    #[test]
+    fn test_create_path_no_parent() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        let s = dc
+            .roots
+            .file(&format!("{}/1/im1.jpg", root_dir(dir.path())));
+
+        //the root folder was never created, an error rather than a panic holding the lock
+        assert!(dc.create_path(&s).is_err());
+        assert!(state.lock().unwrap().files.is_empty());
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        assert!(dc.create_path(&s).is_ok());
+        assert!(state.lock().unwrap().by_name("1").is_some());
+    }
+
+    #[test]
+    fn test_create_path_concurrent() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let db = state_db();
+        Drive3Client::new(opts.clone(), roots(dir.path()), Arc::clone(&db))
+            .create_dir(&root_dir(dir.path()), None)
+            .unwrap();
+        let local_dir = format!("{}/a/b", root_dir(dir.path()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+
+        //two workers, each with its own client, uploading into the same new folders
+        let workers: Vec<_> = (0..2)
+            .map(|n| {
+                let dc = Drive3Client::new(opts.clone(), roots(dir.path()), Arc::clone(&db));
+                let local = format!("{}/im{}.jpg", local_dir, n);
+                std::fs::write(&local, b"jpeg").unwrap();
+                std::thread::spawn(move || dc.upload_file(&local).unwrap())
+            })
+            .collect();
+        for worker in workers {
+            assert!(worker.join().unwrap().is_some());
+        }
+        let drive = state.lock().unwrap();
+        for name in &["a", "b"] {
+            let folders = drive
+                .files
+                .iter()
+                .filter(|f| f.name.as_deref() == Some(name))
+                .count();
+            assert_eq!(1, folders, "{}", name);
+        }
+    }
+
+    #[test]
+    fn test_drive_cli_upload_file() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+
+        let local_dir = format!("{}/drive_test/a", root_dir(dir.path()));
+        std::fs::create_dir_all(&local_dir).unwrap();
+        let local = format!("{}/im1.jpg", local_dir);
+        std::fs::write(&local, b"not really a jpeg").unwrap();
+
+        let id = dc.upload_file(&local).unwrap().unwrap();
+        assert_eq!(Some(id.clone()), dc.id(&local).unwrap());
+
+        let state = state.lock().unwrap();
+        let uploaded = state.by_name("im1.jpg").unwrap();
+        assert_eq!(Some(&id), uploaded.id.as_ref());
+        assert_eq!(
+            Some(vec![state.by_name("a").unwrap().id.clone().unwrap()]),
+            uploaded.parents
+        );
+        assert_eq!(Some(&b"not really a jpeg".to_vec()), state.content.get(&id));
+        assert_eq!(4, state.files.len());
+    }
+
+    #[test]
+    fn test_drive_cli_list_and_trash_remote() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        std::fs::create_dir_all(format!("{}/a", root_dir(dir.path()))).unwrap();
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let im1 = format!("{}/a/im1.jpg", root_dir(dir.path()));
+        let vi1 = format!("{}/vi1.mp4", root_dir(dir.path()));
+        std::fs::write(&im1, b"jpeg").unwrap();
+        std::fs::write(&vi1, b"mpeg4").unwrap();
+        let im1_id = dc.upload_file(&im1).unwrap().unwrap();
+        dc.upload_file(&vi1).unwrap();
+
+        let mut listed = dc.list_remote().unwrap();
+        listed.sort_by(|a, b| a.cloud_path.cmp(&b.cloud_path));
+        let paths: Vec<_> = listed.iter().map(|f| f.cloud_path.clone()).collect();
+        assert_eq!(
+            vec![
+                std::path::PathBuf::from("RpiCamera/a/im1.jpg"),
+                "RpiCamera/vi1.mp4".into()
+            ],
+            paths
+        );
+        assert_eq!(im1_id, listed[0].remote_id);
+        assert_eq!(4, listed[0].size);
+        assert_eq!(Some(md5_hex(&im1).unwrap()), listed[0].md5);
+
+        //trashed, but still recorded so a scan does not upload it again
+        dc.trash_remote(&im1_id).unwrap();
+        assert_eq!(1, dc.list_remote().unwrap().len());
+        assert_eq!(Some(im1_id), dc.id(&im1).unwrap());
+        assert_eq!(4, state.lock().unwrap().files.len());
+    }
+
+    #[test]
+    fn test_drive_cli_upload_updates_in_place() {
+        let dir = tempdir().unwrap();
+        let (opts, state) = fake_drive_server(dir.path());
+        let dc = Drive3Client::new(opts, roots(dir.path()), state_db());
+        std::fs::create_dir(root_dir(dir.path())).unwrap();
+        dc.create_dir(&root_dir(dir.path()), None).unwrap();
+        let local = format!("{}/im1.jpg", root_dir(dir.path()));
+        std::fs::write(&local, b"jpeg").unwrap();
+        let id = dc.upload_file(&local).unwrap().unwrap();
+
+        //triggered again unchanged, nothing is sent
+        assert_eq!(Some(id.clone()), dc.upload_file(&local).unwrap());
+        std::fs::write(&local, b"jpeg, rewritten").unwrap();
+        assert_eq!(Some(id.clone()), dc.upload_file(&local).unwrap());
+        let drive = state.lock().unwrap();
+        assert_eq!(2, drive.files.len());
+        assert_eq!(Some(&b"jpeg, rewritten".to_vec()), drive.content.get(&id));
+        drop(drive);
+        assert_eq!(
+            Some(md5_hex(&local).unwrap()),
+            dc.remote_md5(&local).unwrap()
+        );
+
+        //removed from Drive behind our back, it is uploaded again
+        state
+            .lock()
+            .unwrap()
+            .files
+            .retain(|f| f.id.as_ref() != Some(&id));
+        let again = dc.upload_file(&local).unwrap().unwrap();
+        assert_ne!(id, again);
+        assert_eq!(2, state.lock().unwrap().files.len());
     }
 }
@@ -0,0 +1,178 @@
+//!A backend that only says what it would do. Lookups go to the real backend, so filters, remote
+//!paths and which folders are missing are worked out as in a real sync, but nothing that would
+//!change the backend is called
+use crate::cloud_client::{CloudClient, RemoteFile};
+use crate::common::LOG as log;
+use crate::pi_err::PiSyncResult;
+use crate::upload_handler::{FileOperations, Roots};
+use std::path::Path;
+
+pub struct DryRun {
+    inner: Box<dyn CloudClient>,
+    roots: Roots,
+}
+
+impl DryRun {
+    pub fn new(inner: Box<dyn CloudClient>, roots: Roots) -> Self {
+        DryRun { inner, roots }
+    }
+
+    fn cloud_path(&self, local_path: &str) -> String {
+        match self.roots.file(local_path).cloud_path() {
+            Ok(path) => path.display().to_string(),
+            Err(e) => format!("<{} has no remote path: {}>", local_path, e),
+        }
+    }
+
+    ///The folders above local_path, top down, the backend does not have. Once one is missing so
+    ///is everything under it
+    fn missing_dirs(&self, local_path: &str) -> PiSyncResult<Vec<String>> {
+        let s = self.roots.file(local_path);
+        let root = s.root()?.local_root();
+        let mut dirs = Path::new(local_path)
+            .ancestors()
+            .skip(1)
+            .take_while(|dir| dir.starts_with(root) && *dir != root)
+            .filter_map(|dir| dir.to_str())
+            .collect::<Vec<_>>();
+        dirs.reverse();
+        let mut missing = vec![];
+        for dir in dirs {
+            if missing.is_empty() && self.inner.id(dir)?.is_some() {
+                continue;
+            }
+            missing.push(dir.to_owned());
+        }
+        Ok(missing)
+    }
+}
+
+impl CloudClient for DryRun {
+    ///Resolves the folders as create_path does, then logs the upload. Never returns an id, so
+    ///nothing is taken as uploaded
+    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>> {
+        for dir in self.missing_dirs(local_fs_path)? {
+            info!(log, "Would create folder {}", self.cloud_path(&dir));
+        }
+        let how = match self.inner.id(local_fs_path)? {
+            Some(_) => "replace",
+            None => "upload",
+        };
+        info!(
+            log,
+            "Would {} {} as {}",
+            how,
+            local_fs_path,
+            self.cloud_path(local_fs_path)
+        );
+        Ok(None)
+    }
+
+    fn create_dir(
+        &self,
+        local_fs_path: &str,
+        _parent_id: Option<&str>,
+    ) -> PiSyncResult<Option<String>> {
+        info!(
+            log,
+            "Would create folder {}",
+            self.cloud_path(local_fs_path)
+        );
+        Ok(None)
+    }
+
+    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        self.inner.id(local_path)
+    }
+
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()> {
+        info!(
+            log,
+            "Would {} {}",
+            if trash { "trash" } else { "delete" },
+            self.cloud_path(local_path)
+        );
+        Ok(())
+    }
+
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>> {
+        let id = self.inner.id(from)?;
+        if id.is_some() {
+            info!(
+                log,
+                "Would move {} to {}",
+                self.cloud_path(from),
+                self.cloud_path(to)
+            );
+        }
+        Ok(id)
+    }
+
+    fn remote_md5(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        self.inner.remote_md5(local_path)
+    }
+
+    fn list_remote(&self) -> PiSyncResult<Vec<RemoteFile>> {
+        self.inner.list_remote()
+    }
+
+    fn trash_remote(&self, remote_id: &str) -> PiSyncResult<()> {
+        info!(log, "Would trash remote file {}", remote_id);
+        Ok(())
+    }
+
+    fn check_ready(&self) -> PiSyncResult<()> {
+        self.inner.check_ready()
+    }
+
+    fn probe(&self) -> PiSyncResult<()> {
+        self.inner.probe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dry_run::*;
+    use crate::local_fixture::LocalFixture;
+
+    #[test]
+    fn test_dry_run_changes_nothing() {
+        let LocalFixture {
+            local,
+            target,
+            roots,
+            lb,
+            ..
+        } = LocalFixture::new();
+        std::fs::create_dir_all(local.path().join("a/b")).unwrap();
+        let synced = local.path().join("a/im1.jpg");
+        let new = local.path().join("a/b/im2.jpg");
+        std::fs::write(&synced, b"jpeg").unwrap();
+        std::fs::write(&new, b"jpeg").unwrap();
+        let synced = synced.to_str().unwrap();
+        let new = new.to_str().unwrap();
+        lb.upload_file(synced).unwrap();
+        let dr = DryRun::new(Box::new(lb), roots);
+
+        let a = local.path().join("a");
+        let b = a.join("b");
+        assert_eq!(
+            vec![b.to_str().unwrap().to_owned()],
+            dr.missing_dirs(new).unwrap()
+        );
+        assert_eq!(None, dr.upload_file(new).unwrap());
+        assert!(dr.create_dir(b.to_str().unwrap(), None).unwrap().is_none());
+        assert!(dr.delete(synced, true).is_ok());
+        assert!(dr
+            .rename(synced, a.join("im3.jpg").to_str().unwrap())
+            .unwrap()
+            .is_some());
+        assert_eq!(None, dr.rename(new, synced).unwrap());
+
+        let target = target.path().join("RpiCamera/a");
+        assert!(target.join("im1.jpg").exists());
+        assert!(!target.join("b").exists());
+        assert!(!target.join("im3.jpg").exists());
+        assert_eq!(1, dr.list_remote().unwrap().len());
+    }
+}
@@ -0,0 +1,454 @@
+//!An in-process stand in for the Drive v3 API and Google's token endpoint, just enough of
+//!files.create (multipart and resumable), files.update (metadata and resumable), files.get,
+//!files.list, files.delete, about.get, token refresh, service account grants and the device
+//!code flow to run Drive3Client offline
+use crate::drive_auth::{AuthFlow, AuthOptions};
+use crate::drive_cli::{DriveOptions, DRIVE_SCOPES};
+use regex::Regex;
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+
+const BOUNDARY: &str = "MDuXWGyeE33QFXGchb2VFWc4Z7945d";
+pub const REFRESH_TOKEN: &str = "fake-refresh-token";
+
+#[derive(Default)]
+pub struct FakeDrive {
+    pub files: Vec<drive3::File>,
+    ///Uploaded bytes by file id
+    pub content: HashMap<String, Vec<u8>>,
+    pub token_refreshes: usize,
+    ///The claims of every service account JWT we were sent
+    pub jwt_claims: Vec<serde_json::Value>,
+    ///Device codes handed out, and polls for them. The first poll for each is still pending
+    pub device_codes: usize,
+    pub device_polls: usize,
+    access_token: Option<String>,
+    ///Resumable upload sessions, the file being updated if any, its metadata and the bytes
+    ///received so far
+    sessions: HashMap<String, (Option<String>, drive3::File, Vec<u8>)>,
+    next_id: usize,
+}
+
+impl FakeDrive {
+    pub fn by_name(&self, name: &str) -> Option<&drive3::File> {
+        self.files.iter().find(|f| f.name.as_deref() == Some(name))
+    }
+
+    fn create(&mut self, mut file: drive3::File, content: Vec<u8>) -> drive3::File {
+        self.next_id += 1;
+        let id = format!("fake-id-{}", self.next_id);
+        file.id = Some(id.clone());
+        file.created_time
+            .get_or_insert_with(|| chrono::Utc::now().to_rfc3339());
+        self.content.insert(id, content);
+        self.files.push(file.clone());
+        file
+    }
+
+    ///New content for an existing file, the id stays the same
+    fn replace(&mut self, id: &str, content: Vec<u8>) -> Option<drive3::File> {
+        self.content.insert(id.to_owned(), content);
+        self.files
+            .iter()
+            .find(|f| f.id.as_deref() == Some(id))
+            .cloned()
+    }
+
+    ///files.get, with the size and md5Checksum Drive works out from the content
+    fn get(&self, id: &str) -> Option<drive3::File> {
+        let mut file = self
+            .files
+            .iter()
+            .find(|f| f.id.as_deref() == Some(id))
+            .cloned()?;
+        let content = self.content.get(id).cloned().unwrap_or_default();
+        file.size = Some(content.len().to_string());
+        file.md5_checksum = Some(format!("{:x}", md5::compute(&content)));
+        Some(file)
+    }
+
+    ///The ids of a file and, if it is a folder, everything under it
+    fn tree(&self, id: &str) -> Vec<String> {
+        let mut ids = vec![id.to_owned()];
+        let mut i = 0;
+        while i < ids.len() {
+            for f in &self.files {
+                if f.parents
+                    .as_ref()
+                    .map(|p| p.contains(&ids[i]))
+                    .unwrap_or(false)
+                {
+                    ids.push(f.id.clone().unwrap());
+                }
+            }
+            i += 1;
+        }
+        ids
+    }
+
+    ///Trashing a folder trashes what is in it, as on Drive. Parents are moved with the
+    ///comma separated addParents and removeParents
+    fn update(
+        &mut self,
+        id: &str,
+        patch: drive3::File,
+        add_parents: Option<&String>,
+        remove_parents: Option<&String>,
+    ) -> Option<drive3::File> {
+        if patch.trashed == Some(true) {
+            for id in self.tree(id) {
+                if let Some(f) = self.files.iter_mut().find(|f| f.id.as_deref() == Some(&id)) {
+                    f.trashed = Some(true);
+                }
+            }
+        }
+        let file = self
+            .files
+            .iter_mut()
+            .find(|f| f.id.as_deref() == Some(id))?;
+        if patch.name.is_some() {
+            file.name = patch.name;
+        }
+        if let Some(props) = patch.app_properties {
+            file.app_properties
+                .get_or_insert_with(HashMap::new)
+                .extend(props);
+        }
+        let parents = file.parents.get_or_insert_with(Vec::new);
+        if let Some(remove) = remove_parents {
+            parents.retain(|p| !remove.split(',').any(|r| r == p));
+        }
+        if let Some(add) = add_parents {
+            parents.extend(add.split(',').map(|a| a.to_owned()));
+        }
+        self.files
+            .iter()
+            .find(|f| f.id.as_deref() == Some(id))
+            .cloned()
+    }
+
+    fn delete(&mut self, id: &str) -> bool {
+        let tree = self.tree(id);
+        let before = self.files.len();
+        self.files
+            .retain(|f| !tree.contains(f.id.as_ref().unwrap()));
+        before != self.files.len()
+    }
+
+    ///files.list only understands our own queries, appProperties has { key='k' and value='v' }
+    ///and 'id' in parents
+    fn list(&self, q: &str) -> drive3::FileList {
+        let props =
+            Regex::new(r"appProperties has\s*\{\s*key='([^']*)' and value='([^']*)'\s*}").unwrap();
+        let parent = Regex::new(r"'([^']*)' in parents").unwrap();
+        let matches = |f: &drive3::File| match (props.captures(q), parent.captures(q)) {
+            (Some(c), _) => f
+                .app_properties
+                .as_ref()
+                .and_then(|p| p.get(&c[1]))
+                .map(|v| v == &c[2])
+                .unwrap_or(false),
+            (None, Some(c)) => f
+                .parents
+                .as_ref()
+                .map(|p| p.iter().any(|p| p == &c[1]))
+                .unwrap_or(false),
+            (None, None) => false,
+        };
+        let files = self
+            .files
+            .iter()
+            .filter(|f| matches(f))
+            .filter(|f| !q.contains("trashed = false") || f.trashed != Some(true))
+            .filter_map(|f| self.get(f.id.as_ref()?))
+            .collect();
+        drive3::FileList {
+            files: Some(files),
+            ..Default::default()
+        }
+    }
+
+    fn authorized(&self, req: &tiny_http::Request) -> bool {
+        let expected = match &self.access_token {
+            Some(t) => format!("Bearer {}", t),
+            None => return false,
+        };
+        req.headers()
+            .iter()
+            .any(|h| h.field.equiv("Authorization") && h.value.as_str() == expected)
+    }
+}
+
+///The bodies of each part of a multipart/related upload, metadata first
+fn multipart_parts(body: &[u8]) -> Vec<Vec<u8>> {
+    let body = String::from_utf8_lossy(body);
+    body.split(&format!("\r\n--{}", BOUNDARY))
+        .skip(1)
+        .filter_map(|part| {
+            part.find("\r\n\r\n")
+                .map(|i| part.as_bytes()[i + 4..].to_vec())
+        })
+        .collect()
+}
+
+fn header<'a>(req: &'a tiny_http::Request, name: &'static str) -> Option<&'a str> {
+    req.headers()
+        .iter()
+        .find(|h| h.field.equiv(name))
+        .map(|h| h.value.as_str())
+}
+
+fn json_reply(body: String, status: u16) -> tiny_http::Response<std::io::Cursor<Vec<u8>>> {
+    tiny_http::Response::from_string(body)
+        .with_status_code(status)
+        .with_header(
+            "Content-Type: application/json"
+                .parse::<tiny_http::Header>()
+                .unwrap(),
+        )
+}
+
+fn form_fields(form: &str) -> HashMap<String, String> {
+    hyper::Url::parse(&format!("http://token/?{}", form))
+        .map(|url| url.query_pairs().into_owned().collect())
+        .unwrap_or_default()
+}
+
+///The claims of a service account's JWT bearer grant. The signature is not checked
+fn jwt_claims(form: &str) -> Option<serde_json::Value> {
+    let form = form_fields(form);
+    if form.get("grant_type")?.as_str() != "urn:ietf:params:oauth:grant-type:jwt-bearer" {
+        return None;
+    }
+    let claims = form.get("assertion")?.split('.').nth(1)?;
+    let claims = base64::decode_config(claims, base64::URL_SAFE).ok()?;
+    serde_json::from_slice(&claims).ok()
+}
+
+///Write a service account key, with a new RSA key, into dir. Its token_uri is left for
+///DriveOptions to override
+pub fn service_account_key(dir: &Path) -> String {
+    let rsa = openssl::rsa::Rsa::generate(2048).unwrap();
+    let pem = openssl::pkey::PKey::from_rsa(rsa)
+        .unwrap()
+        .private_key_to_pem_pkcs8()
+        .unwrap();
+    let key = serde_json::json!({
+        "type": "service_account",
+        "private_key_id": "fake-key",
+        "private_key": String::from_utf8(pem).unwrap(),
+        "client_email": "pi-sync@example.iam.gserviceaccount.com",
+        "token_uri": "https://oauth2.example.invalid/token",
+    });
+    let path = dir.join("service-account.json");
+    std::fs::write(&path, key.to_string()).unwrap();
+    path.to_str().unwrap().to_owned()
+}
+
+///Start the server, returning options pointing a Drive3Client at it. A client secret and an
+///expired token are written into dir so the client refreshes against our token endpoint
+pub fn fake_drive_server(dir: &Path) -> (DriveOptions, Arc<Mutex<FakeDrive>>) {
+    let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+    let root = format!("http://{}/", server.server_addr());
+    let state = Arc::new(Mutex::new(FakeDrive::default()));
+    let shared = Arc::clone(&state);
+    let session_root = root.clone();
+
+    std::thread::spawn(move || {
+        for mut req in server.incoming_requests() {
+            let mut body = vec![];
+            req.as_reader().read_to_end(&mut body).unwrap();
+            let url = hyper::Url::parse(&format!("{}{}", session_root, &req.url()[1..])).unwrap();
+            let query: HashMap<String, String> = url.query_pairs().into_owned().collect();
+            let mut drive = shared.lock().unwrap();
+
+            let path = url.path().to_owned();
+            let reply = if path == "/token" {
+                let form = String::from_utf8_lossy(&body);
+                if form.contains("grant_type=refresh_token") && form.contains(REFRESH_TOKEN) {
+                    drive.token_refreshes += 1;
+                    let token = format!("fake-access-{}", drive.token_refreshes);
+                    drive.access_token = Some(token.clone());
+                    json_reply(
+                        format!(
+                            "{{\"access_token\":\"{}\",\"token_type\":\"Bearer\",\"expires_in\":3600}}",
+                            token
+                        ),
+                        200,
+                    )
+                } else if let Some(claims) = jwt_claims(&form) {
+                    drive.jwt_claims.push(claims);
+                    let token = format!("fake-sa-{}", drive.jwt_claims.len());
+                    drive.access_token = Some(token.clone());
+                    json_reply(
+                        format!(
+                            "{{\"access_token\":\"{}\",\"token_type\":\"Bearer\",\"expires_in\":3600}}",
+                            token
+                        ),
+                        200,
+                    )
+                } else if form_fields(&form).get("grant_type").map(|g| g.as_str())
+                    == Some("urn:ietf:params:oauth:grant-type:device_code")
+                {
+                    drive.device_polls += 1;
+                    if drive.device_polls == 1 {
+                        json_reply("{\"error\":\"authorization_pending\"}".to_owned(), 428)
+                    } else {
+                        let token = format!("fake-device-{}", drive.device_codes);
+                        drive.access_token = Some(token.clone());
+                        json_reply(
+                            format!(
+                                "{{\"access_token\":\"{}\",\"refresh_token\":\"{}\",\"token_type\":\"Bearer\",\"expires_in\":3600}}",
+                                token, REFRESH_TOKEN
+                            ),
+                            200,
+                        )
+                    }
+                } else {
+                    json_reply("{\"error\":\"invalid_grant\"}".to_owned(), 400)
+                }
+            } else if path == "/device/code" {
+                drive.device_codes += 1;
+                drive.device_polls = 0;
+                json_reply(
+                    format!(
+                        "{{\"device_code\":\"fake-device-code-{0}\",\"user_code\":\"PI-SYNC-{0}\",\"verification_url\":\"{1}device\",\"expires_in\":60,\"interval\":0}}",
+                        drive.device_codes, session_root
+                    ),
+                    200,
+                )
+            } else if let Some(session) = path.strip_prefix("/upload/session/") {
+                //chunks carry Content-Range: bytes first-last/total
+                let total: usize = header(&req, "Content-Range")
+                    .and_then(|r| r.rsplit('/').next())
+                    .and_then(|t| t.parse().ok())
+                    .unwrap_or(0);
+                match drive.sessions.remove(session) {
+                    Some((target, meta, mut received)) => {
+                        received.extend(body);
+                        if received.len() < total {
+                            let range = format!("bytes=0-{}", received.len() - 1);
+                            drive
+                                .sessions
+                                .insert(session.to_owned(), (target, meta, received));
+                            json_reply(String::new(), 308)
+                                .with_header(tiny_http::Header::from_bytes("Range", range).unwrap())
+                        } else {
+                            let file = match target {
+                                Some(id) => drive.replace(&id, received),
+                                None => Some(drive.create(meta, received)),
+                            };
+                            match file {
+                                Some(file) => {
+                                    json_reply(serde_json::to_string(&file).unwrap(), 200)
+                                }
+                                None => json_reply(String::new(), 404),
+                            }
+                        }
+                    }
+                    None => json_reply(String::new(), 404),
+                }
+            } else if !drive.authorized(&req) {
+                json_reply(
+                    "{\"error\":{\"code\":401,\"message\":\"bad token\"}}".to_owned(),
+                    401,
+                )
+            } else if path == "/drive/v3/about" {
+                json_reply("{\"kind\":\"drive#about\"}".to_owned(), 200)
+            } else if path == "/drive/v3/files" && req.method() == &tiny_http::Method::Get {
+                let list = drive.list(query.get("q").map(|q| q.as_str()).unwrap_or(""));
+                json_reply(serde_json::to_string(&list).unwrap(), 200)
+            } else if let Some(id) = path.strip_prefix("/drive/v3/files/") {
+                match req.method() {
+                    tiny_http::Method::Get => match drive.get(id) {
+                        Some(file) => json_reply(serde_json::to_string(&file).unwrap(), 200),
+                        None => json_reply(
+                            "{\"error\":{\"errors\":[],\"code\":404,\"message\":\"File not found\"}}"
+                                .to_owned(),
+                            404,
+                        ),
+                    },
+                    tiny_http::Method::Patch => {
+                        let patch = serde_json::from_slice(&body).unwrap();
+                        match drive.update(
+                            id,
+                            patch,
+                            query.get("addParents"),
+                            query.get("removeParents"),
+                        ) {
+                            Some(file) => json_reply(serde_json::to_string(&file).unwrap(), 200),
+                            None => json_reply(String::new(), 404),
+                        }
+                    }
+                    tiny_http::Method::Delete if drive.delete(id) => json_reply(String::new(), 204),
+                    _ => json_reply(String::new(), 404),
+                }
+            } else if path == "/upload/drive/v3/files" {
+                let mut parts = multipart_parts(&body).into_iter();
+                let meta = serde_json::from_slice(&parts.next().unwrap_or_default()).unwrap();
+                let file = drive.create(meta, parts.next().unwrap_or_default());
+                json_reply(serde_json::to_string(&file).unwrap(), 200)
+            } else if let Some(target) = path.strip_prefix("/resumable/upload/drive/v3/files") {
+                drive.next_id += 1;
+                let session = drive.next_id.to_string();
+                let meta = serde_json::from_slice(&body).unwrap();
+                let target = target.strip_prefix('/').map(|id| id.to_owned());
+                drive
+                    .sessions
+                    .insert(session.clone(), (target, meta, vec![]));
+                let location = format!("{}upload/session/{}", session_root, session);
+                json_reply(String::new(), 200)
+                    .with_header(tiny_http::Header::from_bytes("Location", location).unwrap())
+            } else {
+                json_reply(String::new(), 404)
+            };
+            drop(drive);
+            let _ = req.respond(reply);
+        }
+    });
+
+    let secret_file = dir.join("drive3-secret.json");
+    std::fs::write(
+        &secret_file,
+        format!(
+            "{{\"installed\":{{\"client_id\":\"pi\",\"client_secret\":\"sync\",\"auth_uri\":\"{0}auth\",\"token_uri\":\"https://oauth2.example.invalid/token\",\"redirect_uris\":[\"urn:ietf:wg:oauth:2.0:oob\"]}}}}",
+            root
+        ),
+    )
+    .unwrap();
+
+    //the same key yup_oauth2 files tokens under, the sorted scopes hashed
+    let mut scopes = DRIVE_SCOPES.to_vec();
+    scopes.sort();
+    let mut hasher = DefaultHasher::new();
+    scopes.hash(&mut hasher);
+    let token_file = dir.join("token.json");
+    std::fs::write(
+        &token_file,
+        format!(
+            "{{\"tokens\":[{{\"hash\":{},\"scopes\":{},\"token\":{{\"access_token\":\"expired\",\"refresh_token\":\"{}\",\"token_type\":\"Bearer\",\"expires_in\":3600,\"expires_in_timestamp\":1}}}}]}}",
+            hasher.finish(),
+            serde_json::to_string(&scopes).unwrap(),
+            REFRESH_TOKEN
+        ),
+    )
+    .unwrap();
+
+    let opts = DriveOptions::new(
+        AuthOptions::new(
+            AuthFlow::Installed,
+            secret_file.to_str().unwrap().to_owned(),
+            token_file.to_str().unwrap().to_owned(),
+            None,
+            None,
+        ),
+        Some(root.clone()),
+        Some(format!("{}token", root)),
+        Some(format!("{}device/code", root)),
+        Arc::default(),
+    );
+    (opts, state)
+}
@@ -0,0 +1,466 @@
+use crate::cloud_client::{CloudClient, RemoteFile, TRASH_DIR};
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::state_db::{md5_hex, StateDb};
+use crate::upload_handler::{FileOperations, Roots, SyncableFile};
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use walkdir::WalkDir;
+
+const PARTIAL_SUFFIX: &str = ".pi_sync_partial";
+
+///Mirror the synced tree into a directory on local disk, e.g. a NAS mount
+pub struct LocalDirBackend {
+    target_dir: PathBuf,
+    roots: Roots,
+    state: Arc<StateDb>,
+}
+
+impl LocalDirBackend {
+    pub fn new(target_dir: &str, roots: Roots, state: Arc<StateDb>) -> Self {
+        LocalDirBackend {
+            target_dir: PathBuf::from(target_dir),
+            roots,
+            state,
+        }
+    }
+
+    ///Where in the target dir a local file or dir lands
+    fn target_path(&self, syncable: &SyncableFile) -> PiSyncResult<PathBuf> {
+        Ok(self.target_dir.join(syncable.cloud_path()?))
+    }
+
+    fn path_id(p: &Path) -> PiSyncResult<Option<String>> {
+        p.to_str()
+            .map(|s| Some(s.to_owned()))
+            .ok_or(SyncerErrors::InvalidPathError)
+    }
+}
+
+impl CloudClient for LocalDirBackend {
+    ///Copy the file next to its target and rename it into place, so readers of the
+    ///target dir never see a half written file
+    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let target = self.target_path(&s)?;
+        trace!(log, "Upload File:: {:?} to {:?}", s.local_path(), target);
+
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| {
+                error!(log, "Cannot create target dir {:?} {}", parent, e);
+                SyncerErrors::ProviderError
+            })?;
+        }
+
+        let mut partial = target.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        std::fs::copy(s.local_path(), &partial)
+            .and_then(|_| std::fs::rename(&partial, &target))
+            .map_err(|e| {
+                error!(
+                    log,
+                    "Cannot copy {:?} to {:?} {}",
+                    s.local_path(),
+                    target,
+                    e
+                );
+                let _ = std::fs::remove_file(&partial);
+                SyncerErrors::ProviderError
+            })?;
+
+        Ok(self.state.recorded(&s, Self::path_id(&target)?))
+    }
+
+    ///Parent ids are paths here, the target dir path is all we need
+    fn create_dir(
+        &self,
+        local_fs_path: &str,
+        _parent_id: Option<&str>,
+    ) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let target = self.target_path(&s)?;
+        trace!(
+            log,
+            "Create dir:: {:?} from local {:?}",
+            target,
+            s.local_path()
+        );
+
+        std::fs::create_dir_all(&target).map_err(|e| {
+            error!(log, "Cannot create dir {:?} {}", target, e);
+            SyncerErrors::ProviderError
+        })?;
+        Ok(self.state.recorded(&s, Self::path_id(&target)?))
+    }
+
+    ///The id of a file is its path in the target dir
+    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_path);
+        if let Some(id) = self.state.remote_id(&s)? {
+            return Ok(Some(id));
+        }
+        let target = self.target_path(&s)?;
+        if target.exists() {
+            Ok(self.state.recorded(&s, Self::path_id(&target)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    ///Trashed files keep their place in the tree under .pi_sync_trash, replacing any older copy
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()> {
+        let s = self.roots.file(local_path);
+        let target = match self.id(local_path)? {
+            Some(id) => PathBuf::from(id),
+            None => {
+                debug!(log, "{} was never synced, nothing to delete", local_path);
+                return Ok(());
+            }
+        };
+
+        let result = if !target.exists() {
+            Ok(())
+        } else if trash {
+            let trashed = self.target_dir.join(TRASH_DIR).join(s.cloud_path()?);
+            trace!(log, "Trash {:?} to {:?}", target, trashed);
+            let _ = remove_any(&trashed);
+            trashed
+                .parent()
+                .map(std::fs::create_dir_all)
+                .unwrap_or(Ok(()))
+                .and_then(|_| std::fs::rename(&target, &trashed))
+        } else {
+            trace!(log, "Delete {:?}", target);
+            remove_any(&target)
+        };
+        result.map_err(|e| {
+            error!(log, "Cannot remove {:?} {}", target, e);
+            SyncerErrors::ProviderError
+        })?;
+        self.state.forget(local_path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>> {
+        let old = match self.id(from)? {
+            Some(id) => PathBuf::from(id),
+            None => return Ok(None),
+        };
+        let t = self.roots.file(to);
+        let target = self.target_path(&t)?;
+        trace!(log, "Rename {:?} to {:?}", old, target);
+
+        target
+            .parent()
+            .map(std::fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| std::fs::rename(&old, &target))
+            .map_err(|e| {
+                error!(log, "Cannot rename {:?} to {:?} {}", old, target, e);
+                SyncerErrors::ProviderError
+            })?;
+        self.state.forget(from)?;
+        Ok(self.state.recorded(&t, Self::path_id(&target)?))
+    }
+
+    ///Everything under each root's folder in the target dir, the trash and partial copies left out
+    fn list_remote(&self) -> PiSyncResult<Vec<RemoteFile>> {
+        let mut files = vec![];
+        for root in self.roots.iter() {
+            let root = root.file(root.local_root().to_str().unwrap_or_default());
+            let walk = WalkDir::new(self.target_path(&root)?)
+                .sort_by(|a, b| a.file_name().cmp(b.file_name()));
+            for entry in walk.into_iter().filter_map(|e| e.ok()) {
+                let path = entry.path();
+                if !entry.file_type().is_file() || path.to_string_lossy().ends_with(PARTIAL_SUFFIX)
+                {
+                    continue;
+                }
+                let cloud_path = path.strip_prefix(&self.target_dir)?;
+                let (id, cloud) = match (path.to_str(), cloud_path.to_str()) {
+                    (Some(id), Some(cloud)) => (id, cloud),
+                    _ => continue,
+                };
+                let meta = entry.metadata().map_err(|e| {
+                    error!(log, "Cannot stat {:?} {}", path, e);
+                    SyncerErrors::ProviderError
+                })?;
+                files.push(RemoteFile::new(
+                    id.to_owned(),
+                    base64::encode(cloud),
+                    cloud_path.to_owned(),
+                    meta.len(),
+                    Some(md5_hex(id)?),
+                    meta.modified()
+                        .map(DateTime::from)
+                        .unwrap_or_else(|_| Utc::now()),
+                ));
+            }
+        }
+        Ok(files)
+    }
+
+    fn trash_remote(&self, remote_id: &str) -> PiSyncResult<()> {
+        let target = Path::new(remote_id);
+        let trashed = self
+            .target_dir
+            .join(TRASH_DIR)
+            .join(target.strip_prefix(&self.target_dir)?);
+        trace!(log, "Trash {:?} to {:?}", target, trashed);
+        trashed
+            .parent()
+            .map(std::fs::create_dir_all)
+            .unwrap_or(Ok(()))
+            .and_then(|_| std::fs::rename(target, &trashed))
+            .map_err(|e| {
+                error!(log, "Cannot trash {:?} {}", target, e);
+                SyncerErrors::ProviderError
+            })
+    }
+
+    fn remote_md5(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        match self.id(local_path)? {
+            Some(id) if Path::new(&id).is_file() => md5_hex(&id).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn check_ready(&self) -> PiSyncResult<()> {
+        std::fs::create_dir_all(&self.target_dir).map_err(|e| {
+            error!(log, "Target dir {:?} unusable {}", self.target_dir, e);
+            SyncerErrors::ProviderError
+        })
+    }
+}
+
+fn remove_any(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path)
+    } else {
+        std::fs::remove_file(path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_client::CloudClient;
+    use crate::local_backend::*;
+    use crate::upload_handler::RootMapping;
+    use tempfile::{tempdir, TempDir};
+
+    fn state() -> Arc<StateDb> {
+        Arc::new(StateDb::open_in_memory().unwrap())
+    }
+
+    ///A local dir to sync from, mapped to RpiCamera, and a target dir to sync to
+    fn backend() -> (TempDir, TempDir, LocalDirBackend) {
+        let local = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let roots = Roots::from(RootMapping::new(
+            local.path().into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let lb = LocalDirBackend::new(target.path().to_str().unwrap(), roots, state());
+        (local, target, lb)
+    }
+
+    #[test]
+    fn test_local_backend_create_dir() {
+        let (local, target, lb) = backend();
+        let d = local.path().join("new_dir");
+
+        let id = lb.create_dir(d.to_str().unwrap(), None).unwrap().unwrap();
+        let expected = target.path().join("RpiCamera").join("new_dir");
+        assert_eq!(expected.to_str().unwrap(), id);
+        assert!(expected.is_dir());
+    }
+
+    #[test]
+    fn test_local_backend_id() {
+        let (local, _target, lb) = backend();
+        let d = local.path().join("a/b");
+        let d = d.to_str().unwrap();
+
+        assert_eq!(None, lb.id(d).unwrap());
+        let created = lb.create_dir(d, None).unwrap();
+        assert_eq!(created, lb.id(d).unwrap());
+    }
+
+    #[test]
+    fn test_local_backend_upload_file() {
+        let (local, target, lb) = backend();
+        std::fs::create_dir_all(local.path().join("a")).unwrap();
+        let f = local.path().join("a/im1.jpg");
+        std::fs::write(&f, b"jpeg").unwrap();
+
+        let id = lb.upload_file(f.to_str().unwrap()).unwrap().unwrap();
+        let expected = target.path().join("RpiCamera/a/im1.jpg");
+        assert_eq!(expected.to_str().unwrap(), id);
+        assert_eq!(b"jpeg".to_vec(), std::fs::read(expected).unwrap());
+    }
+
+    #[test]
+    fn test_local_backend_records_state() {
+        let (local, target, lb) = backend();
+        let f = local.path().join("im1.jpg");
+        std::fs::write(&f, b"jpeg").unwrap();
+        let f = f.to_str().unwrap();
+
+        let id = lb.upload_file(f).unwrap();
+        let record = lb
+            .state
+            .get(&lb.roots.file(f).get_unique_id().unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(id, Some(record.remote_id));
+        assert_eq!(4, record.size);
+
+        //id is answered from the state db, the target dir is not looked at
+        std::fs::remove_file(target.path().join("RpiCamera/im1.jpg")).unwrap();
+        assert_eq!(id, lb.id(f).unwrap());
+    }
+
+    #[test]
+    fn test_local_backend_delete() {
+        let (local, target, lb) = backend();
+        std::fs::create_dir_all(local.path().join("a")).unwrap();
+        for f in &["a/im1.jpg", "a/im2.jpg", "im3.jpg"] {
+            let f = local.path().join(f);
+            std::fs::write(&f, b"jpeg").unwrap();
+            lb.upload_file(f.to_str().unwrap()).unwrap();
+        }
+        let a = local.path().join("a");
+        let im3 = local.path().join("im3.jpg");
+        std::fs::remove_dir_all(&a).unwrap();
+        std::fs::remove_file(&im3).unwrap();
+
+        //the whole folder goes to the trash, keeping its place in the tree
+        lb.delete(a.to_str().unwrap(), true).unwrap();
+        assert!(!target.path().join("RpiCamera/a").exists());
+        assert!(target
+            .path()
+            .join(".pi_sync_trash/RpiCamera/a/im2.jpg")
+            .is_file());
+        assert_eq!(
+            None,
+            lb.id(local.path().join("a/im1.jpg").to_str().unwrap())
+                .unwrap()
+        );
+
+        lb.delete(im3.to_str().unwrap(), false).unwrap();
+        assert!(!target.path().join("RpiCamera/im3.jpg").exists());
+        assert!(!target
+            .path()
+            .join(".pi_sync_trash/RpiCamera/im3.jpg")
+            .exists());
+
+        //never synced is not an error
+        lb.delete(im3.to_str().unwrap(), false).unwrap();
+    }
+
+    #[test]
+    fn test_local_backend_rename() {
+        let (local, target, lb) = backend();
+        std::fs::create_dir_all(local.path().join("a")).unwrap();
+        let f = local.path().join("a/im1.jpg");
+        std::fs::write(&f, b"jpeg").unwrap();
+        lb.upload_file(f.to_str().unwrap()).unwrap();
+
+        let moved = local.path().join("b/c");
+        std::fs::create_dir_all(local.path().join("b")).unwrap();
+        std::fs::rename(local.path().join("a"), &moved).unwrap();
+        let id = lb
+            .rename(
+                local.path().join("a").to_str().unwrap(),
+                moved.to_str().unwrap(),
+            )
+            .unwrap();
+        let expected = target.path().join("RpiCamera/b/c");
+        assert_eq!(Some(expected.to_str().unwrap().to_owned()), id);
+        assert!(expected.join("im1.jpg").is_file());
+        assert!(!target.path().join("RpiCamera/a").exists());
+        assert_eq!(
+            None,
+            lb.id(local.path().join("a/im1.jpg").to_str().unwrap())
+                .unwrap()
+        );
+
+        //never synced, so nothing to move
+        assert_eq!(
+            None,
+            lb.rename(
+                local.path().join("x.jpg").to_str().unwrap(),
+                local.path().join("y.jpg").to_str().unwrap()
+            )
+            .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_local_backend_two_roots() {
+        let media = tempdir().unwrap();
+        let logs = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let roots = Roots::new(vec![
+            RootMapping::new(media.path().into(), "RpiCamera".into(), vec![]),
+            RootMapping::new(logs.path().into(), "logs/motion".into(), vec![]),
+        ]);
+        let lb = LocalDirBackend::new(target.path().to_str().unwrap(), roots, state());
+
+        let im = media.path().join("im1.jpg");
+        std::fs::write(&im, b"jpeg").unwrap();
+        let motion_log = logs.path().join("motion.log");
+        std::fs::write(&motion_log, b"log").unwrap();
+
+        lb.upload_file(im.to_str().unwrap()).unwrap();
+        lb.upload_file(motion_log.to_str().unwrap()).unwrap();
+        assert!(target.path().join("RpiCamera/im1.jpg").is_file());
+        assert!(target.path().join("logs/motion/motion.log").is_file());
+    }
+
+    #[test]
+    fn test_local_backend_list_and_trash_remote() {
+        let (local, target, lb) = backend();
+        let im = local.path().join("im1.jpg");
+        std::fs::write(&im, b"jpeg").unwrap();
+        let id = lb.upload_file(im.to_str().unwrap()).unwrap().unwrap();
+
+        let listed = lb.list_remote().unwrap();
+        assert_eq!(1, listed.len());
+        assert_eq!(id, listed[0].remote_id);
+        assert_eq!(Path::new("RpiCamera/im1.jpg"), listed[0].cloud_path);
+        assert_eq!(
+            lb.roots.file(im.to_str().unwrap()).get_unique_id().unwrap(),
+            listed[0].pi_sync_id
+        );
+
+        lb.trash_remote(&id).unwrap();
+        assert!(lb.list_remote().unwrap().is_empty());
+        assert!(target
+            .path()
+            .join(TRASH_DIR)
+            .join("RpiCamera/im1.jpg")
+            .is_file());
+    }
+
+    #[test]
+    fn test_local_backend_outside_root() {
+        let (_local, _target, lb) = backend();
+        assert!(lb.id("/not/under/root").is_err());
+    }
+
+    #[test]
+    fn test_local_backend_check_ready() {
+        let (_local, target, _lb) = backend();
+        let nested = target.path().join("nas/mount");
+        let roots = Roots::from(RootMapping::new(
+            "/var/www/RpiCamera".into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let lb = LocalDirBackend::new(nested.to_str().unwrap(), roots, state());
+        assert!(lb.check_ready().is_ok());
+        assert!(nested.is_dir());
+    }
+}
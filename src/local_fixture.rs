@@ -0,0 +1,61 @@
+//!A temp dir synced through LocalDirBackend into another, the setup most tests start from
+use crate::local_backend::LocalDirBackend;
+use crate::state_db::StateDb;
+use crate::upload_handler::{RootMapping, Roots};
+use std::sync::Arc;
+use tempfile::{tempdir, TempDir};
+
+pub struct LocalFixture {
+    ///Watched, mapped onto RpiCamera
+    pub local: TempDir,
+    ///Where the backend puts what it uploads
+    pub target: TempDir,
+    pub roots: Roots,
+    pub state: Arc<StateDb>,
+    pub lb: LocalDirBackend,
+}
+
+impl LocalFixture {
+    pub fn new() -> Self {
+        LocalFixture::with_filters(vec![])
+    }
+
+    pub fn with_filters(filters: Vec<String>) -> Self {
+        let local = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let roots = Roots::from(RootMapping::new(
+            local.path().into(),
+            "RpiCamera".into(),
+            filters,
+        ));
+        let state = Arc::new(StateDb::open_in_memory().unwrap());
+        let lb = LocalDirBackend::new(
+            target.path().to_str().unwrap(),
+            roots.clone(),
+            Arc::clone(&state),
+        );
+        LocalFixture {
+            local,
+            target,
+            roots,
+            state,
+            lb,
+        }
+    }
+
+    ///Another client onto the same target and state db, e.g. for an upload worker
+    pub fn client(&self) -> LocalDirBackend {
+        LocalDirBackend::new(
+            self.target.path().to_str().unwrap(),
+            self.roots.clone(),
+            Arc::clone(&self.state),
+        )
+    }
+
+    ///Write content to name under the local dir, returning its path
+    pub fn write(&self, name: &str, content: &[u8]) -> String {
+        let path = self.local.path().join(name);
+        std::fs::write(&path, content).unwrap();
+        path.to_str().unwrap().to_owned()
+    }
+}
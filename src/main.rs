@@ -11,145 +11,638 @@ extern crate notify;
 extern crate tempfile;
 extern crate yup_oauth2 as oauth2;
 
-use clap::{App, Arg};
+use clap::ArgMatches;
+use cli::{EXIT_AUTH, EXIT_BACKEND, EXIT_CONFIG, EXIT_INCOMPLETE, EXIT_OK};
+use cloud_client::{BackendOptions, CloudClient, DeletePolicy};
 use common::LOG as log;
-use drive_cli::CloudClient;
-use notify::{raw_watcher, RecommendedWatcher, RecursiveMode, Watcher};
-use std::sync::mpsc::channel;
-use std::time::Duration;
-use upload_handler::{FileOperations, SyncableFile};
+use config::Config;
+use disk_space::DiskWatch;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use offline::Connectivity;
+use pending::PendingFiles;
+use pi_err::{PiSyncResult, SyncerErrors};
+use renames::PendingRenames;
+use retention::LocalRetention;
+use retry_queue::RetryQueue;
+use state_db::StateDb;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use throttle::Bandwidth;
+use upload_handler::{FileOperations, Roots};
+use upload_pool::{UploadJob, UploadPool};
 
+mod cli;
+mod cloud_client;
 mod common;
+mod config;
+mod disk_space;
+mod drive_auth;
 mod drive_cli;
+mod dry_run;
+#[cfg(test)]
+mod fake_drive;
+mod local_backend;
+#[cfg(test)]
+mod local_fixture;
+mod offline;
+mod pending;
 mod pi_err;
+mod remote_retention;
+mod renames;
+mod retention;
+mod retry_queue;
+mod s3_cli;
+mod scanner;
+mod sftp_cli;
+mod state_db;
+mod throttle;
 mod upload_handler;
+mod upload_pool;
+mod verify;
+mod webdav_cli;
 
 fn main() {
     debug!(log, "Statring Syncer");
+    let matches = cli::app().get_matches();
+    let code = match run(&matches) {
+        Ok(code) => code,
+        Err(e) => cli::exit_code(&e),
+    };
+    common::flush_log();
+    std::process::exit(code);
+}
 
-    let matches = App::new("Rusty Cam Syncer")
-        .version("1.0")
-        .author("Alan R. <alan@alanryan.name>")
-        .version("1.0")
-        .about("Will Sync a Dir recursvively with the smarts of a sheep")
-        .arg(
-            Arg::with_name("check_auth")
-                .short("a")
-                .long("check_auth")
-                .value_name("check_auth")
-                .help("yes/no")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("secret_file")
-                .short("s")
-                .long("secret_file")
-                .value_name("secret_file")
-                .help("Where to find Google Drive API JSON secrets")
-                .takes_value(true),
-        )
-        .arg(
-            Arg::with_name("regexp_filter")
-                .short("f")
-                .long("regexp_filter")
-                .value_name("regexp_filter")
-                .help("Command separated list of filters, e.g. ^im.*jpg$ or ^vi.*mp4$ ")
-                .takes_value(true),
-        )
-        .get_matches();
-
-    let do_auth = matches.value_of("check_auth");
-
-    let file_name_filters = matches
-        .value_of("regexp_filter")
-        .unwrap_or("")
-        .split(",")
-        .collect::<Vec<&str>>();
-
-    let secret_file = matches
-        .value_of("secret_file")
-        .unwrap_or("/home/alan/.google-service-cli/drive3-secret.json");
-
-    debug!(log, "Using {} as Auth File", secret_file);
-
-    //Create Base Folder on Cloud Provider
-    //make sure it exists locally too
-    let syncer_drive_cli = drive_cli::Drive3Client::new(secret_file.to_owned(), file_name_filters);
-    if let Err(hub_err) = syncer_drive_cli.get_hub() {
-        println!("Error {}", hub_err);
-        error!(log, "Cloud Provider {}", hub_err);
-        std::process::exit(0x0100);
-    }
-
-    //create our dirs to sync
-    let root_remote_dir = format!(
-        "{}/{}",
-        upload_handler::LOCAL_ROOT_FOLDER, //TODO: should be using target dir
-        upload_handler::DRIVE_ROOT_FOLDER
-    );
-    let target_dir = root_remote_dir.clone();
-    debug!(log, "Using {} as Local Dir to monitor", root_remote_dir);
-
-    if let Err(e) = std::fs::create_dir(root_remote_dir.clone()) {
-        warn!(log, "Root Folder Create Response: {}", e.to_string());
-    }
-
-    match syncer_drive_cli.id(&root_remote_dir) {
-        Ok(id) => match id {
-            Some(_id) => debug!(log, "Root Dir Exists, not creating"),
-            None => match syncer_drive_cli.create_dir(&root_remote_dir, None) {
-                Ok(id) => debug!(log, "Created Root Dir {:?}", id),
-                Err(e) => debug!(log, "Could not create root dir {:?}", e),
-            },
-        },
-        Err(_e) => warn!(log, "Error getting drive id for root folder"),
+///What the commands that sync share
+struct Syncer<'a> {
+    config: &'a Config,
+    roots: Roots,
+    state: Arc<StateDb>,
+    ///For a client per upload worker
+    backend_options: BackendOptions<'a>,
+    ///Stays with the calling thread
+    client: Box<dyn CloudClient>,
+    queue: Arc<RetryQueue>,
+    online: Arc<Connectivity>,
+    retention: Arc<LocalRetention>,
+}
+
+fn run(matches: &ArgMatches) -> PiSyncResult<i32> {
+    let config = cli::config(matches)?;
+    //a dry run works on a copy, so the queue and our records are left as they are
+    let state = if config.dry_run {
+        StateDb::snapshot(Path::new(&config.state_db))
+    } else {
+        StateDb::open(Path::new(&config.state_db))
+    };
+    let state = state.map_err(|e| {
+        error!(log, "State db {}: {}", config.state_db, e);
+        e
+    })?;
+    let state = Arc::new(state);
+    match matches.subcommand() {
+        ("status", Some(sub)) => return status(&state, sub.is_present("dead_letters")),
+        ("requeue", Some(sub)) => {
+            return requeue(&config, &state, sub.value_of("path").unwrap_or("all"))
+        }
+        _ => {}
+    }
+
+    //one limit for all the workers together
+    let bandwidth = Arc::new(Bandwidth::new(config.rate_schedule()?));
+    let backend_options = cli::backend_options(matches, &config, &bandwidth)?;
+    let client =
+        cloud_client::new_backend(backend_options.clone(), Arc::clone(&state)).map_err(|e| {
+            error!(log, "Cannot configure the backend: {}", e);
+            e
+        })?;
+    if let Err(e) = client.check_ready() {
+        error!(log, "Cloud Provider {}", e);
+        return Err(e);
+    }
+    match matches.subcommand() {
+        ("auth", _) => return auth(client.as_ref()),
+        ("ls-remote", _) => return ls_remote(client.as_ref()),
+        ("prune", _) => return prune(&config, client.as_ref()),
+        ("verify", Some(sub)) => {
+            return verify(
+                client.as_ref(),
+                &config.roots(),
+                &state,
+                sub.is_present("json"),
+                sub.is_present("repair"),
+            )
+        }
+        _ => {}
+    }
+
+    let roots = config.roots();
+    create_roots(client.as_ref(), &roots)?;
+    let retention = Arc::new(LocalRetention::new(
+        config.retention_policy()?,
+        roots.clone(),
+        Arc::clone(&state),
+    ));
+    let queue = Arc::new(RetryQueue::new(
+        Arc::clone(&state),
+        config.retry_policy(),
+        config.max_queued,
+    ));
+    match queue.interrupted() {
+        Ok(0) => {}
+        Ok(n) => info!(log, "{} uploads were cut short last time, retrying them", n),
+        Err(e) => warn!(log, "Cannot reset the upload queue {}", e),
+    }
+    let syncer = Syncer {
+        config: &config,
+        roots,
+        state,
+        backend_options,
+        client,
+        queue,
+        online: Arc::new(Connectivity::new(Duration::from_secs(config.probe_secs))),
+        retention,
+    };
+    match matches.subcommand_name() {
+        Some("sync-once") => sync_once(&syncer),
+        _ => daemon(&syncer),
+    }
+}
+
+///Queue and state db counts. EXIT_INCOMPLETE if anything is dead lettered
+fn status(state: &StateDb, list_dead: bool) -> PiSyncResult<i32> {
+    let dead = state.dead_letters()?;
+    println!("Synced files\t{}", state.synced_files()?.len());
+    println!("Queued uploads\t{}", state.queue_len()? - dead.len());
+    println!("Dead letters\t{}", dead.len());
+    if let Some(next) = state.next_upload_due()? {
+        let next = UNIX_EPOCH + Duration::from_secs(next.max(0) as u64);
+        let wait = next.duration_since(SystemTime::now()).unwrap_or_default();
+        println!("Next retry in\t{}s", wait.as_secs());
+    }
+    if list_dead {
+        for upload in &dead {
+            println!(
+                "{}\t{}\t{}",
+                upload.attempts,
+                upload.last_error.as_deref().unwrap_or_default(),
+                upload.local_path
+            );
+        }
+    }
+    Ok(if dead.is_empty() {
+        EXIT_OK
+    } else {
+        EXIT_INCOMPLETE
+    })
+}
+
+fn requeue(config: &Config, state: &StateDb, which: &str) -> PiSyncResult<i32> {
+    let which = if which == "all" { None } else { Some(which) };
+    let n = state.requeue(which)?;
+    if config.dry_run {
+        println!("Would requeue {} uploads", n);
+    } else {
+        println!("Requeued {} uploads", n);
+    }
+    Ok(EXIT_OK)
+}
+
+///Building the client has already logged in, or refreshed the token, this checks it is taken
+fn auth(client: &dyn CloudClient) -> PiSyncResult<i32> {
+    match client.probe() {
+        Ok(()) => {
+            println!("Token Check Done");
+            Ok(EXIT_OK)
+        }
+        Err(e) => {
+            error!(log, "The backend turned us away {}", e);
+            Ok(EXIT_AUTH)
+        }
     }
+}
+
+fn ls_remote(client: &dyn CloudClient) -> PiSyncResult<i32> {
+    let mut files = client.list_remote()?;
+    files.sort_by(|a, b| a.cloud_path.cmp(&b.cloud_path));
+    for file in files {
+        println!(
+            "{}\t{}\t{}",
+            file.created.format("%Y-%m-%d %H:%M"),
+            file.size,
+            file.cloud_path.display()
+        );
+    }
+    Ok(EXIT_OK)
+}
+
+fn prune(config: &Config, client: &dyn CloudClient) -> PiSyncResult<i32> {
+    let pruner = match config.pruner()? {
+        Some(pruner) => pruner,
+        None => {
+            error!(log, "No [remote_retention] in the config");
+            return Ok(EXIT_CONFIG);
+        }
+    };
+    let prunes = pruner.run(client, config.dry_run)?;
+    if config.dry_run {
+        for prune in prunes {
+            println!("{}", prune);
+        }
+    }
+    Ok(EXIT_OK)
+}
 
-    if let Some("yes") = matches.value_of("check_auth") {
-        println!("Token Check Done");
-        debug!(
-            log,
-            "outta here, just getting a token check - we may have never meet"
+///EXIT_INCOMPLETE if anything is left that does not match
+fn verify(
+    client: &dyn CloudClient,
+    roots: &Roots,
+    state: &StateDb,
+    json: bool,
+    repair: bool,
+) -> PiSyncResult<i32> {
+    let mut report = verify::verify(client, roots, state)?;
+    if repair {
+        verify::repair(client, state, &mut report);
+    }
+    if json {
+        match serde_json::to_string_pretty(&report) {
+            Ok(json) => println!("{}", json),
+            Err(e) => {
+                error!(log, "Cannot write the report as JSON {}", e);
+                return Err(SyncerErrors::SyncerNoneError);
+            }
+        }
+    } else {
+        for finding in &report.findings {
+            println!("{}", finding);
+        }
+        println!(
+            "Local files\t{}\nRemote files\t{}\nMatched\t{}\nRemote only\t{}\nProblems\t{}\nRepaired\t{}",
+            report.local_files,
+            report.remote_files,
+            report.matched,
+            report.remote_only,
+            report.findings.len(),
+            report.repaired
         );
-        std::process::exit(0x0100);
     }
+    Ok(if report.clean() {
+        EXIT_OK
+    } else {
+        EXIT_INCOMPLETE
+    })
+}
+
+///Create Base Folder on Cloud Provider, make sure it exists locally too
+fn create_roots(client: &dyn CloudClient, roots: &Roots) -> PiSyncResult<()> {
+    for root in roots.iter() {
+        let root_remote_dir = root.local_root().to_str().ok_or_else(|| {
+            error!(log, "Watch dir {:?} is not valid UTF-8", root.local_root());
+            SyncerErrors::InvalidConfig
+        })?;
+        debug!(log, "Using {} as Local Dir to monitor", root_remote_dir);
+
+        if let Err(e) = std::fs::create_dir_all(root_remote_dir) {
+            warn!(log, "Root Folder Create Response: {}", e.to_string());
+        }
+
+        match client.id(root_remote_dir) {
+            Ok(id) => match id {
+                Some(_id) => debug!(log, "Root Dir Exists, not creating"),
+                None => match client.create_dir(root_remote_dir, None) {
+                    Ok(id) => debug!(log, "Created Root Dir {:?}", id),
+                    Err(e) => debug!(log, "Could not create root dir {:?}", e),
+                },
+            },
+            Err(_e) => warn!(log, "Error getting drive id for root folder"),
+        }
+    }
+    Ok(())
+}
+
+///What each upload worker does with a path, counting the uploads that succeed
+fn upload_job(s: &Syncer, uploaded: Arc<AtomicUsize>) -> Arc<UploadJob> {
+    let (queue, online) = (Arc::clone(&s.queue), Arc::clone(&s.online));
+    let (retention, state) = (Arc::clone(&s.retention), Arc::clone(&s.state));
+    Arc::new(move |client: &dyn CloudClient, path: &str| {
+        let queued = if !online.is_online() {
+            debug!(log, "Offline, {} waits in the upload queue", path);
+            queue.held(path)
+        } else {
+            //a panic is a failed upload, its row must not be left marked as uploading
+            let uploaded_file = catch_unwind(AssertUnwindSafe(|| {
+                state.hashed_upload(path, || client.upload_file(path))
+            }))
+            .unwrap_or_else(|_| {
+                error!(log, "Upload of {} panicked", path);
+                Err(SyncerErrors::ProviderError)
+            });
+            match uploaded_file {
+                Ok(id) => {
+                    debug!(log, "created File {}, id = {:?}", path, id);
+                    uploaded.fetch_add(1, Ordering::Relaxed);
+                    if id.is_some() {
+                        retention.uploaded(client, path, SystemTime::now());
+                    }
+                    queue.done(path)
+                }
+                Err(e) if !online.call_failed(client, Instant::now()) => {
+                    //the link is down, not the upload's fault, so no attempt is used up
+                    warn!(
+                        log,
+                        "cannot  create  File{} {}, retrying when back online", path, e
+                    );
+                    queue.held(path)
+                }
+                Err(e) => {
+                    warn!(log, "cannot  create  File{} {}", path, e);
+                    queue.failed(path, &e, SystemTime::now())
+                }
+            }
+        };
+        if let Err(e) = queued {
+            warn!(log, "Upload queue not updated for {} {}", path, e);
+        }
+    })
+}
+
+///Each worker has its own client, the one in the Syncer stays with the calling thread
+fn upload_pool(s: &Syncer, uploaded: Arc<AtomicUsize>) -> PiSyncResult<UploadPool> {
+    let clients = (0..s.config.workers.max(1))
+        .map(|_| cloud_client::new_backend(s.backend_options.clone(), Arc::clone(&s.state)))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| {
+            error!(log, "Cannot configure upload workers: {}", e);
+            e
+        })?;
+    Ok(UploadPool::new(clients, upload_job(s, uploaded)))
+}
+
+fn disk_watch(s: &Syncer) -> PiSyncResult<Option<DiskWatch>> {
+    Ok(s.config.watermarks()?.map(|watermarks| {
+        DiskWatch::new(
+            watermarks,
+            s.roots.clone(),
+            Arc::clone(&s.state),
+            Arc::clone(&s.retention),
+        )
+    }))
+}
+
+///For cron: upload what the backend is missing and any retries due, wait for the uploads, then
+///apply local retention. EXIT_INCOMPLETE if anything is left for next time
+fn sync_once(s: &Syncer) -> PiSyncResult<i32> {
+    if let Err(e) = s.client.probe() {
+        error!(log, "Backend unreachable, nothing synced {}", e);
+        return Ok(EXIT_BACKEND);
+    }
+    let mut files = scanner::missing_files(s.client.as_ref(), &s.roots);
+    files.extend(s.queue.due(SystemTime::now())?);
+    files.sort();
+    files.dedup();
 
-    let handle_event = |_, p: std::path::PathBuf| {
+    let quiet = Duration::from_secs(s.config.quiet_secs);
+    let uploaded = Arc::new(AtomicUsize::new(0));
+    let mut to_upload = 0;
+    let pool = upload_pool(s, Arc::clone(&uploaded))?;
+    for path in files {
+        let p = match path.to_str() {
+            Some(p) => p,
+            None => continue,
+        };
+        if !s.roots.passes_filter(p) || !path.is_file() {
+            debug!(log, "{} is filtered out or gone", p);
+            if let Err(e) = s.queue.done(p) {
+                warn!(log, "Upload queue not updated for {} {}", p, e);
+            }
+            continue;
+        }
+        to_upload += 1;
+        if written_within(&path, quiet) {
+            info!(
+                log,
+                "{} is still being written, leaving it for the next run", p
+            );
+            continue;
+        }
+        if let Err(e) = s.queue.start(p) {
+            warn!(
+                log,
+                "{} not queued, it is not retried if this fails {}", p, e
+            );
+        }
+        if !pool.submit(path.clone()) {
+            //left in the queue for the next run
+            if let Err(e) = s.queue.held(p) {
+                warn!(log, "Upload queue not updated for {} {}", p, e);
+            }
+        }
+    }
+    //waits for the workers to finish
+    drop(pool);
+
+    if let Err(e) = s.retention.sweep(s.client.as_ref(), SystemTime::now()) {
+        warn!(log, "Local retention sweep failed {}", e);
+    }
+    if let Some(watch) = disk_watch(s)? {
+        watch.check(s.client.as_ref());
+    }
+    let uploaded = uploaded.load(Ordering::Relaxed);
+    if s.config.dry_run {
+        info!(log, "Would upload {} of {} files", uploaded, to_upload);
+    } else {
+        info!(log, "Uploaded {} of {} files", uploaded, to_upload);
+    }
+    Ok(if uploaded < to_upload {
+        EXIT_INCOMPLETE
+    } else {
+        EXIT_OK
+    })
+}
+
+fn written_within(path: &Path, quiet: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|meta| meta.modified())
+        .map(|mtime| mtime.elapsed().map(|age| age < quiet).unwrap_or(true))
+        .unwrap_or(false)
+}
+
+///Watch the roots and sync until stopped
+fn daemon(s: &Syncer) -> PiSyncResult<i32> {
+    let (config, roots, queue, online) = (s.config, &s.roots, &s.queue, &s.online);
+    let (retention, syncer_drive_cli) = (&s.retention, &s.client);
+    let pruner = config.pruner()?;
+    let pool = upload_pool(s, Arc::new(AtomicUsize::new(0)))?;
+    if let Some(watch) = disk_watch(s)? {
+        let client = cloud_client::new_backend(s.backend_options.clone(), Arc::clone(&s.state))
+            .map_err(|e| {
+                error!(log, "Cannot configure disk watch: {}", e);
+                e
+            })?;
+        watch.spawn(client);
+    } else {
+        debug!(log, "No disk watermarks, nothing is evicted");
+    }
+
+    let handle_event = |_, p: PathBuf| {
         if let Some(path) = p.to_str() {
-            let file_to_sync = SyncableFile::new(path.into());
-            if syncer_drive_cli.passes_filter(path) {
-                if file_to_sync.is_file() {
-                    match syncer_drive_cli.upload_file(path /*, Some(pid.unwrap().as_str())*/) {
-                        Ok(id) => debug!(log, "created File {}, id = {:?}", path, id),
-                        Err(e) => warn!(log, "cannot  create  File{} {}", path, e),
+            let file_to_sync = roots.file(path);
+            if roots.passes_filter(path) && file_to_sync.is_file() {
+                if !online.is_online() {
+                    debug!(log, "Offline, {} waits in the upload queue", path);
+                    if let Err(e) = queue.push(path) {
+                        warn!(log, "{} not queued, it waits for the next scan {}", path, e);
                     }
-                } else {
-                    info!(log, "Not creating dir {}", path);
+                    return;
                 }
-            } else {
+                if let Err(e) = queue.start(path) {
+                    warn!(
+                        log,
+                        "{} not queued, it is not retried if this fails {}", path, e
+                    );
+                }
+                if !pool.submit(p.clone()) {
+                    warn!(log, "{} not uploaded, it waits in the upload queue", path);
+                    if let Err(e) = queue.held(path) {
+                        warn!(log, "Upload queue not updated for {} {}", path, e);
+                    }
+                }
+                return;
+            } else if !roots.passes_filter(path) {
                 debug!(log, "{} is filtered out", path);
+            } else if file_to_sync.is_dir() {
+                info!(log, "Not creating dir {}", path);
+            } else {
+                debug!(log, "{} is gone before we got to it", path);
+            }
+            //whatever it was queued for, there is nothing to upload now
+            if let Err(e) = queue.done(path) {
+                warn!(log, "Upload queue not updated for {} {}", path, e);
             }
         } else {
             warn!(log, "Cannot Create {:?}", p);
         }
     };
 
+    let handle_remove = |p: PathBuf| {
+        if retention.took(&p) {
+            debug!(log, "{:?} removed after upload, the remote copy stays", p);
+            return;
+        }
+        let trash = match config.on_delete {
+            DeletePolicy::Ignore => return,
+            DeletePolicy::Trash => true,
+            DeletePolicy::Delete => false,
+        };
+        if roots.iter().any(|root| root.local_root() == p.as_path()) {
+            warn!(log, "Watch dir {:?} removed, leaving the remote copy", p);
+        } else if !online.is_online() {
+            warn!(log, "Offline, {:?} is not removed remotely", p);
+        } else if let Some(path) = p.to_str() {
+            match syncer_drive_cli.delete(path, trash) {
+                Ok(()) => debug!(log, "{} removed, {:?} remotely", path, config.on_delete),
+                Err(e) => {
+                    warn!(log, "cannot remove {} remotely {}", path, e);
+                    online.call_failed(syncer_drive_cli.as_ref(), Instant::now());
+                }
+            }
+        } else {
+            warn!(log, "Cannot Remove {:?}", p);
+        }
+    };
+
+    let handle_rename = |from: &Path, to: &Path| {
+        renames::rename_remote(syncer_drive_cli.as_ref(), roots, online, from, to)
+    };
+
     let (sender, receiver) = channel();
     let mut watcher: RecommendedWatcher = Watcher::new_raw(sender).expect("cannot create watcher");
-    watcher
-        .watch(target_dir, RecursiveMode::Recursive)
-        .expect("cannot create watcher");
+    for root in roots.iter() {
+        watcher
+            .watch(root.local_root(), RecursiveMode::Recursive)
+            .expect("cannot create watcher");
+    }
+
+    //only once the watcher is up, so nothing written during the scan is missed
+    let rescan = config.rescan_secs.map(Duration::from_secs);
+    let mut next_scan = Some(Instant::now());
+    //files kept a while after upload, or whose removal failed
+    let mut next_sweep = Instant::now();
+    let mut next_prune = pruner.as_ref().map(|_| Instant::now());
+    //uploads wait here until the file is closed or stops changing
+    let mut pending = PendingFiles::new(Duration::from_secs(config.quiet_secs));
+    //the old half of a rename, waiting for its new half
+    let mut renames = PendingRenames::default();
 
     loop {
-        match receiver.recv() {
+        let mut wake: Vec<Instant> = pending
+            .next_check()
+            .into_iter()
+            .chain(renames.next_check())
+            .chain(online.next_probe())
+            .collect();
+        //offline, the scan and the queue wait for a probe to get an answer
+        if online.is_online() {
+            wake.extend(next_scan);
+            wake.push(next_sweep);
+            wake.extend(next_prune);
+            wake.extend(queue.next_due().ok().flatten().map(|at| {
+                Instant::now() + at.duration_since(SystemTime::now()).unwrap_or_default()
+            }));
+        }
+        let wake = wake.into_iter().min();
+        let event = match wake {
+            Some(at) => receiver.recv_timeout(at.saturating_duration_since(Instant::now())),
+            None => receiver.recv().map_err(|_e| RecvTimeoutError::Disconnected),
+        };
+        match event {
+            Err(RecvTimeoutError::Timeout) => {}
             Ok(notify::RawEvent {
                 path: Some(path),
                 op: Ok(op),
                 cookie,
             }) => {
-                if op == notify::Op::CREATE {
+                if op.contains(notify::Op::CREATE) {
+                    trace!(log, "handled event {:?}{:?}{:?}", path, op, cookie);
+                    pending.created(path, Instant::now());
+                } else if op.contains(notify::Op::CLOSE_WRITE) {
+                    trace!(log, "handled event {:?}{:?}{:?}", path, op, cookie);
+                    //new or rewritten in place, closed after writing it is ready either way
+                    pending.closed(&path);
+                    handle_event("", path);
+                } else if op.contains(notify::Op::WRITE) {
+                    pending.written(&path, Instant::now());
+                } else if op.contains(notify::Op::REMOVE) {
                     trace!(log, "handled event {:?}{:?}{:?}", path, op, cookie);
-                    handle_event("", path.clone());
+                    pending.closed(&path);
+                    handle_remove(path);
+                } else if op.contains(notify::Op::RENAME) {
+                    trace!(log, "handled event {:?}{:?}{:?}", path, op, cookie);
+                    let now = Instant::now();
+                    let from = match cookie {
+                        Some(cookie) if !path.exists() => {
+                            renames.moved_from(cookie, path, now);
+                            continue;
+                        }
+                        Some(cookie) => renames.moved_to(cookie),
+                        None => None,
+                    };
+                    match from {
+                        //still being written, it is uploaded under its new name once ready
+                        Some(from) if pending.closed(&from).is_some() => pending.created(path, now),
+                        Some(from) if handle_rename(&from, &path) => {}
+                        //never synced or moved in from outside, either way it is new to us
+                        _ if path.exists() => {
+                            for file in scanner::files_in(&path) {
+                                pending.created(file, now);
+                            }
+                        }
+                        _ => handle_remove(path),
+                    }
                 } else {
                     warn!(log, "unhandled event {:?}{:?}{:?}", path, op, cookie);
                 }
@@ -157,5 +650,55 @@ fn main() {
             Ok(other) => trace!(log, "unhandled event {:?}", other),
             Err(e) => error!(log, "watch error: {:?}", e),
         }
+
+        let now = Instant::now();
+        online.probe_if_due(syncer_drive_cli.as_ref(), now);
+        for path in renames.moved_out(now) {
+            pending.closed(&path);
+            handle_remove(path);
+        }
+        for path in pending.ready(now) {
+            handle_event("", path);
+        }
+        if !online.is_online() {
+            continue;
+        }
+        //retries, what built up while offline, and on startup whatever was queued when we stopped
+        match queue.due(SystemTime::now()) {
+            Ok(due) => due.into_iter().for_each(|path| handle_event("", path)),
+            Err(e) => warn!(log, "Cannot read the upload queue {}", e),
+        }
+        //files the full queue could not take are only found again by a scan. Not straight away,
+        //so uploads that keep failing with the queue full are not rescanned in a tight loop
+        if queue.take_left_for_scan() {
+            info!(
+                log,
+                "Files were left out of the full upload queue, scanning in {}s", config.retry_secs
+            );
+            let at = now + Duration::from_secs(config.retry_secs);
+            next_scan = Some(next_scan.map_or(at, |next| next.min(at)));
+        }
+        //the drain may have found the link down again
+        if online.is_online() && next_scan.map(|at| at <= now).unwrap_or(false) {
+            //what the scan finds may still be being written too
+            for path in scanner::missing_files(syncer_drive_cli.as_ref(), roots) {
+                pending.created(path, now);
+            }
+            next_scan = rescan.map(|every| now + every);
+        }
+        if online.is_online() && next_sweep <= now {
+            if let Err(e) = retention.sweep(syncer_drive_cli.as_ref(), SystemTime::now()) {
+                warn!(log, "Local retention sweep failed {}", e);
+            }
+            next_sweep = now + retention::SWEEP_EVERY;
+        }
+        if let (true, Some(pruner), Some(at)) = (online.is_online(), &pruner, next_prune) {
+            if at <= now {
+                if let Err(e) = pruner.run(syncer_drive_cli.as_ref(), config.dry_run) {
+                    warn!(log, "Remote retention failed {}", e);
+                }
+                next_prune = Some(now + pruner.every());
+            }
+        }
     }
 }
@@ -0,0 +1,110 @@
+//!Whether the backend can be reached. A failed call is followed by a probe and if that fails too
+//!we are offline: uploads only go into the queue and nothing else calls the backend until a
+//!probe, one every probe_every, gets an answer again
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+pub struct Connectivity {
+    probe_every: Duration,
+    ///When to probe next, None while online. Shared by the watcher thread and the workers
+    next_probe: Mutex<Option<Instant>>,
+}
+
+impl Connectivity {
+    pub fn new(probe_every: Duration) -> Self {
+        Connectivity {
+            probe_every,
+            next_probe: Mutex::new(None),
+        }
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.next_probe.lock().unwrap().is_none()
+    }
+
+    ///A call to the backend failed, probe to tell an outage from a failure of that one call.
+    ///Returns whether we are still online
+    pub fn call_failed(&self, client: &dyn CloudClient, now: Instant) -> bool {
+        if client.probe().is_ok() {
+            return true;
+        }
+        warn!(
+            log,
+            "Backend unreachable, holding uploads until it is back, probing every {:?}",
+            self.probe_every
+        );
+        *self.next_probe.lock().unwrap() = Some(now + self.probe_every);
+        false
+    }
+
+    ///Probe if offline and one is due, true if that brought us back online
+    pub fn probe_if_due(&self, client: &dyn CloudClient, now: Instant) -> bool {
+        let next_probe = *self.next_probe.lock().unwrap();
+        match next_probe {
+            Some(at) if at <= now => {
+                if client.probe().is_ok() {
+                    info!(log, "Backend reachable again");
+                    *self.next_probe.lock().unwrap() = None;
+                    true
+                } else {
+                    debug!(log, "Backend still unreachable");
+                    *self.next_probe.lock().unwrap() = Some(now + self.probe_every);
+                    false
+                }
+            }
+            _ => false,
+        }
+    }
+
+    ///When probe_if_due should next be called, None while online
+    pub fn next_probe(&self) -> Option<Instant> {
+        *self.next_probe.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_backend::LocalDirBackend;
+    use crate::offline::*;
+    use crate::state_db::StateDb;
+    use crate::upload_handler::{RootMapping, Roots};
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_offline_probes_until_back() {
+        let dir = tempdir().unwrap();
+        //the target dir cannot be created while a file is in the way, like an unmounted NAS
+        let blocker = dir.path().join("nas");
+        std::fs::write(&blocker, b"").unwrap();
+        let lb = LocalDirBackend::new(
+            blocker.join("share").to_str().unwrap(),
+            Roots::from(RootMapping::new(
+                dir.path().into(),
+                "RpiCamera".into(),
+                vec![],
+            )),
+            Arc::new(StateDb::open_in_memory().unwrap()),
+        );
+        let every = Duration::from_secs(30);
+        let online = Connectivity::new(every);
+        let start = Instant::now();
+
+        assert!(online.is_online());
+        assert!(!online.call_failed(&lb, start));
+        assert!(!online.is_online());
+        assert_eq!(Some(start + every), online.next_probe());
+
+        assert!(!online.probe_if_due(&lb, start + every));
+        assert_eq!(Some(start + every * 2), online.next_probe());
+
+        std::fs::remove_file(&blocker).unwrap();
+        assert!(!online.probe_if_due(&lb, start + every));
+        assert!(online.probe_if_due(&lb, start + every * 2));
+        assert!(online.is_online());
+        assert_eq!(None, online.next_probe());
+        assert!(online.call_failed(&lb, start));
+    }
+}
@@ -0,0 +1,165 @@
+//!Files we have seen created or written that may still be being written, e.g. a RaspiMJPEG video
+//!mid recording, or a synced file rewritten in place. They are held back until closed, or until
+//!their size and mtime stop changing
+use crate::common::LOG as log;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+struct Pending {
+    size: u64,
+    mtime: Option<SystemTime>,
+    ///When we last saw the file change
+    changed: Instant,
+}
+
+pub struct PendingFiles {
+    quiet: Duration,
+    files: HashMap<PathBuf, Pending>,
+}
+
+impl PendingFiles {
+    ///Files are ready once unchanged for the quiet period
+    pub fn new(quiet: Duration) -> Self {
+        PendingFiles {
+            quiet,
+            files: HashMap::new(),
+        }
+    }
+
+    ///Start waiting on a new file, a repeat for a path already waiting just restarts its wait
+    pub fn created(&mut self, path: PathBuf, now: Instant) {
+        let (size, mtime) = stamp(&path);
+        trace!(log, "Waiting on {:?}", path);
+        self.files.insert(
+            path,
+            Pending {
+                size,
+                mtime,
+                changed: now,
+            },
+        );
+    }
+
+    ///Writes to a waiting file restart its wait. A write to a file we never saw created is a
+    ///rewrite in place, it waits the same as a new one
+    pub fn written(&mut self, path: &Path, now: Instant) {
+        match self.files.get_mut(path) {
+            Some(pending) => pending.changed = now,
+            None if path.is_file() => self.created(path.to_owned(), now),
+            None => {}
+        }
+    }
+
+    ///Stop waiting on a file, e.g. it was removed or has been closed after writing
+    pub fn closed(&mut self, path: &Path) -> Option<PathBuf> {
+        self.files.remove_entry(path).map(|(path, _)| path)
+    }
+
+    ///Take every file that has been stable for the quiet period, files that are gone are dropped
+    pub fn ready(&mut self, now: Instant) -> Vec<PathBuf> {
+        let quiet = self.quiet;
+        let mut ready = vec![];
+        self.files.retain(|path, pending| {
+            if !path.exists() {
+                debug!(log, "{:?} is gone before we got to it", path);
+                return false;
+            }
+            let (size, mtime) = stamp(path);
+            if size != pending.size || mtime != pending.mtime {
+                pending.size = size;
+                pending.mtime = mtime;
+                pending.changed = now;
+                true
+            } else if now.duration_since(pending.changed) >= quiet {
+                ready.push(path.clone());
+                false
+            } else {
+                true
+            }
+        });
+        ready.sort();
+        ready
+    }
+
+    ///When ready should next be called, None if nothing is waiting
+    pub fn next_check(&self) -> Option<Instant> {
+        self.files.values().map(|p| p.changed + self.quiet).min()
+    }
+}
+
+fn stamp(path: &Path) -> (u64, Option<SystemTime>) {
+    std::fs::metadata(path)
+        .map(|m| (m.len(), m.modified().ok()))
+        .unwrap_or((0, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::pending::*;
+    use tempfile::tempdir;
+
+    const QUIET: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn test_pending_waits_for_quiet_period() {
+        let dir = tempdir().unwrap();
+        let video = dir.path().join("vi1.mp4");
+        std::fs::write(&video, b"part").unwrap();
+        let start = Instant::now();
+        let mut pending = PendingFiles::new(QUIET);
+
+        pending.created(video.clone(), start);
+        assert_eq!(Some(start + QUIET), pending.next_check());
+        assert!(pending.ready(start + Duration::from_secs(1)).is_empty());
+
+        //still recording, the wait starts again from when we noticed
+        std::fs::write(&video, b"part and more").unwrap();
+        let grew = start + QUIET;
+        assert!(pending.ready(grew).is_empty());
+        assert_eq!(Some(grew + QUIET), pending.next_check());
+
+        assert_eq!(vec![video], pending.ready(grew + QUIET));
+        assert_eq!(None, pending.next_check());
+    }
+
+    #[test]
+    fn test_pending_coalesces_writes() {
+        let dir = tempdir().unwrap();
+        let image = dir.path().join("im1.jpg");
+        std::fs::write(&image, b"jpeg").unwrap();
+        let start = Instant::now();
+        let mut pending = PendingFiles::new(QUIET);
+
+        pending.created(image.clone(), start);
+        pending.created(image.clone(), start);
+        let later = start + Duration::from_secs(3);
+        pending.written(&image, later);
+        assert!(pending.ready(start + QUIET).is_empty());
+        assert_eq!(vec![image], pending.ready(later + QUIET));
+    }
+
+    #[test]
+    fn test_pending_closed_and_gone() {
+        let dir = tempdir().unwrap();
+        let image = dir.path().join("im1.jpg");
+        let gone = dir.path().join("im2.jpg");
+        std::fs::write(&image, b"jpeg").unwrap();
+        std::fs::write(&gone, b"jpeg").unwrap();
+        let start = Instant::now();
+        let mut pending = PendingFiles::new(QUIET);
+
+        //a rewrite in place waits like a new file, a write to one already gone does not
+        pending.written(&image, start);
+        pending.written(&dir.path().join("im3.jpg"), start);
+        assert_eq!(Some(start + QUIET), pending.next_check());
+        assert_eq!(Some(image.clone()), pending.closed(&image));
+
+        pending.created(image.clone(), start);
+        pending.created(gone.clone(), start);
+        assert_eq!(Some(image.clone()), pending.closed(&image));
+        std::fs::remove_file(&gone).unwrap();
+        assert!(pending.ready(start + QUIET).is_empty());
+        assert_eq!(None, pending.next_check());
+    }
+}
@@ -7,6 +7,10 @@ pub enum SyncerErrors {
     SyncerNoneError,
     NoAppSecret,
     ProviderError,
+    UnknownBackend,
+    InvalidConfig,
+    StateDbError,
+    NotSupported,
 }
 impl std::error::Error for SyncerErrors {}
 pub type PiSyncResult<T> = std::result::Result<T, SyncerErrors>;
@@ -24,6 +28,10 @@ impl fmt::Display for SyncerErrors {
             SyncerErrors::SyncerNoneError => write!(f, "Missing a value/response/input somwehere"),
             SyncerErrors::NoAppSecret => write!(f, "Missing Auth Secret/Creds"),
             SyncerErrors::ProviderError => write!(f, "Issue with call to Storgare Provider"),
+            SyncerErrors::UnknownBackend => write!(f, "No such Storage Backend"),
+            SyncerErrors::InvalidConfig => write!(f, "Cannot read or parse the config file"),
+            SyncerErrors::StateDbError => write!(f, "Cannot read or write the local state db"),
+            SyncerErrors::NotSupported => write!(f, "Not supported by this Storage Backend"),
         }
     }
 }
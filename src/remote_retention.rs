@@ -0,0 +1,275 @@
+//!Keeps the backend under quota. Synced files are trashed once older than max_age_days, which
+//!rules can override by file name, e.g. images for 90 days and videos for 14, then the oldest
+//!go until what is left fits max_total_mb. Our records are kept, so nothing is uploaded again
+use crate::cloud_client::{CloudClient, RemoteFile};
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use serde::Deserialize;
+use std::fmt;
+use std::time::Duration;
+
+///The [remote_retention] section of the config
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct RemoteRetention {
+    ///Trash files uploaded longer ago than this, unless a rule says otherwise
+    pub max_age_days: Option<u64>,
+    ///Trash the oldest files until the rest add up to no more than this
+    pub max_total_mb: Option<u64>,
+    ///How often the daemon prunes
+    pub every_hours: u64,
+    ///The first rule whose filter matches a file name wins
+    pub rules: Vec<AgeRule>,
+}
+
+impl Default for RemoteRetention {
+    fn default() -> Self {
+        RemoteRetention {
+            max_age_days: None,
+            max_total_mb: None,
+            every_hours: 24,
+            rules: vec![],
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct AgeRule {
+    ///Regex on the file name, as for filters
+    pub filter: String,
+    ///Left out to keep matching files whatever their age
+    pub max_age_days: Option<u64>,
+}
+
+///Why a file is to be trashed
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PruneReason {
+    ///Older than this many days
+    Age(u64),
+    ///The oldest left while over budget
+    Budget,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Prune {
+    pub file: RemoteFile,
+    pub reason: PruneReason,
+}
+
+impl fmt::Display for Prune {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let why = match self.reason {
+            PruneReason::Age(days) => format!("older than {} days", days),
+            PruneReason::Budget => "over the size budget".to_owned(),
+        };
+        write!(
+            f,
+            "{}\t{}\t{}\t{}",
+            self.file.created.format("%Y-%m-%d %H:%M"),
+            self.file.size,
+            why,
+            self.file.cloud_path.display()
+        )
+    }
+}
+
+pub struct Pruner {
+    max_age_days: Option<u64>,
+    max_total: Option<u64>,
+    rules: Vec<(Regex, Option<u64>)>,
+    every: Duration,
+}
+
+impl Pruner {
+    pub fn new(config: &RemoteRetention) -> PiSyncResult<Pruner> {
+        let rules = config
+            .rules
+            .iter()
+            .map(|rule| {
+                Regex::new(&rule.filter)
+                    .map(|re| (re, rule.max_age_days))
+                    .map_err(|e| {
+                        error!(log, "Bad remote_retention filter {} {}", rule.filter, e);
+                        SyncerErrors::InvalidConfig
+                    })
+            })
+            .collect::<PiSyncResult<_>>()?;
+        Ok(Pruner {
+            max_age_days: config.max_age_days,
+            max_total: config.max_total_mb.map(|mb| mb * 1024 * 1024),
+            rules,
+            every: Duration::from_secs(config.every_hours.max(1) * 3600),
+        })
+    }
+
+    pub fn every(&self) -> Duration {
+        self.every
+    }
+
+    ///What should go, oldest first
+    pub fn plan(&self, mut files: Vec<RemoteFile>, now: DateTime<Utc>) -> Vec<Prune> {
+        files.sort_by_key(|f| f.created);
+        let mut prunes = vec![];
+        let mut kept = vec![];
+        for file in files {
+            match self.max_age(&file) {
+                Some(days) if (now - file.created).num_days() >= days as i64 => {
+                    prunes.push(Prune {
+                        file,
+                        reason: PruneReason::Age(days),
+                    })
+                }
+                _ => kept.push(file),
+            }
+        }
+        if let Some(max_total) = self.max_total {
+            let mut total: u64 = kept.iter().map(|f| f.size).sum();
+            for file in kept {
+                if total <= max_total {
+                    break;
+                }
+                total -= file.size;
+                prunes.push(Prune {
+                    file,
+                    reason: PruneReason::Budget,
+                });
+            }
+        }
+        prunes
+    }
+
+    ///List the backend and trash what plan says should go, or with dry_run only say so.
+    ///Returns what was, or would have been, trashed
+    pub fn run(&self, client: &dyn CloudClient, dry_run: bool) -> PiSyncResult<Vec<Prune>> {
+        let prunes = self.plan(client.list_remote()?, Utc::now());
+        for prune in &prunes {
+            if dry_run {
+                info!(log, "Would trash {}", prune);
+                continue;
+            }
+            match client.trash_remote(&prune.file.remote_id) {
+                Ok(()) => info!(log, "Trashed {}", prune),
+                Err(e) => warn!(log, "Cannot trash {} {}", prune, e),
+            }
+        }
+        info!(
+            log,
+            "Remote retention {} {} files",
+            if dry_run { "would trash" } else { "trashed" },
+            prunes.len()
+        );
+        Ok(prunes)
+    }
+
+    fn max_age(&self, file: &RemoteFile) -> Option<u64> {
+        let name = file
+            .cloud_path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .unwrap_or_default();
+        self.rules
+            .iter()
+            .find(|(re, _)| re.is_match(name))
+            .map(|(_, days)| *days)
+            .unwrap_or(self.max_age_days)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::remote_retention::*;
+
+    fn file(name: &str, days_old: i64, size: u64, now: DateTime<Utc>) -> RemoteFile {
+        RemoteFile::new(
+            format!("id-{}", name),
+            base64::encode(name),
+            format!("RpiCamera/{}", name).into(),
+            size,
+            None,
+            now - chrono::Duration::days(days_old),
+        )
+    }
+
+    fn names(prunes: &[Prune]) -> Vec<(String, PruneReason)> {
+        prunes
+            .iter()
+            .map(|p| (p.file.remote_id.clone(), p.reason))
+            .collect()
+    }
+
+    #[test]
+    fn test_remote_retention_by_age() {
+        let config: RemoteRetention = toml::from_str(
+            r#"
+            max_age_days = 30
+            [[rules]]
+            filter = "^im.*jpg$"
+            max_age_days = 90
+            [[rules]]
+            filter = "^vi.*mp4$"
+            max_age_days = 14
+            [[rules]]
+            filter = "\\.log$"
+            "#,
+        )
+        .unwrap();
+        let pruner = Pruner::new(&config).unwrap();
+        let now = Utc::now();
+        let files = vec![
+            file("im1.jpg", 60, 1, now),
+            file("im2.jpg", 100, 1, now),
+            file("vi1.mp4", 20, 1, now),
+            file("vi2.mp4", 10, 1, now),
+            file("motion.log", 400, 1, now),
+            file("notes.txt", 31, 1, now),
+        ];
+
+        assert_eq!(
+            vec![
+                ("id-im2.jpg".to_owned(), PruneReason::Age(90)),
+                ("id-notes.txt".to_owned(), PruneReason::Age(30)),
+                ("id-vi1.mp4".to_owned(), PruneReason::Age(14)),
+            ],
+            names(&pruner.plan(files, now))
+        );
+        assert_eq!(Duration::from_secs(24 * 3600), pruner.every());
+    }
+
+    #[test]
+    fn test_remote_retention_by_size() {
+        let mb = 1024 * 1024;
+        let config = RemoteRetention {
+            max_age_days: Some(30),
+            max_total_mb: Some(5),
+            ..Default::default()
+        };
+        let pruner = Pruner::new(&config).unwrap();
+        let now = Utc::now();
+        let files = vec![
+            file("vi3.mp4", 1, 2 * mb, now),
+            file("vi1.mp4", 40, 2 * mb, now),
+            file("vi2.mp4", 3, 2 * mb, now),
+            file("vi4.mp4", 0, 2 * mb, now),
+        ];
+
+        //the age limit goes first, then the oldest of the rest until 4MiB is left
+        assert_eq!(
+            vec![
+                ("id-vi1.mp4".to_owned(), PruneReason::Age(30)),
+                ("id-vi2.mp4".to_owned(), PruneReason::Budget),
+            ],
+            names(&pruner.plan(files, now))
+        );
+        let bad = RemoteRetention {
+            rules: vec![AgeRule {
+                filter: "(".into(),
+                max_age_days: None,
+            }],
+            ..Default::default()
+        };
+        assert!(Pruner::new(&bad).is_err());
+    }
+}
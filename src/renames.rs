@@ -0,0 +1,165 @@
+//!Pair up the two halves of a rename. inotify reports a move as a RENAME for the old path then
+//!one for the new path carrying the same cookie. A half with no partner was a move into or out
+//!of the watched tree
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use crate::offline::Connectivity;
+use crate::upload_handler::Roots;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+///How long the old path waits for its new path, the two arrive together in practice
+const PAIR_WAIT: Duration = Duration::from_millis(500);
+
+#[derive(Default)]
+pub struct PendingRenames {
+    froms: HashMap<u32, (PathBuf, Instant)>,
+}
+
+impl PendingRenames {
+    ///The old path of a rename, it waits for its new path
+    pub fn moved_from(&mut self, cookie: u32, path: PathBuf, now: Instant) {
+        trace!(
+            log,
+            "Waiting on the new path for rename {} of {:?}",
+            cookie,
+            path
+        );
+        self.froms.insert(cookie, (path, now));
+    }
+
+    ///The new path of a rename, giving back the old path it pairs with. None means it was
+    ///moved in from outside the watched tree
+    pub fn moved_to(&mut self, cookie: u32) -> Option<PathBuf> {
+        self.froms.remove(&cookie).map(|(from, _)| from)
+    }
+
+    ///Old paths that never got a new one, they were moved out of the watched tree
+    pub fn moved_out(&mut self, now: Instant) -> Vec<PathBuf> {
+        let mut gone = vec![];
+        self.froms.retain(|_cookie, (from, at)| {
+            if now.duration_since(*at) >= PAIR_WAIT {
+                gone.push(from.clone());
+                false
+            } else {
+                true
+            }
+        });
+        gone.sort();
+        gone
+    }
+
+    ///When moved_out should next be called, None if nothing is waiting
+    pub fn next_check(&self) -> Option<Instant> {
+        self.froms.values().map(|(_, at)| *at + PAIR_WAIT).min()
+    }
+}
+
+///Rename the remote copy of a paired rename. False when to should be uploaded as a new file
+///instead: from was never synced, we are offline, or the rename failed
+pub fn rename_remote(
+    client: &dyn CloudClient,
+    roots: &Roots,
+    online: &Connectivity,
+    from: &Path,
+    to: &Path,
+) -> bool {
+    if roots.iter().any(|root| root.local_root() == from) {
+        warn!(log, "Watch dir {:?} renamed, leaving the remote copy", from);
+        return true;
+    }
+    if !online.is_online() {
+        warn!(log, "Offline, {:?} is uploaded again as {:?}", from, to);
+        return false;
+    }
+    match (from.to_str(), to.to_str()) {
+        (Some(f), Some(t)) => match client.rename(f, t) {
+            Ok(Some(id)) => {
+                debug!(log, "renamed {} to {}, id = {}", f, t, id);
+                true
+            }
+            Ok(None) => false,
+            Err(e) => {
+                warn!(
+                    log,
+                    "cannot rename {} to {} remotely, uploading it again {}", f, t, e
+                );
+                online.call_failed(client, Instant::now());
+                false
+            }
+        },
+        _ => {
+            warn!(log, "Cannot Rename {:?} to {:?}", from, to);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_client::CloudClient;
+    use crate::local_fixture::LocalFixture;
+    use crate::renames::*;
+
+    #[test]
+    fn test_renames_pair() {
+        let now = Instant::now();
+        let mut renames = PendingRenames::default();
+        renames.moved_from(7, "/w/a.jpg".into(), now);
+        renames.moved_from(8, "/w/b.jpg".into(), now);
+        assert_eq!(Some(now + PAIR_WAIT), renames.next_check());
+        assert_eq!(Some("/w/a.jpg".into()), renames.moved_to(7));
+        assert_eq!(None, renames.moved_to(7));
+        assert!(renames.moved_out(now).is_empty());
+        assert_eq!(
+            vec![PathBuf::from("/w/b.jpg")],
+            renames.moved_out(now + PAIR_WAIT)
+        );
+        assert_eq!(None, renames.next_check());
+    }
+
+    #[test]
+    fn test_renames_rename_remote() {
+        let s = LocalFixture::new();
+        let online = Connectivity::new(Duration::from_secs(30));
+        let from = s.write("a.jpg", b"jpeg");
+        s.lb.upload_file(&from).unwrap();
+        std::fs::rename(&from, s.local.path().join("b.jpg")).unwrap();
+
+        let to = s.local.path().join("b.jpg");
+        assert!(rename_remote(
+            &s.lb,
+            &s.roots,
+            &online,
+            Path::new(&from),
+            &to
+        ));
+        assert!(s.target.path().join("RpiCamera/b.jpg").is_file());
+        //never synced, so it is uploaded as new
+        let never = s.local.path().join("c.jpg");
+        assert!(!rename_remote(&s.lb, &s.roots, &online, &never, &to));
+    }
+
+    #[test]
+    fn test_renames_rename_remote_fails() {
+        let s = LocalFixture::new();
+        let online = Connectivity::new(Duration::from_secs(30));
+        let from = s.write("a.jpg", b"jpeg");
+        s.lb.upload_file(&from).unwrap();
+        //a dir in the way of the new name
+        std::fs::create_dir_all(s.target.path().join("RpiCamera/b.jpg/x")).unwrap();
+
+        //not lost, it falls through to an upload, and the backend is still there
+        let to = s.local.path().join("b.jpg");
+        assert!(!rename_remote(
+            &s.lb,
+            &s.roots,
+            &online,
+            Path::new(&from),
+            &to
+        ));
+        assert!(online.is_online());
+        assert!(s.target.path().join("RpiCamera/a.jpg").is_file());
+    }
+}
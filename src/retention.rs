@@ -0,0 +1,330 @@
+//!What happens to a local file once it is safely on the backend, so the SD card does not fill.
+//!A file is only ever removed once confirmed: the state db has it uploaded with the content it
+//!has now and, with verify_md5, the backend reports the same md5. Anything we remove is noted so
+//!the watcher does not mirror the removal to the backend
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::state_db::{md5_hex, StateDb};
+use crate::upload_handler::{FileOperations, Roots};
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+///How often files kept for a while are checked for being old enough to go
+pub const SWEEP_EVERY: Duration = Duration::from_secs(3600);
+
+///What to do with the local copy of an uploaded file
+#[derive(Deserialize, Debug, PartialEq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum AfterUpload {
+    Keep,
+    Delete,
+    ///Move it under archive_dir, e.g. a USB disk, keeping its place in the remote tree
+    Archive,
+}
+
+#[derive(new, Debug, Clone, PartialEq)]
+pub struct RetentionPolicy {
+    after_upload: AfterUpload,
+    archive_dir: Option<PathBuf>,
+    ///Leave uploaded files this long, by mtime, before they are deleted or archived
+    keep_for: Option<Duration>,
+    ///Ask the backend for its md5 too, rather than trusting a successful upload
+    verify_md5: bool,
+    ///Only log what would be deleted or archived
+    dry_run: bool,
+}
+
+impl RetentionPolicy {
+    ///Archiving needs somewhere to archive to, outside the roots or it would be uploaded again
+    pub fn check(&self, roots: &Roots) -> PiSyncResult<()> {
+        match (self.after_upload, &self.archive_dir) {
+            (AfterUpload::Archive, None) => {
+                error!(log, "after_upload = \"archive\" needs an archive_dir");
+                Err(SyncerErrors::InvalidConfig)
+            }
+            (AfterUpload::Archive, Some(dir)) if roots.mapping_for(dir).is_some() => {
+                error!(log, "archive_dir {:?} is inside a watched dir", dir);
+                Err(SyncerErrors::InvalidConfig)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+pub struct LocalRetention {
+    policy: RetentionPolicy,
+    roots: Roots,
+    state: Arc<StateDb>,
+    ///Removed by us, their remove events are ours and not the user's
+    released: Mutex<HashSet<PathBuf>>,
+}
+
+impl LocalRetention {
+    pub fn new(policy: RetentionPolicy, roots: Roots, state: Arc<StateDb>) -> Self {
+        LocalRetention {
+            policy,
+            roots,
+            state,
+            released: Mutex::new(HashSet::new()),
+        }
+    }
+
+    ///An upload of local_path succeeded. Deleted or archived now unless it is to be kept a while,
+    ///then the sweep gets to it
+    pub fn uploaded(&self, client: &dyn CloudClient, local_path: &str, now: SystemTime) {
+        if self.policy.after_upload == AfterUpload::Keep || !self.old_enough(local_path, now) {
+            return;
+        }
+        if let Err(e) = self.release(client, local_path) {
+            warn!(log, "Keeping {} for now {}", local_path, e);
+        }
+    }
+
+    ///Delete or archive every synced file that has now been kept long enough
+    pub fn sweep(&self, client: &dyn CloudClient, now: SystemTime) -> PiSyncResult<usize> {
+        if self.policy.after_upload == AfterUpload::Keep {
+            return Ok(0);
+        }
+        let mut released = 0;
+        for record in self.state.synced_files()? {
+            let path = record.local_path.as_str();
+            if !Path::new(path).is_file() || !self.old_enough(path, now) {
+                continue;
+            }
+            match self.release(client, path) {
+                Ok(true) => released += 1,
+                Ok(false) => {}
+                Err(e) => warn!(log, "Keeping {} for now {}", path, e),
+            }
+        }
+        debug!(log, "Retention sweep removed {} local files", released);
+        Ok(released)
+    }
+
+    ///Whether the backend holds local_path as it is on disk now
+    pub fn confirmed(&self, client: &dyn CloudClient, local_path: &str) -> PiSyncResult<bool> {
+        let s = self.roots.file(local_path);
+        let recorded = match self.state.get(&s.get_unique_id()?)? {
+            Some(record) if !record.is_dir => record.checksum,
+            _ => None,
+        };
+        let md5 = md5_hex(local_path)?;
+        if recorded.as_ref() != Some(&md5) {
+            debug!(log, "{} has changed since it was uploaded", local_path);
+            return Ok(false);
+        }
+        if self.policy.verify_md5 && client.remote_md5(local_path)?.as_ref() != Some(&md5) {
+            warn!(
+                log,
+                "Remote copy of {} does not match, keeping it", local_path
+            );
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    ///Delete or archive local_path if it is confirmed uploaded. False if it was not
+    pub fn release(&self, client: &dyn CloudClient, local_path: &str) -> PiSyncResult<bool> {
+        if self.policy.after_upload == AfterUpload::Keep || !self.confirmed(client, local_path)? {
+            return Ok(false);
+        }
+        self.remove(local_path, self.policy.after_upload)
+            .map(|_| true)
+    }
+
+    ///Delete local_path to free space, whatever after_upload says, if it is confirmed uploaded.
+    ///False if it was not
+    pub fn evict(&self, client: &dyn CloudClient, local_path: &str) -> PiSyncResult<bool> {
+        if !self.confirmed(client, local_path)? {
+            return Ok(false);
+        }
+        self.remove(local_path, AfterUpload::Delete).map(|_| true)
+    }
+
+    ///Whether removals are only logged
+    pub fn dry_run(&self) -> bool {
+        self.policy.dry_run
+    }
+
+    fn remove(&self, local_path: &str, how: AfterUpload) -> PiSyncResult<()> {
+        if self.policy.dry_run {
+            match (how, &self.policy.archive_dir) {
+                (AfterUpload::Archive, Some(dir)) => info!(
+                    log,
+                    "Would archive uploaded {} to {:?}",
+                    local_path,
+                    dir.join(self.roots.file(local_path).cloud_path()?)
+                ),
+                _ => info!(log, "Would delete uploaded {}", local_path),
+            }
+            return Ok(());
+        }
+        let path = PathBuf::from(local_path);
+        self.released.lock().unwrap().insert(path.clone());
+        let result = match (how, &self.policy.archive_dir) {
+            (AfterUpload::Archive, Some(dir)) => {
+                let archived = dir.join(self.roots.file(local_path).cloud_path()?);
+                info!(log, "Archiving uploaded {} to {:?}", local_path, archived);
+                archive(&path, &archived)
+            }
+            _ => {
+                info!(log, "Deleting uploaded {}", local_path);
+                std::fs::remove_file(&path)
+            }
+        };
+        result.map_err(|e| {
+            self.released.lock().unwrap().remove(&path);
+            error!(log, "Cannot remove {} {}", local_path, e);
+            SyncerErrors::InvalidPathError
+        })
+    }
+
+    ///True, once, if we removed local_path, so its remove event is not mirrored
+    pub fn took(&self, local_path: &Path) -> bool {
+        self.released.lock().unwrap().remove(local_path)
+    }
+
+    fn old_enough(&self, local_path: &str, now: SystemTime) -> bool {
+        let keep_for = match self.policy.keep_for {
+            Some(keep_for) => keep_for,
+            None => return true,
+        };
+        std::fs::metadata(local_path)
+            .and_then(|meta| meta.modified())
+            .map(|mtime| now.duration_since(mtime).unwrap_or_default() >= keep_for)
+            .unwrap_or(false)
+    }
+}
+
+///Rename, or copy and remove when the archive is on another filesystem
+fn archive(from: &Path, to: &Path) -> std::io::Result<()> {
+    if let Some(parent) = to.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::rename(from, to).or_else(|_| {
+        std::fs::copy(from, to)?;
+        std::fs::remove_file(from)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_fixture::LocalFixture;
+    use crate::retention::*;
+    use tempfile::tempdir;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    fn retention(s: &LocalFixture, policy: RetentionPolicy) -> LocalRetention {
+        LocalRetention::new(policy, s.roots.clone(), Arc::clone(&s.state))
+    }
+
+    #[test]
+    fn test_retention_delete_confirmed_only() {
+        let s = LocalFixture::new();
+        let r = retention(
+            &s,
+            RetentionPolicy::new(AfterUpload::Delete, None, None, true, false),
+        );
+        let never = s.write("im1.jpg", b"jpeg");
+        let changed = s.write("im2.jpg", b"jpeg");
+        let uploaded = s.write("im3.jpg", b"jpeg");
+        s.lb.upload_file(&changed).unwrap();
+        s.lb.upload_file(&uploaded).unwrap();
+        std::fs::write(&changed, b"jpeg, longer").unwrap();
+
+        for path in &[&never, &changed, &uploaded] {
+            r.uploaded(&s.lb, path, SystemTime::now());
+        }
+        assert!(Path::new(&never).exists());
+        assert!(Path::new(&changed).exists());
+        assert!(!Path::new(&uploaded).exists());
+        assert!(r.took(Path::new(&uploaded)));
+        assert!(!r.took(Path::new(&uploaded)));
+        assert!(!r.took(Path::new(&never)));
+    }
+
+    #[test]
+    fn test_retention_archive() {
+        let s = LocalFixture::new();
+        let archive_dir = tempdir().unwrap();
+        let policy = RetentionPolicy::new(
+            AfterUpload::Archive,
+            Some(archive_dir.path().into()),
+            None,
+            false,
+            false,
+        );
+        assert!(policy.check(&s.roots).is_ok());
+        let r = retention(&s, policy);
+        std::fs::create_dir(s.local.path().join("a")).unwrap();
+        let path = s.write("a/im1.jpg", b"jpeg");
+        s.lb.upload_file(&path).unwrap();
+
+        r.uploaded(&s.lb, &path, SystemTime::now());
+        assert!(!Path::new(&path).exists());
+        assert_eq!(
+            b"jpeg".to_vec(),
+            std::fs::read(archive_dir.path().join("RpiCamera/a/im1.jpg")).unwrap()
+        );
+
+        let inside = RetentionPolicy::new(
+            AfterUpload::Archive,
+            Some(s.local.path().join("archive")),
+            None,
+            false,
+            false,
+        );
+        assert!(inside.check(&s.roots).is_err());
+        let nowhere = RetentionPolicy::new(AfterUpload::Archive, None, None, false, false);
+        assert!(nowhere.check(&s.roots).is_err());
+    }
+
+    #[test]
+    fn test_retention_keep_days() {
+        let s = LocalFixture::new();
+        let r = retention(
+            &s,
+            RetentionPolicy::new(AfterUpload::Delete, None, Some(DAY * 7), false, false),
+        );
+        let path = s.write("im1.jpg", b"jpeg");
+        s.lb.upload_file(&path).unwrap();
+        let now = SystemTime::now();
+
+        r.uploaded(&s.lb, &path, now);
+        assert_eq!(0, r.sweep(&s.lb, now + DAY).unwrap());
+        assert!(Path::new(&path).exists());
+        assert_eq!(1, r.sweep(&s.lb, now + DAY * 8).unwrap());
+        assert!(!Path::new(&path).exists());
+
+        let keep = retention(
+            &s,
+            RetentionPolicy::new(AfterUpload::Keep, None, None, false, false),
+        );
+        let path = s.write("im2.jpg", b"jpeg");
+        s.lb.upload_file(&path).unwrap();
+        keep.uploaded(&s.lb, &path, now);
+        assert_eq!(0, keep.sweep(&s.lb, now + DAY * 8).unwrap());
+        assert!(Path::new(&path).exists());
+    }
+
+    #[test]
+    fn test_retention_dry_run() {
+        let s = LocalFixture::new();
+        let r = retention(
+            &s,
+            RetentionPolicy::new(AfterUpload::Delete, None, None, false, true),
+        );
+        let path = s.write("im1.jpg", b"jpeg");
+        s.lb.upload_file(&path).unwrap();
+
+        assert!(r.release(&s.lb, &path).unwrap());
+        assert_eq!(1, r.sweep(&s.lb, SystemTime::now()).unwrap());
+        assert!(Path::new(&path).exists());
+        assert!(!r.took(Path::new(&path)));
+    }
+}
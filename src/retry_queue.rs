@@ -0,0 +1,237 @@
+//!Uploads that have not succeeded yet, kept in the state db so they survive a restart. A file is
+//!queued as it is handed to the backend and dropped once uploaded. Failures are retried with
+//!exponential backoff and jitter until max_attempts, then dead lettered until requeued by hand.
+//!Files still settling in PendingFiles are not queued, the startup scan finds those again.
+//!While offline the queue is the backlog, bounded by max_queued and drained oldest first. What
+//!does not fit is left for a scan, which the daemon runs once it can upload again
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::state_db::StateDb;
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+///How failed uploads are retried
+#[derive(new, Debug, Clone, Copy, PartialEq)]
+pub struct RetryPolicy {
+    ///Tries before an upload is dead lettered, the first included
+    max_attempts: u32,
+    ///Wait after the first failure, doubled after each one after
+    base: Duration,
+    ///Longest wait between tries
+    max: Duration,
+}
+
+impl RetryPolicy {
+    ///The wait before the next try after this many failures, None once out of attempts.
+    ///Anywhere from half to all of the backoff, so uploads failing together spread out
+    pub fn delay(&self, failures: u32) -> Option<Duration> {
+        if failures >= self.max_attempts {
+            return None;
+        }
+        let backoff = self
+            .base
+            .checked_mul(1 << failures.saturating_sub(1).min(31))
+            .unwrap_or(self.max)
+            .min(self.max);
+        let half = backoff / 2;
+        let jitter = RandomState::new().build_hasher().finish() % (half.as_millis() as u64 + 1);
+        Some(half + Duration::from_millis(jitter))
+    }
+}
+
+pub struct RetryQueue {
+    state: Arc<StateDb>,
+    policy: RetryPolicy,
+    ///Most uploads the queue holds, so a long outage cannot fill the SD card
+    max_queued: usize,
+    ///A file was left out of the queue, only a scan finds it again
+    left_for_scan: AtomicBool,
+}
+
+impl RetryQueue {
+    pub fn new(state: Arc<StateDb>, policy: RetryPolicy, max_queued: usize) -> Self {
+        RetryQueue {
+            state,
+            policy,
+            max_queued,
+            left_for_scan: AtomicBool::new(false),
+        }
+    }
+
+    ///Offline, so it is uploaded when we are back.
+    ///False if the queue is full, the next scan finds the file again
+    pub fn push(&self, local_path: &str) -> PiSyncResult<bool> {
+        let queued = self.add(local_path, false)?;
+        if !queued {
+            self.left_for_scan.store(true, Ordering::Relaxed);
+        }
+        Ok(queued)
+    }
+
+    ///Whether a file has been left for a scan since this was last asked
+    pub fn take_left_for_scan(&self) -> bool {
+        self.left_for_scan.swap(false, Ordering::Relaxed)
+    }
+
+    ///Handed to a worker, so it is tried again after a failure or restart but is not due
+    ///while the worker has it. False if the queue is full
+    pub fn start(&self, local_path: &str) -> PiSyncResult<bool> {
+        self.add(local_path, true)
+    }
+
+    fn add(&self, local_path: &str, uploading: bool) -> PiSyncResult<bool> {
+        if self.state.queued(local_path)?.is_none() && self.state.queue_len()? >= self.max_queued {
+            warn!(
+                log,
+                "Upload queue full at {}, {} waits for the next scan", self.max_queued, local_path
+            );
+            return Ok(false);
+        }
+        self.state.enqueue(local_path, uploading).map(|_| true)
+    }
+
+    ///A worker gave up on it without trying, e.g. we went offline, it is due again as before
+    pub fn held(&self, local_path: &str) -> PiSyncResult<()> {
+        self.state.hold_upload(Some(local_path)).map(|_| ())
+    }
+
+    ///On startup, anything a worker had when we stopped is due again
+    pub fn interrupted(&self) -> PiSyncResult<usize> {
+        self.state.hold_upload(None)
+    }
+
+    ///Uploaded, or there is nothing left to upload
+    pub fn done(&self, local_path: &str) -> PiSyncResult<()> {
+        self.state.dequeue(local_path)
+    }
+
+    ///Schedule the next try, or dead letter it if that was the last
+    pub fn failed(&self, local_path: &str, e: &SyncerErrors, now: SystemTime) -> PiSyncResult<()> {
+        let mut upload = match self.state.queued(local_path)? {
+            Some(upload) => upload,
+            None => {
+                debug!(
+                    log,
+                    "{} was not queued, it waits for the next scan", local_path
+                );
+                self.left_for_scan.store(true, Ordering::Relaxed);
+                return Ok(());
+            }
+        };
+        upload.attempts += 1;
+        upload.last_error = Some(e.to_string());
+        match self.policy.delay(upload.attempts) {
+            Some(delay) => {
+                upload.next_try = epoch_secs(now + delay);
+                info!(
+                    log,
+                    "Upload of {} failed {} times, next try in {:?}",
+                    local_path,
+                    upload.attempts,
+                    delay
+                );
+            }
+            None => {
+                upload.dead = true;
+                error!(
+                    log,
+                    "Upload of {} failed {} times, giving up until requeued",
+                    local_path,
+                    upload.attempts
+                );
+            }
+        }
+        self.state.save_queued(&upload)
+    }
+
+    ///Uploads due a try now
+    pub fn due(&self, now: SystemTime) -> PiSyncResult<Vec<PathBuf>> {
+        Ok(self
+            .state
+            .due_uploads(epoch_secs(now))?
+            .into_iter()
+            .map(|u| u.local_path.into())
+            .collect())
+    }
+
+    ///When due should next be called, None if nothing is waiting
+    pub fn next_due(&self) -> PiSyncResult<Option<SystemTime>> {
+        Ok(self
+            .state
+            .next_upload_due()?
+            .map(|secs| UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)))
+    }
+}
+
+fn epoch_secs(at: SystemTime) -> i64 {
+    at.duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::retry_queue::*;
+
+    fn policy() -> RetryPolicy {
+        RetryPolicy::new(4, Duration::from_secs(30), Duration::from_secs(90))
+    }
+
+    #[test]
+    fn test_retry_policy_delay() {
+        for (failures, backoff) in &[(1, 30), (2, 60), (3, 90)] {
+            let delay = policy().delay(*failures).unwrap();
+            let backoff = Duration::from_secs(*backoff);
+            assert!(delay >= backoff / 2 && delay <= backoff, "{:?}", delay);
+        }
+        assert_eq!(None, policy().delay(4));
+    }
+
+    #[test]
+    fn test_retry_queue_dead_letters() {
+        let state = Arc::new(StateDb::open_in_memory().unwrap());
+        let queue = RetryQueue::new(Arc::clone(&state), policy(), 1);
+        let now = UNIX_EPOCH + Duration::from_secs(1_600_000_000);
+
+        assert!(queue.start("/w/im1.jpg").unwrap());
+        assert!(queue.push("/w/im1.jpg").unwrap());
+        assert!(!queue.take_left_for_scan());
+        assert!(!queue.push("/w/im2.jpg").unwrap());
+        assert!(queue.take_left_for_scan());
+        assert!(!queue.take_left_for_scan());
+        queue
+            .failed("/w/im2.jpg", &SyncerErrors::ProviderError, now)
+            .unwrap();
+        assert!(queue.take_left_for_scan());
+        assert!(queue.due(now).unwrap().is_empty());
+        assert_eq!(1, queue.interrupted().unwrap());
+        assert_eq!(vec![PathBuf::from("/w/im1.jpg")], queue.due(now).unwrap());
+        queue
+            .failed("/w/im1.jpg", &SyncerErrors::ProviderError, now)
+            .unwrap();
+        assert!(queue.due(now).unwrap().is_empty());
+        let next = queue.next_due().unwrap().unwrap();
+        assert!(next >= now + Duration::from_secs(14) && next <= now + Duration::from_secs(30));
+
+        for _ in 0..3 {
+            queue
+                .failed("/w/im1.jpg", &SyncerErrors::ProviderError, now)
+                .unwrap();
+        }
+        assert_eq!(None, queue.next_due().unwrap());
+        let dead = state.dead_letters().unwrap();
+        assert_eq!(4, dead[0].attempts);
+        assert_eq!(
+            Some(SyncerErrors::ProviderError.to_string()),
+            dead[0].last_error
+        );
+
+        state.requeue(None).unwrap();
+        queue.done("/w/im1.jpg").unwrap();
+        assert_eq!(None, queue.next_due().unwrap());
+    }
+}
@@ -0,0 +1,166 @@
+//!Find what the watcher missed, files written while the daemon was stopped or between rescans
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use crate::upload_handler::Roots;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+
+///Every file under the roots that passes its filters but the backend does not have.
+///Anything we cannot check is left for the next scan
+pub fn missing_files(client: &dyn CloudClient, roots: &Roots) -> Vec<PathBuf> {
+    let mut missing = vec![];
+    for path in local_files(roots) {
+        let p = path.to_str().unwrap_or_default();
+        match client.id(p) {
+            Ok(Some(_id)) => trace!(log, "{} already synced", p),
+            Ok(None) => missing.push(path),
+            Err(e) => warn!(
+                log,
+                "Cannot check {}, leaving it for the next scan {}", p, e
+            ),
+        }
+    }
+    info!(log, "Scan found {} files to sync", missing.len());
+    missing
+}
+
+///Every file under the roots that passes its filters, each found once under the root it
+///belongs to
+pub fn local_files(roots: &Roots) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for root in roots.iter() {
+        debug!(log, "Scanning {:?}", root.local_root());
+        let walk = WalkDir::new(root.local_root()).sort_by(|a, b| a.file_name().cmp(b.file_name()));
+        for entry in walk {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    warn!(log, "Cannot scan {}", e);
+                    continue;
+                }
+            };
+            if !entry.file_type().is_file() {
+                continue;
+            }
+            let path = match entry.path().to_str() {
+                Some(path) => path,
+                None => {
+                    warn!(log, "Not scanning {:?}, not valid UTF-8", entry.path());
+                    continue;
+                }
+            };
+            //a nested root owns its own files
+            if roots.mapping_for(entry.path()) != Some(root) || !roots.passes_filter(path) {
+                continue;
+            }
+            files.push(entry.into_path());
+        }
+    }
+    files
+}
+
+///Files in a dir that was moved in, or a file that was, which the watcher says nothing more about
+pub fn files_in(path: &Path) -> Vec<PathBuf> {
+    WalkDir::new(path)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter(|e| e.file_type().is_file())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+///What is now under a renamed dir, as (old path, new path), parents before their children
+pub fn moved_children(from: &str, to: &str) -> Vec<(String, String)> {
+    WalkDir::new(to)
+        .min_depth(1)
+        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
+        .into_iter()
+        .filter_map(|e| e.ok())
+        .filter_map(|e| {
+            let relative = e.path().strip_prefix(to).ok()?;
+            Some((
+                Path::new(from).join(relative).to_str()?.to_owned(),
+                e.path().to_str()?.to_owned(),
+            ))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_backend::LocalDirBackend;
+    use crate::local_fixture::LocalFixture;
+    use crate::scanner::*;
+    use crate::state_db::StateDb;
+    use crate::upload_handler::RootMapping;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_scanner_missing_files() {
+        let s = LocalFixture::with_filters(vec!["^im.*jpg$".into()]);
+        let (local, lb) = (&s.local, &s.lb);
+        std::fs::create_dir_all(local.path().join("a/b")).unwrap();
+        for f in &["im1.jpg", "a/im2.jpg", "a/b/im3.jpg", "a/vi1.mp4"] {
+            std::fs::write(local.path().join(f), b"jpeg").unwrap();
+        }
+        lb.upload_file(local.path().join("a/im2.jpg").to_str().unwrap())
+            .unwrap();
+
+        assert_eq!(
+            vec![
+                local.path().join("a/b/im3.jpg"),
+                local.path().join("im1.jpg")
+            ],
+            missing_files(lb, &s.roots)
+        );
+    }
+
+    #[test]
+    fn test_scanner_moved_children() {
+        let dir = tempdir().unwrap();
+        let to = dir.path().join("b");
+        std::fs::create_dir_all(to.join("c")).unwrap();
+        std::fs::write(to.join("c/im1.jpg"), b"jpeg").unwrap();
+        let from = dir.path().join("a");
+        let (from, to) = (from.to_str().unwrap(), to.to_str().unwrap());
+
+        assert_eq!(
+            vec![
+                (format!("{}/c", from), format!("{}/c", to)),
+                (format!("{}/c/im1.jpg", from), format!("{}/c/im1.jpg", to)),
+            ],
+            moved_children(from, to)
+        );
+        assert_eq!(
+            vec![PathBuf::from(format!("{}/c/im1.jpg", to))],
+            files_in(Path::new(to))
+        );
+    }
+
+    #[test]
+    fn test_scanner_nested_roots() {
+        let local = tempdir().unwrap();
+        let target = tempdir().unwrap();
+        let logs = local.path().join("logs");
+        std::fs::create_dir(&logs).unwrap();
+        std::fs::write(local.path().join("im1.jpg"), b"jpeg").unwrap();
+        std::fs::write(logs.join("motion.log"), b"log").unwrap();
+        let roots = Roots::new(vec![
+            RootMapping::new(local.path().into(), "RpiCamera".into(), vec![]),
+            RootMapping::new(logs.clone(), "logs/motion".into(), vec![]),
+        ]);
+        let lb = LocalDirBackend::new(
+            target.path().to_str().unwrap(),
+            roots.clone(),
+            Arc::new(StateDb::open_in_memory().unwrap()),
+        );
+
+        //each file is found once, under the root it belongs to
+        assert_eq!(
+            vec![local.path().join("im1.jpg"), logs.join("motion.log")],
+            missing_files(&lb, &roots)
+        );
+    }
+}
@@ -0,0 +1,418 @@
+use crate::cloud_client::{CloudClient, TRASH_DIR};
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::state_db::StateDb;
+use crate::throttle::{Bandwidth, Throttled};
+use crate::upload_handler::{FileOperations, Roots, SyncableFile};
+use ssh2::{CheckResult, KnownHostFileKind, RenameFlags, Session, Sftp};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+const PARTIAL_SUFFIX: &str = ".pi_sync_partial";
+const SSH_TIMEOUT_MS: u32 = 30_000;
+
+///Where to push to, the RpiCamera tree is created under remote_root on the host
+#[derive(new, Clone, Debug)]
+pub struct SftpOptions {
+    host: String,
+    port: u16,
+    username: String,
+    key_file: String,
+    remote_root: String,
+    ///Host keys are checked against this, an unknown or changed host key is refused
+    known_hosts: String,
+    ///Shared by every upload, see throttle
+    bandwidth: Arc<Bandwidth>,
+}
+
+///Push files to a plain Linux box over SFTP. Files are written next to their target and renamed
+///into place once complete, so nothing on the server ever sees a partial file
+pub struct SftpClient {
+    opts: SftpOptions,
+    remote_root: PathBuf,
+    roots: Roots,
+    sftp: Mutex<Option<Sftp>>,
+    state: Arc<StateDb>,
+}
+
+impl SftpClient {
+    ///The key passphrase, if the key has one, is read from SFTP_KEY_PASSPHRASE
+    pub fn new(opts: SftpOptions, roots: Roots, state: Arc<StateDb>) -> Self {
+        SftpClient {
+            remote_root: PathBuf::from(&opts.remote_root),
+            opts,
+            roots,
+            sftp: Mutex::new(None),
+            state,
+        }
+    }
+
+    fn connect(&self) -> PiSyncResult<Sftp> {
+        debug!(
+            log,
+            "SFTP connecting to {}@{}:{}", self.opts.username, self.opts.host, self.opts.port
+        );
+        let tcp = TcpStream::connect((self.opts.host.as_str(), self.opts.port)).map_err(|e| {
+            error!(
+                log,
+                "Cannot connect to {}:{} {}", self.opts.host, self.opts.port, e
+            );
+            SyncerErrors::ProviderError
+        })?;
+
+        let mut session = Session::new().map_err(ssh_err)?;
+        session.set_timeout(SSH_TIMEOUT_MS);
+        session.set_tcp_stream(tcp);
+        session.handshake().map_err(ssh_err)?;
+        self.check_host_key(&session)?;
+
+        let passphrase = std::env::var("SFTP_KEY_PASSPHRASE").ok();
+        session
+            .userauth_pubkey_file(
+                &self.opts.username,
+                None,
+                Path::new(&self.opts.key_file),
+                passphrase.as_deref(),
+            )
+            .map_err(|e| {
+                error!(
+                    log,
+                    "SFTP key auth with {} refused {}", self.opts.key_file, e
+                );
+                SyncerErrors::NoAppSecret
+            })?;
+
+        session.sftp().map_err(ssh_err)
+    }
+
+    fn check_host_key(&self, session: &Session) -> PiSyncResult<()> {
+        let mut known_hosts = session.known_hosts().map_err(ssh_err)?;
+        known_hosts
+            .read_file(
+                Path::new(&self.opts.known_hosts),
+                KnownHostFileKind::OpenSSH,
+            )
+            .map_err(|e| {
+                error!(
+                    log,
+                    "Cannot read known hosts {} {}", self.opts.known_hosts, e
+                );
+                SyncerErrors::NoAppSecret
+            })?;
+        let (key, _key_type) = session.host_key().ok_or(SyncerErrors::ProviderError)?;
+        match known_hosts.check_port(&self.opts.host, self.opts.port, key) {
+            CheckResult::Match => Ok(()),
+            r => {
+                error!(
+                    log,
+                    "Host key for {} is {:?} in {}, refusing to connect",
+                    self.opts.host,
+                    r,
+                    self.opts.known_hosts
+                );
+                Err(SyncerErrors::NoAppSecret)
+            }
+        }
+    }
+
+    ///Run f on the open connection, connecting first if need be. Any failure drops the
+    ///connection so the next call starts afresh
+    fn with_sftp<T, F>(&self, f: F) -> PiSyncResult<T>
+    where
+        F: FnOnce(&Sftp) -> PiSyncResult<T>,
+    {
+        let mut conn = self.sftp.lock().unwrap();
+        if conn.is_none() {
+            *conn = Some(self.connect()?);
+        }
+        let result = f(conn.as_ref().unwrap());
+        if result.is_err() {
+            *conn = None;
+        }
+        result
+    }
+
+    fn remote_path(&self, syncable: &SyncableFile) -> PiSyncResult<PathBuf> {
+        Ok(self.remote_root.join(syncable.cloud_path()?))
+    }
+
+    fn path_id(p: &Path) -> PiSyncResult<Option<String>> {
+        p.to_str()
+            .map(|s| Some(s.to_owned()))
+            .ok_or(SyncerErrors::InvalidPathError)
+    }
+
+    ///Create each missing dir from the remote root down to the file's parent
+    fn create_path(&self, syncable: &SyncableFile) -> PiSyncResult<bool> {
+        let root = syncable.root()?;
+        let mut ancestors = vec![];
+        let mut parent = syncable.parent_path()?;
+        while let Some(p) = parent.to_str() {
+            if root.file(p).cloud_path().is_err() {
+                break;
+            }
+            ancestors.push(p.to_owned());
+            if !parent.pop() {
+                break;
+            }
+        }
+
+        for dir in ancestors.iter().rev() {
+            if self.state.remote_id(&self.roots.file(dir))?.is_some() {
+                debug!(log, "State db hit for {:?}, not creating dir", dir);
+                continue;
+            }
+            self.create_dir(dir, None)?;
+        }
+        Ok(true)
+    }
+}
+
+fn ssh_err(e: ssh2::Error) -> SyncerErrors {
+    error!(log, "SFTP call failed {}", e);
+    SyncerErrors::ProviderError
+}
+
+///Make the remote dir, and any parents of it, that are missing
+fn mkdir_all(sftp: &Sftp, dir: &Path) -> PiSyncResult<()> {
+    if sftp.stat(dir).map(|s| s.is_dir()).unwrap_or(false) {
+        return Ok(());
+    }
+    if let Some(parent) = dir.parent() {
+        mkdir_all(sftp, parent)?;
+    }
+    trace!(log, "SFTP mkdir {:?}", dir);
+    sftp.mkdir(dir, 0o755).or_else(|e| {
+        //lost a race with someone else creating it
+        if sftp.stat(dir).map(|s| s.is_dir()).unwrap_or(false) {
+            Ok(())
+        } else {
+            Err(ssh_err(e))
+        }
+    })
+}
+
+///Remove a remote file, or a dir and everything in it
+fn remove_all(sftp: &Sftp, path: &Path) -> PiSyncResult<()> {
+    let stat = match sftp.lstat(path) {
+        Ok(stat) => stat,
+        Err(_e) => return Ok(()),
+    };
+    if stat.is_dir() {
+        for (child, _stat) in sftp.readdir(path).map_err(ssh_err)? {
+            remove_all(sftp, &child)?;
+        }
+        trace!(log, "SFTP rmdir {:?}", path);
+        sftp.rmdir(path).map_err(ssh_err)
+    } else {
+        trace!(log, "SFTP unlink {:?}", path);
+        sftp.unlink(path).map_err(ssh_err)
+    }
+}
+
+impl CloudClient for SftpClient {
+    ///Write to target.pi_sync_partial, then rename over the target
+    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let target = self.remote_path(&s)?;
+        self.create_path(&s)?;
+
+        let mut file = std::fs::File::open(local_fs_path).map_err(|_e| {
+            error!(log, "File deleted before we got to it");
+            SyncerErrors::ProviderError
+        })?;
+
+        let mut partial = target.clone().into_os_string();
+        partial.push(PARTIAL_SUFFIX);
+        let partial = PathBuf::from(partial);
+
+        self.with_sftp(|sftp| {
+            trace!(log, "SFTP upload {:?} to {:?}", local_fs_path, partial);
+            let mut remote = sftp.create(&partial).map_err(ssh_err)?;
+            let mut file = Throttled::new(&mut file, &self.opts.bandwidth);
+            std::io::copy(&mut file, &mut remote).map_err(|e| {
+                error!(log, "SFTP write of {:?} failed {}", partial, e);
+                let _ = sftp.unlink(&partial);
+                SyncerErrors::ProviderError
+            })?;
+            drop(remote);
+
+            let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+            sftp.rename(&partial, &target, Some(flags)).or_else(|_e| {
+                //SFTP v3 servers such as OpenSSH ignore the flags and will not rename over an existing file
+                debug!(log, "Rename over {:?} refused, replacing it", target);
+                let _ = sftp.unlink(&target);
+                sftp.rename(&partial, &target, None).map_err(ssh_err)
+            })
+        })?;
+
+        debug!(log, "Uploaded {} to {:?}", local_fs_path, target);
+        Ok(self.state.recorded(&s, Self::path_id(&target)?))
+    }
+
+    fn create_dir(
+        &self,
+        local_fs_path: &str,
+        _parent_id: Option<&str>,
+    ) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let dir = self.remote_path(&s)?;
+        self.with_sftp(|sftp| mkdir_all(sftp, &dir))?;
+        Ok(self.state.recorded(&s, Self::path_id(&dir)?))
+    }
+
+    ///The remote path is the id, partial uploads are never at it
+    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_path);
+        if let Some(id) = self.state.remote_id(&s)? {
+            return Ok(Some(id));
+        }
+        let remote = self.remote_path(&s)?;
+        let exists = self.with_sftp(|sftp| Ok(sftp.stat(&remote).is_ok()))?;
+        if exists {
+            Ok(self.state.recorded(&s, Self::path_id(&remote)?))
+        } else {
+            Ok(None)
+        }
+    }
+
+    ///Trashed files keep their place in the tree under .pi_sync_trash, replacing any older copy
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()> {
+        let s = self.roots.file(local_path);
+        let target = match self.id(local_path)? {
+            Some(id) => PathBuf::from(id),
+            None => {
+                debug!(log, "{} was never synced, nothing to delete", local_path);
+                return Ok(());
+            }
+        };
+        let trashed = self.remote_root.join(TRASH_DIR).join(s.cloud_path()?);
+
+        self.with_sftp(|sftp| {
+            if sftp.lstat(&target).is_err() {
+                Ok(())
+            } else if trash {
+                trace!(log, "SFTP trash {:?} to {:?}", target, trashed);
+                remove_all(sftp, &trashed)?;
+                if let Some(parent) = trashed.parent() {
+                    mkdir_all(sftp, parent)?;
+                }
+                sftp.rename(&target, &trashed, None).map_err(ssh_err)
+            } else {
+                remove_all(sftp, &target)
+            }
+        })?;
+        self.state.forget(local_path)
+    }
+
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>> {
+        let old = match self.id(from)? {
+            Some(id) => PathBuf::from(id),
+            None => return Ok(None),
+        };
+        let t = self.roots.file(to);
+        let target = self.remote_path(&t)?;
+
+        self.with_sftp(|sftp| {
+            trace!(log, "SFTP rename {:?} to {:?}", old, target);
+            if let Some(parent) = target.parent() {
+                mkdir_all(sftp, parent)?;
+            }
+            let flags = RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE;
+            sftp.rename(&old, &target, Some(flags)).or_else(|_e| {
+                remove_all(sftp, &target)?;
+                sftp.rename(&old, &target, None).map_err(ssh_err)
+            })
+        })?;
+        self.state.forget(from)?;
+        Ok(self.state.recorded(&t, Self::path_id(&target)?))
+    }
+
+    fn check_ready(&self) -> PiSyncResult<()> {
+        let root = self.remote_root.clone();
+        self.with_sftp(|sftp| mkdir_all(sftp, &root))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_client::CloudClient;
+    use crate::sftp_cli::*;
+    use crate::upload_handler::RootMapping;
+    use tempfile::tempdir;
+
+    fn options(port: u16) -> SftpOptions {
+        SftpOptions::new(
+            "127.0.0.1".into(),
+            port,
+            std::env::var("USER").unwrap_or_else(|_| "pi".into()),
+            format!("{}/.ssh/id_rsa", std::env::var("HOME").unwrap_or_default()),
+            "/tmp/pi_sync/sftp".into(),
+            format!(
+                "{}/.ssh/known_hosts",
+                std::env::var("HOME").unwrap_or_default()
+            ),
+            Arc::default(),
+        )
+    }
+
+    fn state() -> Arc<StateDb> {
+        Arc::new(StateDb::open_in_memory().unwrap())
+    }
+
+    #[test]
+    fn test_sftp_remote_path() {
+        let roots = Roots::from(RootMapping::new(
+            "/var/www/RpiCamera".into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let sftp = SftpClient::new(options(22), roots.clone(), state());
+        assert_eq!(
+            PathBuf::from("/tmp/pi_sync/sftp/RpiCamera/a/im1.jpg"),
+            sftp.remote_path(&roots.file("/var/www/RpiCamera/a/im1.jpg"))
+                .unwrap()
+        );
+    }
+
+    #[test]
+    fn test_sftp_unreachable_host() {
+        //nothing listens on port 1, so this fails fast without needing a server
+        let roots = Roots::from(RootMapping::new(
+            "/var/www/RpiCamera".into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let sftp = SftpClient::new(options(1), roots, state());
+        assert!(sftp.check_ready().is_err());
+        assert!(sftp.sftp.lock().unwrap().is_none());
+    }
+
+    ///Needs sshd on localhost accepting ~/.ssh/id_rsa, with localhost in known_hosts
+    #[test]
+    #[ignore]
+    fn test_sftp_localhost_upload() {
+        let root = tempdir().unwrap();
+        let dir = format!("{}/sftp_test/a", root.path().to_str().unwrap());
+        std::fs::create_dir_all(&dir).unwrap();
+        let local = format!("{}/im1.jpg", dir);
+        std::fs::write(&local, b"jpeg").unwrap();
+
+        let roots = Roots::from(RootMapping::new(
+            root.path().into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let sftp = SftpClient::new(options(22), roots, state());
+        assert!(sftp.check_ready().is_ok());
+        let id = sftp.upload_file(&local).unwrap();
+        assert_eq!(
+            Some("/tmp/pi_sync/sftp/RpiCamera/sftp_test/a/im1.jpg".to_owned()),
+            id
+        );
+        assert_eq!(id, sftp.id(&local).unwrap());
+        //a second upload replaces the first
+        assert_eq!(id, sftp.upload_file(&local).unwrap());
+    }
+}
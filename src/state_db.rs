@@ -0,0 +1,731 @@
+//!What we have already put on the backend, kept on disk so a restart does not have to ask the
+//!backend about every folder again
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::upload_handler::{FileOperations, SyncableFile};
+use rusqlite::{params, Connection, OptionalExtension};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+const SCHEMA: &str = "CREATE TABLE IF NOT EXISTS synced (
+    pi_sync_id TEXT PRIMARY KEY,
+    local_path TEXT NOT NULL,
+    remote_id TEXT NOT NULL,
+    is_dir INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    mtime INTEGER NOT NULL,
+    checksum TEXT
+);
+CREATE TABLE IF NOT EXISTS upload_queue (
+    local_path TEXT PRIMARY KEY,
+    attempts INTEGER NOT NULL,
+    next_try INTEGER NOT NULL,
+    last_error TEXT,
+    dead INTEGER NOT NULL,
+    uploading INTEGER NOT NULL DEFAULT 0
+);";
+///Kept in PRAGMA user_version, bumped with each migration in migrate
+const SCHEMA_VERSION: i64 = 1;
+
+///A file or folder as it was when we last synced it
+#[derive(new, Debug, Clone, PartialEq)]
+pub struct SyncRecord {
+    pub pi_sync_id: String,
+    pub local_path: String,
+    ///The backend's id, a Drive file id, an S3 key or a remote path
+    pub remote_id: String,
+    pub is_dir: bool,
+    pub size: u64,
+    ///Seconds since the epoch
+    pub mtime: i64,
+    ///Hex md5 of the content, None for folders
+    pub checksum: Option<String>,
+}
+
+///An upload that has not succeeded yet
+#[derive(new, Debug, Clone, PartialEq)]
+pub struct QueuedUpload {
+    pub local_path: String,
+    ///Failed tries so far
+    pub attempts: u32,
+    ///Seconds since the epoch, 0 for straight away
+    pub next_try: i64,
+    pub last_error: Option<String>,
+    ///Out of attempts, it waits to be requeued by hand
+    pub dead: bool,
+}
+
+///pi_sync_id -> remote id mappings and the upload queue, safe to share between threads
+pub struct StateDb {
+    conn: Mutex<Connection>,
+}
+
+impl StateDb {
+    ///Creates the db, and the dir it goes in, if need be
+    pub fn open(path: &Path) -> PiSyncResult<StateDb> {
+        debug!(log, "Opening state db {:?}", path);
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            std::fs::create_dir_all(dir).map_err(|e| {
+                error!(log, "Cannot create state db dir {:?} {}", dir, e);
+                SyncerErrors::StateDbError
+            })?;
+        }
+        let conn = Connection::open(path).map_err(|e| {
+            error!(log, "Cannot open state db {:?} {}", path, e);
+            SyncerErrors::StateDbError
+        })?;
+        StateDb::with_schema(conn)
+    }
+
+    ///An in memory copy of the db at path, for a dry run to change as it likes. Empty if there is
+    ///no db there yet
+    pub fn snapshot(path: &Path) -> PiSyncResult<StateDb> {
+        debug!(log, "Copying state db {:?} into memory", path);
+        let state = StateDb::with_schema(Connection::open_in_memory().map_err(db_err)?)?;
+        if path.exists() {
+            let conn = state.conn.lock().unwrap();
+            conn.execute(
+                "ATTACH DATABASE ?1 AS disk",
+                params![path.to_string_lossy()],
+            )
+            .and_then(|_| {
+                conn.execute_batch(
+                    "INSERT INTO main.synced SELECT * FROM disk.synced;
+                    INSERT INTO main.upload_queue (local_path, attempts, next_try, last_error, dead)
+                    SELECT local_path, attempts, next_try, last_error, dead FROM disk.upload_queue;
+                    DETACH DATABASE disk;",
+                )
+            })
+            .map_err(|e| {
+                error!(log, "Cannot copy state db {:?} {}", path, e);
+                SyncerErrors::StateDbError
+            })?;
+        }
+        Ok(state)
+    }
+
+    #[cfg(test)]
+    pub fn open_in_memory() -> PiSyncResult<StateDb> {
+        StateDb::with_schema(Connection::open_in_memory().map_err(db_err)?)
+    }
+
+    fn with_schema(conn: Connection) -> PiSyncResult<StateDb> {
+        conn.execute_batch(SCHEMA).map_err(db_err)?;
+        migrate(&conn).map_err(db_err)?;
+        Ok(StateDb {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    pub fn get(&self, pi_sync_id: &str) -> PiSyncResult<Option<SyncRecord>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT pi_sync_id, local_path, remote_id, is_dir, size, mtime, checksum
+                 FROM synced WHERE pi_sync_id = ?1",
+                params![pi_sync_id],
+                |row| {
+                    Ok(SyncRecord::new(
+                        row.get(0)?,
+                        row.get(1)?,
+                        row.get(2)?,
+                        row.get(3)?,
+                        row.get::<_, i64>(4)? as u64,
+                        row.get(5)?,
+                        row.get(6)?,
+                    ))
+                },
+            )
+            .optional()
+            .map_err(db_err)
+    }
+
+    pub fn upsert(&self, record: &SyncRecord) -> PiSyncResult<()> {
+        trace!(log, "State db upsert {:?}", record);
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT OR REPLACE INTO synced
+                 (pi_sync_id, local_path, remote_id, is_dir, size, mtime, checksum)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+                params![
+                    record.pi_sync_id,
+                    record.local_path,
+                    record.remote_id,
+                    record.is_dir,
+                    record.size as i64,
+                    record.mtime,
+                    record.checksum
+                ],
+            )
+            .map(|_| ())
+            .map_err(db_err)
+    }
+
+    ///Drop the records for a local path and, if it was a folder, everything that was in it
+    pub fn forget(&self, local_path: &str) -> PiSyncResult<()> {
+        let local_path = local_path.trim_end_matches('/');
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM synced WHERE local_path = ?1
+                 OR substr(local_path, 1, length(?1) + 1) = ?1 || '/'",
+                params![local_path],
+            )
+            .map(|_| ())
+            .map_err(db_err)
+    }
+
+    ///Every file, not folder, recorded as synced
+    pub fn synced_files(&self) -> PiSyncResult<Vec<SyncRecord>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(
+                "SELECT pi_sync_id, local_path, remote_id, is_dir, size, mtime, checksum
+                 FROM synced WHERE is_dir = 0 ORDER BY local_path",
+            )
+            .map_err(db_err)?;
+        let rows = stmt
+            .query_map(params![], |row| {
+                Ok(SyncRecord::new(
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get::<_, i64>(4)? as u64,
+                    row.get(5)?,
+                    row.get(6)?,
+                ))
+            })
+            .map_err(db_err)?;
+        rows.collect::<Result<_, _>>().map_err(db_err)
+    }
+
+    ///The remote id we recorded for this file, if any
+    pub fn remote_id(&self, syncable: &SyncableFile) -> PiSyncResult<Option<String>> {
+        let record = self.get(&syncable.get_unique_id()?)?;
+        if record.is_some() {
+            trace!(log, "State db hit for {:?}", syncable.local_path());
+        }
+        Ok(record.map(|r| r.remote_id))
+    }
+
+    ///Record remote_id, if there is one, and hand it back. The backend call has already
+    ///succeeded, so failing to record it is only worth a warning
+    pub fn recorded(&self, syncable: &SyncableFile, remote_id: Option<String>) -> Option<String> {
+        if let Some(id) = &remote_id {
+            if let Err(e) = self.record(syncable, id) {
+                warn!(
+                    log,
+                    "{:?} not recorded in state db: {}",
+                    syncable.local_path(),
+                    e
+                );
+            }
+        }
+        remote_id
+    }
+
+    ///Note that the file, as it is on disk now, is on the backend as remote_id
+    pub fn record(&self, syncable: &SyncableFile, remote_id: &str) -> PiSyncResult<()> {
+        let local_path = syncable
+            .local_path()
+            .to_str()
+            .ok_or(SyncerErrors::InvalidPathError)?;
+        let meta = std::fs::metadata(local_path).map_err(|e| {
+            error!(log, "Cannot stat {} {}", local_path, e);
+            SyncerErrors::InvalidPathError
+        })?;
+        let mtime = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs() as i64)
+            .unwrap_or(0);
+        let checksum = if meta.is_dir() {
+            None
+        } else {
+            Some(md5_hex(local_path)?)
+        };
+        self.upsert(&SyncRecord::new(
+            syncable.get_unique_id()?,
+            local_path.to_owned(),
+            remote_id.to_owned(),
+            meta.is_dir(),
+            if meta.is_dir() { 0 } else { meta.len() },
+            mtime,
+            checksum,
+        ))
+    }
+
+    ///Upload local_path, recording the checksum it had before the upload started. record
+    ///hashes it again afterwards, and a file rewritten during the upload then has a checksum
+    ///the backend does not hold. Retention would trust that and remove the only good copy
+    pub fn hashed_upload(
+        &self,
+        local_path: &str,
+        upload: impl FnOnce() -> PiSyncResult<Option<String>>,
+    ) -> PiSyncResult<Option<String>> {
+        let before = md5_hex(local_path)?;
+        let remote_id = upload()?;
+        if let Err(e) = self
+            .conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE synced SET checksum = ?2 WHERE local_path = ?1 AND NOT is_dir",
+                params![local_path, before],
+            )
+            .map_err(db_err)
+        {
+            warn!(log, "Checksum of {} not recorded: {}", local_path, e);
+        }
+        Ok(remote_id)
+    }
+}
+
+///The upload queue, see retry_queue
+impl StateDb {
+    ///Queue an upload to be tried straight away, one already queued keeps its attempts. An
+    ///upload a worker is busy with is not due until it fails, or is held back
+    pub fn enqueue(&self, local_path: &str, uploading: bool) -> PiSyncResult<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO upload_queue (local_path, attempts, next_try, dead, uploading)
+                 VALUES (?1, 0, 0, 0, ?2)
+                 ON CONFLICT (local_path) DO UPDATE SET uploading = uploading OR ?2",
+                params![local_path, uploading],
+            )
+            .map(|_| ())
+            .map_err(db_err)
+    }
+
+    pub fn queued(&self, local_path: &str) -> PiSyncResult<Option<QueuedUpload>> {
+        Ok(self
+            .select_queue("WHERE local_path = ?1", params![local_path])?
+            .pop())
+    }
+
+    pub fn save_queued(&self, upload: &QueuedUpload) -> PiSyncResult<()> {
+        trace!(log, "Upload queue save {:?}", upload);
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "INSERT INTO upload_queue (local_path, attempts, next_try, last_error, dead)
+                 VALUES (?1, ?2, ?3, ?4, ?5)
+                 ON CONFLICT (local_path) DO UPDATE SET attempts = ?2, next_try = ?3,
+                 last_error = ?4, dead = ?5, uploading = 0",
+                params![
+                    upload.local_path,
+                    upload.attempts,
+                    upload.next_try,
+                    upload.last_error,
+                    upload.dead
+                ],
+            )
+            .map(|_| ())
+            .map_err(db_err)
+    }
+
+    ///No longer being uploaded, e.g. we went offline, it is due again at its next_try.
+    ///With no local_path every upload, for those a crash or restart cut short
+    pub fn hold_upload(&self, local_path: Option<&str>) -> PiSyncResult<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE upload_queue SET uploading = 0 WHERE ?1 IS NULL OR local_path = ?1",
+                params![local_path],
+            )
+            .map_err(db_err)
+    }
+
+    pub fn dequeue(&self, local_path: &str) -> PiSyncResult<()> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "DELETE FROM upload_queue WHERE local_path = ?1",
+                params![local_path],
+            )
+            .map(|_| ())
+            .map_err(db_err)
+    }
+
+    ///Live uploads whose next try is at or before now, oldest first. The rowid is the order
+    ///they were queued in, save_queued updates in place so it is kept
+    pub fn due_uploads(&self, now: i64) -> PiSyncResult<Vec<QueuedUpload>> {
+        self.select_queue(
+            "WHERE dead = 0 AND uploading = 0 AND next_try <= ?1 ORDER BY rowid",
+            params![now],
+        )
+    }
+
+    ///Uploads queued, dead ones included
+    pub fn queue_len(&self) -> PiSyncResult<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row("SELECT count(*) FROM upload_queue", params![], |row| {
+                row.get::<_, i64>(0)
+            })
+            .map(|n| n as usize)
+            .map_err(db_err)
+    }
+
+    ///When the soonest live upload not already being uploaded is due, None if there are none
+    pub fn next_upload_due(&self) -> PiSyncResult<Option<i64>> {
+        self.conn
+            .lock()
+            .unwrap()
+            .query_row(
+                "SELECT min(next_try) FROM upload_queue WHERE dead = 0 AND uploading = 0",
+                params![],
+                |row| row.get(0),
+            )
+            .map_err(db_err)
+    }
+
+    pub fn dead_letters(&self) -> PiSyncResult<Vec<QueuedUpload>> {
+        self.select_queue("WHERE dead = 1 ORDER BY local_path", params![])
+    }
+
+    ///Give dead uploads, all of them or just local_path, a fresh set of attempts.
+    ///Returns how many were requeued
+    pub fn requeue(&self, local_path: Option<&str>) -> PiSyncResult<usize> {
+        self.conn
+            .lock()
+            .unwrap()
+            .execute(
+                "UPDATE upload_queue SET dead = 0, attempts = 0, next_try = 0
+                 WHERE dead = 1 AND (?1 IS NULL OR local_path = ?1)",
+                params![local_path],
+            )
+            .map_err(db_err)
+    }
+
+    fn select_queue(
+        &self,
+        clause: &str,
+        params: &[&dyn rusqlite::ToSql],
+    ) -> PiSyncResult<Vec<QueuedUpload>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn
+            .prepare(&format!(
+                "SELECT local_path, attempts, next_try, last_error, dead FROM upload_queue {}",
+                clause
+            ))
+            .map_err(db_err)?;
+        let rows = stmt
+            .query_map(params, |row| {
+                Ok(QueuedUpload::new(
+                    row.get(0)?,
+                    row.get(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                    row.get(4)?,
+                ))
+            })
+            .map_err(db_err)?;
+        rows.collect::<Result<_, _>>().map_err(db_err)
+    }
+}
+
+///The md5 of a file, in the lower case hex Drive reports as md5Checksum
+pub fn md5_hex(local_path: &str) -> PiSyncResult<String> {
+    let mut file = std::fs::File::open(local_path).map_err(|e| {
+        error!(log, "Cannot read {} {}", local_path, e);
+        SyncerErrors::InvalidPathError
+    })?;
+    let mut ctx = md5::Context::new();
+    std::io::copy(&mut file, &mut ctx).map_err(|e| {
+        error!(log, "Cannot read {} {}", local_path, e);
+        SyncerErrors::InvalidPathError
+    })?;
+    Ok(format!("{:x}", ctx.compute()))
+}
+
+///Bring a db written by an older version up to SCHEMA_VERSION. CREATE TABLE IF NOT EXISTS
+///leaves the tables it already has as they were
+fn migrate(conn: &Connection) -> rusqlite::Result<()> {
+    let version: i64 = conn.query_row("PRAGMA user_version", params![], |row| row.get(0))?;
+    if version < 1 && conn.prepare("SELECT uploading FROM upload_queue").is_err() {
+        debug!(log, "Adding uploading to the upload queue");
+        conn.execute_batch(
+            "ALTER TABLE upload_queue ADD COLUMN uploading INTEGER NOT NULL DEFAULT 0",
+        )?;
+    }
+    if version < SCHEMA_VERSION {
+        conn.execute_batch(&format!("PRAGMA user_version = {}", SCHEMA_VERSION))?;
+    }
+    Ok(())
+}
+
+fn db_err(e: rusqlite::Error) -> SyncerErrors {
+    error!(log, "State db call failed {}", e);
+    SyncerErrors::StateDbError
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::state_db::*;
+    use crate::upload_handler::{RootMapping, Roots};
+    use tempfile::tempdir;
+
+    fn record(id: &str) -> SyncRecord {
+        SyncRecord::new(
+            id.into(),
+            "/var/www/RpiCamera/a/im1.jpg".into(),
+            "fake-id-1".into(),
+            false,
+            4,
+            1_600_000_000,
+            Some("abc".into()),
+        )
+    }
+
+    #[test]
+    fn test_state_db_upsert_get() {
+        let db = StateDb::open_in_memory().unwrap();
+        assert_eq!(None, db.get("x").unwrap());
+
+        db.upsert(&record("x")).unwrap();
+        assert_eq!(Some(record("x")), db.get("x").unwrap());
+
+        let mut moved = record("x");
+        moved.remote_id = "fake-id-2".into();
+        db.upsert(&moved).unwrap();
+        assert_eq!(Some(moved), db.get("x").unwrap());
+    }
+
+    #[test]
+    fn test_state_db_forget() {
+        let db = StateDb::open_in_memory().unwrap();
+        let mut folder = record("a");
+        folder.local_path = "/var/www/RpiCamera/a".into();
+        let mut sibling = record("ab");
+        sibling.local_path = "/var/www/RpiCamera/ab".into();
+        db.upsert(&folder).unwrap();
+        db.upsert(&record("a/im1.jpg")).unwrap();
+        db.upsert(&sibling).unwrap();
+
+        db.forget("/var/www/RpiCamera/a/").unwrap();
+        assert_eq!(None, db.get("a").unwrap());
+        assert_eq!(None, db.get("a/im1.jpg").unwrap());
+        assert_eq!(Some(sibling), db.get("ab").unwrap());
+    }
+
+    #[test]
+    fn test_state_db_survives_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("pi_drive_sync/state.db");
+        StateDb::open(&path).unwrap().upsert(&record("x")).unwrap();
+        assert_eq!(
+            Some(record("x")),
+            StateDb::open(&path).unwrap().get("x").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_state_db_snapshot() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        assert_eq!(0, StateDb::snapshot(&path).unwrap().queue_len().unwrap());
+        assert!(!path.exists());
+
+        let db = StateDb::open(&path).unwrap();
+        db.upsert(&record("x")).unwrap();
+        db.enqueue("/var/www/RpiCamera/im1.jpg", false).unwrap();
+        let copy = StateDb::snapshot(&path).unwrap();
+        assert_eq!(Some(record("x")), copy.get("x").unwrap());
+        assert_eq!(1, copy.queue_len().unwrap());
+
+        copy.upsert(&record("y")).unwrap();
+        copy.dequeue("/var/www/RpiCamera/im1.jpg").unwrap();
+        assert_eq!(None, db.get("y").unwrap());
+        assert_eq!(1, db.queue_len().unwrap());
+    }
+
+    #[test]
+    fn test_state_db_migrates_old_queue() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("state.db");
+        //upload_queue as it was before uploads ran on workers
+        Connection::open(&path)
+            .unwrap()
+            .execute_batch(
+                "CREATE TABLE synced (
+                    pi_sync_id TEXT PRIMARY KEY,
+                    local_path TEXT NOT NULL,
+                    remote_id TEXT NOT NULL,
+                    is_dir INTEGER NOT NULL,
+                    size INTEGER NOT NULL,
+                    mtime INTEGER NOT NULL,
+                    checksum TEXT
+                );
+                CREATE TABLE upload_queue (
+                    local_path TEXT PRIMARY KEY,
+                    attempts INTEGER NOT NULL,
+                    next_try INTEGER NOT NULL,
+                    last_error TEXT,
+                    dead INTEGER NOT NULL
+                );
+                INSERT INTO upload_queue VALUES ('/w/im1.jpg', 2, 100, 'x', 0);",
+            )
+            .unwrap();
+        let old = QueuedUpload::new("/w/im1.jpg".into(), 2, 100, Some("x".into()), false);
+        assert_eq!(
+            Some(old.clone()),
+            StateDb::snapshot(&path)
+                .unwrap()
+                .queued("/w/im1.jpg")
+                .unwrap()
+        );
+
+        let db = StateDb::open(&path).unwrap();
+        db.enqueue("/w/im2.jpg", true).unwrap();
+        assert_eq!(Some(old), db.queued("/w/im1.jpg").unwrap());
+        assert_eq!(vec!["/w/im1.jpg"], due_paths(&db, 100));
+        drop(db);
+        //and once migrated, opening it again leaves it be
+        let db = StateDb::open(&path).unwrap();
+        assert_eq!(2, db.queue_len().unwrap());
+        let version: i64 = db
+            .conn
+            .lock()
+            .unwrap()
+            .query_row("PRAGMA user_version", params![], |row| row.get(0))
+            .unwrap();
+        assert_eq!(SCHEMA_VERSION, version);
+    }
+
+    fn due_paths(db: &StateDb, now: i64) -> Vec<String> {
+        db.due_uploads(now)
+            .unwrap()
+            .into_iter()
+            .map(|u| u.local_path)
+            .collect()
+    }
+
+    #[test]
+    fn test_state_db_upload_queue() {
+        let db = StateDb::open_in_memory().unwrap();
+        db.enqueue("/w/im2.jpg", false).unwrap();
+        db.enqueue("/w/im1.jpg", false).unwrap();
+        let mut failed = QueuedUpload::new("/w/im2.jpg".into(), 3, 100, Some("x".into()), false);
+        db.save_queued(&failed).unwrap();
+        //queued again while waiting on a retry, the attempts so far are kept
+        db.enqueue("/w/im2.jpg", false).unwrap();
+        assert_eq!(Some(failed.clone()), db.queued("/w/im2.jpg").unwrap());
+
+        assert_eq!(Some(0), db.next_upload_due().unwrap());
+        let due: Vec<_> = db
+            .due_uploads(50)
+            .unwrap()
+            .into_iter()
+            .map(|u| u.local_path)
+            .collect();
+        assert_eq!(vec!["/w/im1.jpg"], due);
+        //oldest first, a retry does not lose its place
+        let due: Vec<_> = db
+            .due_uploads(100)
+            .unwrap()
+            .into_iter()
+            .map(|u| u.local_path)
+            .collect();
+        assert_eq!(vec!["/w/im2.jpg", "/w/im1.jpg"], due);
+        assert_eq!(2, db.queue_len().unwrap());
+
+        //a worker has it, it is not due again until held back
+        db.enqueue("/w/im1.jpg", true).unwrap();
+        db.enqueue("/w/im1.jpg", false).unwrap();
+        assert_eq!(1, db.due_uploads(100).unwrap().len());
+        assert_eq!(Some(100), db.next_upload_due().unwrap());
+        assert_eq!(1, db.hold_upload(Some("/w/im1.jpg")).unwrap());
+        assert_eq!(2, db.due_uploads(100).unwrap().len());
+
+        db.dequeue("/w/im1.jpg").unwrap();
+        failed.dead = true;
+        db.save_queued(&failed).unwrap();
+        assert_eq!(None, db.next_upload_due().unwrap());
+        assert!(db.due_uploads(1000).unwrap().is_empty());
+        assert_eq!(vec![failed], db.dead_letters().unwrap());
+
+        assert_eq!(0, db.requeue(Some("/w/im1.jpg")).unwrap());
+        assert_eq!(1, db.requeue(None).unwrap());
+        assert_eq!(
+            Some(QueuedUpload::new(
+                "/w/im2.jpg".into(),
+                0,
+                0,
+                Some("x".into()),
+                false
+            )),
+            db.queued("/w/im2.jpg").unwrap()
+        );
+    }
+
+    #[test]
+    fn test_state_db_record() {
+        let dir = tempdir().unwrap();
+        std::fs::create_dir(dir.path().join("a")).unwrap();
+        let local = dir.path().join("a/im1.jpg");
+        std::fs::write(&local, b"jpeg").unwrap();
+        let roots = Roots::from(RootMapping::new(
+            dir.path().into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let db = StateDb::open_in_memory().unwrap();
+
+        let file = roots.file(local.to_str().unwrap());
+        db.record(&file, "fake-id-1").unwrap();
+        let rec = db.get(&file.get_unique_id().unwrap()).unwrap().unwrap();
+        assert_eq!("fake-id-1", rec.remote_id);
+        assert!(!rec.is_dir);
+        assert_eq!(4, rec.size);
+        assert_eq!(Some(format!("{:x}", md5::compute(b"jpeg"))), rec.checksum);
+        assert_eq!(Some("fake-id-1".to_owned()), db.remote_id(&file).unwrap());
+
+        let folder = roots.file(dir.path().join("a").to_str().unwrap());
+        db.record(&folder, "fake-id-2").unwrap();
+        let rec = db.get(&folder.get_unique_id().unwrap()).unwrap().unwrap();
+        assert!(rec.is_dir);
+        assert_eq!(None, rec.checksum);
+    }
+
+    #[test]
+    fn test_state_db_hashed_upload() {
+        let dir = tempdir().unwrap();
+        let local = dir.path().join("im1.jpg");
+        let path = local.to_str().unwrap();
+        std::fs::write(&local, b"jpeg").unwrap();
+        let roots = Roots::from(RootMapping::new(
+            dir.path().into(),
+            "RpiCamera".into(),
+            vec![],
+        ));
+        let db = StateDb::open_in_memory().unwrap();
+        let file = roots.file(path);
+
+        //rewritten after the backend took its copy, but before the upload returned
+        let id = db.hashed_upload(path, || {
+            std::fs::write(&local, b"jpeg, edited").unwrap();
+            Ok(db.recorded(&file, Some("fake-id-1".to_owned())))
+        });
+        assert_eq!(Some("fake-id-1".to_owned()), id.unwrap());
+        let rec = db.get(&file.get_unique_id().unwrap()).unwrap().unwrap();
+        assert_eq!(Some(format!("{:x}", md5::compute(b"jpeg"))), rec.checksum);
+
+        //a failed upload records nothing
+        std::fs::remove_file(&local).unwrap();
+        assert!(db.hashed_upload(path, || Ok(None)).is_err());
+    }
+}
@@ -0,0 +1,267 @@
+//!Upload rate limiting. One token bucket is shared by every upload so the workers together stay
+//!under the limit, and the limit itself can change with the time of day
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use chrono::NaiveTime;
+use serde::Deserialize;
+use std::io::{Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+///Reads are cut to this, so a big chunk goes out steadily rather than in one burst
+const MAX_READ: usize = 16 * 1024;
+
+///A time of day rule, e.g. full speed from 00:00 to 06:00. A window whose end is before its
+///start runs over midnight
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct RateWindow {
+    ///HH:MM, local time
+    pub from: String,
+    ///HH:MM, local time, not included
+    pub to: String,
+    ///Left out for no limit
+    pub kib_per_sec: Option<u64>,
+}
+
+///The upload rate in force at each time of day, in bytes a second, None for no limit
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct RateSchedule {
+    default: Option<u64>,
+    windows: Vec<(NaiveTime, NaiveTime, Option<u64>)>,
+}
+
+impl RateSchedule {
+    ///default_kib applies outside every window, the first window that matches wins
+    pub fn new(default_kib: Option<u64>, windows: &[RateWindow]) -> PiSyncResult<RateSchedule> {
+        let windows = windows
+            .iter()
+            .map(|w| {
+                Ok((
+                    parse_time(&w.from)?,
+                    parse_time(&w.to)?,
+                    bytes_per_sec(w.kib_per_sec)?,
+                ))
+            })
+            .collect::<PiSyncResult<_>>()?;
+        Ok(RateSchedule {
+            default: bytes_per_sec(default_kib)?,
+            windows,
+        })
+    }
+
+    pub fn rate_at(&self, time: NaiveTime) -> Option<u64> {
+        self.windows
+            .iter()
+            .find(|(from, to, _)| {
+                if from <= to {
+                    *from <= time && time < *to
+                } else {
+                    *from <= time || time < *to
+                }
+            })
+            .map(|(_, _, rate)| *rate)
+            .unwrap_or(self.default)
+    }
+}
+
+///A rate of 0 would never send anything, no limit is written by leaving the rate out
+fn bytes_per_sec(kib_per_sec: Option<u64>) -> PiSyncResult<Option<u64>> {
+    match kib_per_sec {
+        Some(0) => {
+            error!(
+                log,
+                "An upload rate of 0 KiB/s never sends, leave it out for no limit"
+            );
+            Err(SyncerErrors::InvalidConfig)
+        }
+        kib => Ok(kib.map(|kib| kib * 1024)),
+    }
+}
+
+fn parse_time(hh_mm: &str) -> PiSyncResult<NaiveTime> {
+    NaiveTime::parse_from_str(hh_mm, "%H:%M").map_err(|e| {
+        error!(log, "Bad time of day {}, expected HH:MM {}", hh_mm, e);
+        SyncerErrors::InvalidConfig
+    })
+}
+
+#[derive(Debug, Default)]
+struct Bucket {
+    ///Bytes that may go now, negative when uploads are waiting on the ones before them
+    tokens: f64,
+    last: Option<Instant>,
+    ///The rate last used, so a change can be logged
+    rate: Option<Option<u64>>,
+}
+
+///Shared by every upload, no schedule means no limit
+#[derive(Debug, Default)]
+pub struct Bandwidth {
+    schedule: RateSchedule,
+    bucket: Mutex<Bucket>,
+}
+
+impl Bandwidth {
+    pub fn new(schedule: RateSchedule) -> Self {
+        Bandwidth {
+            schedule,
+            bucket: Mutex::new(Bucket::default()),
+        }
+    }
+
+    ///Block until bytes more may be sent at the rate in force now
+    pub fn take(&self, bytes: usize) {
+        let wait = self.reserve(bytes, chrono::Local::now().time(), Instant::now());
+        if wait > Duration::from_millis(0) {
+            std::thread::sleep(wait);
+        }
+    }
+
+    ///Take bytes from the bucket, returning how long to wait before sending them. The bucket
+    ///holds at most a second's worth, so an idle link does not allow a long burst after
+    fn reserve(&self, bytes: usize, time_of_day: NaiveTime, now: Instant) -> Duration {
+        let rate = self.schedule.rate_at(time_of_day);
+        let mut bucket = self.bucket.lock().unwrap();
+        if bucket.rate != Some(rate) {
+            match rate {
+                Some(rate) => info!(log, "Upload rate now {} KiB/s", rate / 1024),
+                None => info!(log, "Upload rate now unlimited"),
+            }
+            bucket.rate = Some(rate);
+            bucket.tokens = rate.unwrap_or(0) as f64;
+            bucket.last = Some(now);
+        }
+        let rate = match rate {
+            Some(rate) if rate > 0 => rate as f64,
+            _ => return Duration::from_millis(0),
+        };
+        let elapsed = bucket
+            .last
+            .map(|last| now.saturating_duration_since(last).as_secs_f64())
+            .unwrap_or(0.0);
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - bytes as f64;
+        bucket.last = Some(now);
+        if bucket.tokens >= 0.0 {
+            Duration::from_millis(0)
+        } else {
+            Duration::from_secs_f64(-bucket.tokens / rate)
+        }
+    }
+}
+
+///Wraps what an upload reads from, holding each read back to the bandwidth
+pub struct Throttled<'a, R> {
+    inner: R,
+    bandwidth: &'a Bandwidth,
+}
+
+impl<'a, R> Throttled<'a, R> {
+    pub fn new(inner: R, bandwidth: &'a Bandwidth) -> Self {
+        Throttled { inner, bandwidth }
+    }
+}
+
+impl<'a, R: Read> Read for Throttled<'a, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let max = buf.len().min(MAX_READ);
+        let n = self.inner.read(&mut buf[..max])?;
+        self.bandwidth.take(n);
+        Ok(n)
+    }
+}
+
+impl<'a, R: Seek> Seek for Throttled<'a, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::throttle::*;
+
+    fn at(hh_mm: &str) -> NaiveTime {
+        parse_time(hh_mm).unwrap()
+    }
+
+    fn window(from: &str, to: &str, kib_per_sec: Option<u64>) -> RateWindow {
+        RateWindow {
+            from: from.into(),
+            to: to.into(),
+            kib_per_sec,
+        }
+    }
+
+    #[test]
+    fn test_throttle_schedule() {
+        let schedule = RateSchedule::new(
+            Some(256),
+            &[
+                window("00:00", "06:00", None),
+                window("22:00", "01:00", Some(1024)),
+            ],
+        )
+        .unwrap();
+        assert_eq!(None, schedule.rate_at(at("03:00")));
+        assert_eq!(Some(256 * 1024), schedule.rate_at(at("06:00")));
+        assert_eq!(Some(1024 * 1024), schedule.rate_at(at("23:30")));
+        //the first window that matches wins
+        assert_eq!(None, schedule.rate_at(at("00:30")));
+        assert_eq!(None, RateSchedule::default().rate_at(at("12:00")));
+        assert!(RateSchedule::new(None, &[window("6am", "07:00", None)]).is_err());
+    }
+
+    #[test]
+    fn test_throttle_zero_rate() {
+        //0 would stall every upload, leaving the rate out is no limit
+        assert!(RateSchedule::new(Some(0), &[]).is_err());
+        assert!(RateSchedule::new(None, &[window("00:00", "06:00", Some(0))]).is_err());
+        assert!(RateSchedule::new(Some(1), &[window("00:00", "06:00", None)]).is_ok());
+    }
+
+    #[test]
+    fn test_throttle_token_bucket() {
+        let bandwidth = Bandwidth::new(RateSchedule::new(Some(100), &[]).unwrap());
+        let noon = at("12:00");
+        let start = Instant::now();
+        let zero = Duration::from_millis(0);
+
+        //a second's worth goes straight away, after that it is paced at the rate
+        assert_eq!(zero, bandwidth.reserve(100 * 1024, noon, start));
+        assert_eq!(
+            Duration::from_millis(500),
+            bandwidth.reserve(50 * 1024, noon, start)
+        );
+        //a second worker queues up behind the first
+        assert_eq!(
+            Duration::from_millis(1000),
+            bandwidth.reserve(50 * 1024, noon, start)
+        );
+        assert_eq!(
+            zero,
+            bandwidth.reserve(0, noon, start + Duration::from_secs(1))
+        );
+        //idle for a minute, still only a second's worth
+        let later = start + Duration::from_secs(60);
+        assert_eq!(zero, bandwidth.reserve(100 * 1024, noon, later));
+        assert_eq!(
+            Duration::from_millis(100),
+            bandwidth.reserve(10 * 1024, noon, later)
+        );
+    }
+
+    #[test]
+    fn test_throttle_unlimited() {
+        let bandwidth = Bandwidth::default();
+        let mut read = vec![];
+        Throttled::new(&b"jpeg"[..], &bandwidth)
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(b"jpeg".to_vec(), read);
+        assert_eq!(
+            Duration::from_millis(0),
+            bandwidth.reserve(usize::MAX / 2, at("12:00"), Instant::now())
+        );
+    }
+}
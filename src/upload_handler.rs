@@ -1,16 +1,108 @@
 use crate::common::LOG as log;
 use crate::pi_err::{PiSyncResult, SyncerErrors};
 use base64::encode;
+use regex::Regex;
 use std::ffi::OsStr;
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
 
-pub const LOCAL_ROOT_FOLDER: &str = "/var/www"; //basing base64 on this is dodgy as if I change this we get a different id
-pub const DRIVE_ROOT_FOLDER: &str = "RpiCamera"; //TODO: this does not allow cli to override
+///Maps a watched local dir onto its root folder on the storage provider, e.g.
+///local /var/www/RpiCamera/a/im1.jpg to remote RpiCamera/a/im1.jpg
+#[derive(new, Clone, Debug, PartialEq)]
+pub struct RootMapping {
+    local_root: PathBuf,
+    remote_root: PathBuf,
+    ///Regexes file names under this root must match, empty means everything
+    filters: Vec<String>,
+}
+
+impl RootMapping {
+    pub fn local_root(&self) -> &Path {
+        &self.local_root
+    }
+
+    ///A file or dir under this mapping's local root
+    pub fn file(&self, local_disk_path: &str) -> SyncableFile {
+        SyncableFile::new(local_disk_path.to_owned(), Some(self.clone()))
+    }
+
+    pub fn passes_filter(&self, filename: &str) -> bool {
+        trace!(log, "Check Filter for {}", filename);
+
+        if self.filters.is_empty() {
+            trace!(log, "No filters enabled, {:?} allowed", filename);
+            true
+        } else {
+            let matched = self
+                .filters
+                .iter()
+                .filter_map(|val| Regex::new(val).ok())
+                .any(|re: Regex| {
+                    trace!(log, "Checking {:} against {:?} ", filename, re);
+                    re.is_match(filename)
+                });
+            debug!(log, "Passes Filter = {}", matched);
+            matched
+        }
+    }
+}
+
+///Every watched dir, a path belongs to the root it is deepest inside
+#[derive(new, Clone, Debug, PartialEq)]
+pub struct Roots {
+    mappings: Vec<RootMapping>,
+}
+
+impl Roots {
+    pub fn iter(&self) -> std::slice::Iter<'_, RootMapping> {
+        self.mappings.iter()
+    }
+
+    pub fn mapping_for(&self, local_path: &Path) -> Option<&RootMapping> {
+        self.mappings
+            .iter()
+            .filter(|m| local_path.starts_with(&m.local_root))
+            .max_by_key(|m| m.local_root.components().count())
+    }
+
+    ///A file or dir under one of the roots, cloud_path fails if it is under none of them
+    pub fn file(&self, local_disk_path: &str) -> SyncableFile {
+        let mapping = self.mapping_for(Path::new(local_disk_path)).cloned();
+        SyncableFile::new(local_disk_path.to_owned(), mapping)
+    }
+
+    ///Filtered by the filters of the root the path is in, paths outside every root never pass
+    pub fn passes_filter(&self, local_fs_path: &str) -> bool {
+        let s = self.file(local_fs_path);
+        match (&s.root, s.get_filename()) {
+            (Some(root), Some(filename)) => root.passes_filter(filename),
+            _ => false,
+        }
+    }
+}
+
+impl From<RootMapping> for Roots {
+    fn from(mapping: RootMapping) -> Self {
+        Roots::new(vec![mapping])
+    }
+}
 
 #[derive(new)]
 pub struct SyncableFile {
     local_disk_path: String,
+    root: Option<RootMapping>,
+}
+
+impl SyncableFile {
+    ///The mapping this file is synced under
+    pub fn root(&self) -> PiSyncResult<&RootMapping> {
+        self.root.as_ref().ok_or_else(|| {
+            warn!(
+                log,
+                "{} is not under any watched root", self.local_disk_path
+            );
+            SyncerErrors::InvalidPathError
+        })
+    }
 }
 
 pub trait FileOperations {
@@ -35,11 +127,15 @@ impl FileOperations for SyncableFile {
     fn local_path(&self) -> &Path {
         Path::new(&self.local_disk_path)
     }
+    ///The unique id is based on this, so changing remote_root changes every id
     fn cloud_path(&self) -> PiSyncResult<PathBuf> {
-        Ok(Path::new(DRIVE_ROOT_FOLDER).join(
-            self.local_path()
-                .strip_prefix(Path::new(LOCAL_ROOT_FOLDER))?,
-        ))
+        let root = self.root()?;
+        let relative = self.local_path().strip_prefix(&root.local_root)?;
+        if relative.as_os_str().is_empty() {
+            Ok(root.remote_root.clone())
+        } else {
+            Ok(root.remote_root.join(relative))
+        }
     }
 
     ///Using the local fs based path, return the parent directory path
@@ -69,7 +165,7 @@ impl FileOperations for SyncableFile {
     fn get_unique_id(&self) -> PiSyncResult<String> {
         let cp = &self.cloud_path()?;
         cp.to_str()
-            .and_then(|p| Some(Ok(encode(p))))
+            .map(|p| Ok(encode(p)))
             .ok_or(SyncerErrors::SyncerNoneError)?
     }
 
@@ -86,26 +182,31 @@ impl FileOperations for SyncableFile {
 mod tests {
 
     use crate::upload_handler::*;
+    use std::io::prelude::*;
     use std::path::Path;
+    use tempfile::tempdir;
+
+    const LOCAL_ROOT: &str = "/var/www/RpiCamera";
+    const REMOTE_ROOT: &str = "RpiCamera";
 
     fn syncable_file(p: String) -> SyncableFile {
-        SyncableFile::new(p)
+        RootMapping::new(LOCAL_ROOT.into(), REMOTE_ROOT.into(), vec![]).file(&p)
     }
 
     #[test]
     fn test_upload_get_unique_id_file() {
-        let local_file = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan.txt");
+        let local_file = format!("{}{}", LOCAL_ROOT, "/alan.txt");
         let s = syncable_file(local_file);
         let cp = s.cloud_path().unwrap();
         let cp_string = cp.to_str().unwrap();
 
         assert_eq!(
-            format!("{}/{}", DRIVE_ROOT_FOLDER, "alan.txt"),
+            format!("{}/{}", REMOTE_ROOT, "alan.txt"),
             cp_string,
             "Cloud path not correctly computed"
         );
         assert_eq!(
-            encode(format!("{}/{}", DRIVE_ROOT_FOLDER, "alan.txt")),
+            encode(format!("{}/{}", REMOTE_ROOT, "alan.txt")),
             s.get_unique_id().unwrap(),
             "Base64 Calc of Syncable File does not match a manual encode of same path"
         );
@@ -113,58 +214,135 @@ mod tests {
 
     #[test]
     fn test_upload_get_unique_id_dir() {
-        let local_dir_parent = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan");
+        let local_dir_parent = format!("{}{}", LOCAL_ROOT, "/alan");
         let parent_dir = syncable_file(local_dir_parent.clone());
         assert_eq!(local_dir_parent, parent_dir.local_path().to_str().unwrap());
         let puid = parent_dir.get_unique_id().unwrap();
 
-        let local_file = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan/alan.txt");
+        let local_file = format!("{}{}", LOCAL_ROOT, "/alan/alan.txt");
         let child_file = syncable_file(local_file);
 
         let pp_from_child_path = child_file.parent_path().unwrap();
         let parent_path_as_string = pp_from_child_path.to_str().unwrap();
         assert_eq!(
-            "/tmp/pi_sync/images/alan", parent_path_as_string,
+            "/var/www/RpiCamera/alan", parent_path_as_string,
             "Parent Path Calc is wrong"
         );
 
         //issue cannot construct a Sycable path from Cloud Path
 
-        let tmp_syncable = SyncableFile::new(parent_path_as_string.clone().to_owned());
+        let tmp_syncable = syncable_file(parent_path_as_string.to_owned());
         let tuid = tmp_syncable.get_unique_id().unwrap();
         assert_eq!(puid, tuid);
     }
 
+    #[test]
+    fn test_upload_root_and_outside_root() {
+        let root = syncable_file(LOCAL_ROOT.to_owned());
+        assert_eq!(Path::new(REMOTE_ROOT), root.cloud_path().unwrap());
+        assert_eq!(
+            root.get_unique_id().unwrap(),
+            syncable_file(format!("{}/", LOCAL_ROOT))
+                .get_unique_id()
+                .unwrap()
+        );
+        assert!(syncable_file("/var/www/other/alan.txt".to_owned())
+            .cloud_path()
+            .is_err());
+    }
+
+    #[test]
+    fn test_upload_deepest_root_wins() {
+        let roots = Roots::new(vec![
+            RootMapping::new("/var/www".into(), "www".into(), vec![]),
+            RootMapping::new("/var/www/media".into(), "RpiCamera".into(), vec![]),
+            RootMapping::new("/home/pi/timelapse".into(), "timelapse".into(), vec![]),
+        ]);
+        assert_eq!(
+            Path::new("RpiCamera/a/im1.jpg"),
+            roots.file("/var/www/media/a/im1.jpg").cloud_path().unwrap()
+        );
+        assert_eq!(
+            Path::new("www/index.html"),
+            roots.file("/var/www/index.html").cloud_path().unwrap()
+        );
+        assert_eq!(
+            Path::new("timelapse/tl1.jpg"),
+            roots
+                .file("/home/pi/timelapse/tl1.jpg")
+                .cloud_path()
+                .unwrap()
+        );
+        //a sibling that shares a prefix is not inside the root
+        assert_eq!(
+            Path::new("www/mediaX/a.jpg"),
+            roots.file("/var/www/mediaX/a.jpg").cloud_path().unwrap()
+        );
+        assert!(roots.file("/var/log/motion.log").cloud_path().is_err());
+    }
+
+    #[test]
+    fn test_upload_passes_filter() {
+        let roots = Roots::new(vec![
+            RootMapping::new("/var/www/media".into(), "RpiCamera".into(), vec![]),
+            RootMapping::new(
+                "/var/log/motion".into(),
+                "logs".into(),
+                vec!["^im.*jpg$".to_owned(), "^vi.*mp4$".to_owned()],
+            ),
+        ]);
+        assert!(roots.passes_filter("/var/www/media/status_mjpeg.txt"));
+        assert!(roots.passes_filter("/var/log/motion/im1.jpg"));
+        assert!(roots.passes_filter("/var/log/motion/a/vi1.mp4"));
+        assert!(!roots.passes_filter("/var/log/motion/status_mjpeg.txt"));
+        assert!(!roots.passes_filter("/tmp/im1.jpg"));
+    }
+
     #[test]
     fn test_upload_is_file() {
-        let mut file = std::fs::File::create("/tmp/pi_sync/images/alan.txt").unwrap();
+        let root = tempdir().unwrap();
+        let local_file = root.path().join("alan.txt");
+        let mut file = std::fs::File::create(&local_file).unwrap();
         file.write_all(b"empty_file\n").unwrap();
 
-        let local_file = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan.txt");
-        let s = syncable_file(local_file);
-        assert_eq!(true, s.is_file());
+        let roots = RootMapping::new(root.path().into(), REMOTE_ROOT.into(), vec![]);
+        let s = roots.file(local_file.to_str().unwrap());
+        assert!(s.is_file());
+        assert!(!s.is_dir());
     }
 
     #[test]
     fn test_upload_is_dir() {
-        std::fs::create_dir("/tmp/pi_sync/images/alan");
-        let local_dir = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan");
-        let s = syncable_file(local_dir);
-        assert_eq!(true, s.is_dir());
+        let root = tempdir().unwrap();
+        let local_dir = root.path().join("alan");
+        std::fs::create_dir(&local_dir).unwrap();
+
+        let roots = RootMapping::new(root.path().into(), REMOTE_ROOT.into(), vec![]);
+        let s = roots.file(local_dir.to_str().unwrap());
+        assert!(s.is_dir());
+        assert!(!s.is_file());
     }
 
     #[test]
     fn test_upload_remote_path() {
-        let local_dir = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan");
+        let local_dir = format!("{}{}", LOCAL_ROOT, "/alan");
         let s = syncable_file(local_dir);
-        let rp = Path::new(DRIVE_ROOT_FOLDER).join("alan");
+        let rp = Path::new(REMOTE_ROOT).join("alan");
         let cp = s.cloud_path();
         assert_eq!(rp, cp.unwrap());
+
+        //any local dir can be mapped to any remote root
+        let s = RootMapping::new("/home/pi/footage".into(), "cams/front".into(), vec![])
+            .file("/home/pi/footage/2020/vi1.mp4");
+        assert_eq!(
+            Path::new("cams/front/2020/vi1.mp4"),
+            s.cloud_path().unwrap()
+        );
     }
 
     #[test]
     fn test_upload_local_path() {
-        let local_dir = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan");
+        let local_dir = format!("{}{}", LOCAL_ROOT, "/alan");
         let s = syncable_file(local_dir.clone());
         let lp = s.local_path();
         let str_lp = lp.to_str().unwrap();
@@ -173,45 +351,45 @@ mod tests {
 
     #[test]
     fn test_upload_parent_path() {
-        let root_file = format!("{}{}", LOCAL_ROOT_FOLDER, "/alan.txt");
+        let root_file = format!("{}{}", LOCAL_ROOT, "/alan.txt");
         let root_s = syncable_file(root_file);
 
         assert_eq!(
-            Path::new(LOCAL_ROOT_FOLDER),
+            Path::new(LOCAL_ROOT),
             root_s.parent_path().unwrap(),
             "Parent path is not correct"
         );
 
-        let child = format!("{}{}", LOCAL_ROOT_FOLDER, "/a/a.txt");
+        let child = format!("{}{}", LOCAL_ROOT, "/a/a.txt");
         let c = syncable_file(child);
 
         assert_eq!(
-            Path::new(LOCAL_ROOT_FOLDER).join("a"),
+            Path::new(LOCAL_ROOT).join("a"),
             c.parent_path().unwrap(),
             "Parent path is not correct for /a/a.txt"
         );
 
-        let child1 = format!("{}{}", LOCAL_ROOT_FOLDER, "/b/b");
+        let child1 = format!("{}{}", LOCAL_ROOT, "/b/b");
         let c1 = syncable_file(child1);
 
         assert_eq!(
-            Path::new(LOCAL_ROOT_FOLDER).join("b"),
+            Path::new(LOCAL_ROOT).join("b"),
             c1.parent_path().unwrap(),
             "Parent path is not correct for /b/b"
         );
 
-        let child2 = format!("{}{}", LOCAL_ROOT_FOLDER, "/c/c/test.txt");
+        let child2 = format!("{}{}", LOCAL_ROOT, "/c/c/test.txt");
         let c2 = syncable_file(child2);
         assert_eq!(
-            Path::new(LOCAL_ROOT_FOLDER).join("c/c"),
+            Path::new(LOCAL_ROOT).join("c/c"),
             c2.parent_path().unwrap(),
             "Parent path is not correct for /c/c/test.txt"
         );
 
-        let child3 = format!("{}{}", LOCAL_ROOT_FOLDER, "/d");
+        let child3 = format!("{}{}", LOCAL_ROOT, "/d");
         let c3 = syncable_file(child3);
         assert_eq!(
-            Path::new(LOCAL_ROOT_FOLDER),
+            Path::new(LOCAL_ROOT),
             c3.parent_path().unwrap(),
             "Parent path is not correct for /d"
         );
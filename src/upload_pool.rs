@@ -0,0 +1,218 @@
+//!Uploads run on a pool of workers so one big video does not hold up every snapshot behind it,
+//!or the watcher thread. Each worker has its own client, a Drive hub cannot be shared
+use crate::cloud_client::CloudClient;
+use crate::common::LOG as log;
+use std::collections::HashMap;
+use std::panic::{catch_unwind, AssertUnwindSafe};
+use std::path::PathBuf;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+///What a worker does with each path, with its own client
+pub type UploadJob = dyn Fn(&dyn CloudClient, &str) + Send + Sync;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum InFlight {
+    Waiting,
+    Uploading,
+    ///Submitted again while uploading, it may have changed under the worker
+    Again,
+}
+
+pub struct UploadPool {
+    jobs: Option<Sender<PathBuf>>,
+    in_flight: Arc<Mutex<HashMap<PathBuf, InFlight>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl UploadPool {
+    ///One worker per client
+    pub fn new(clients: Vec<Box<dyn CloudClient>>, job: Arc<UploadJob>) -> Self {
+        let (sender, receiver) = channel();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let in_flight = Arc::new(Mutex::new(HashMap::new()));
+        let workers = clients
+            .into_iter()
+            .enumerate()
+            .map(|(n, client)| {
+                let receiver = Arc::clone(&receiver);
+                let in_flight = Arc::clone(&in_flight);
+                let job = Arc::clone(&job);
+                std::thread::Builder::new()
+                    .name(format!("upload-{}", n))
+                    .spawn(move || work(client.as_ref(), &receiver, &in_flight, job.as_ref()))
+                    .expect("cannot start upload worker")
+            })
+            .collect::<Vec<_>>();
+        debug!(log, "Started {} upload workers", workers.len());
+        UploadPool {
+            jobs: Some(sender),
+            in_flight,
+            workers,
+        }
+    }
+
+    ///Hand a path to the workers. One already waiting is not sent twice, one being uploaded
+    ///goes again once the worker is done with it. False if the workers are gone, the caller
+    ///keeps the path
+    pub fn submit(&self, path: PathBuf) -> bool {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        match in_flight.get(&path).copied() {
+            None => {
+                let jobs = match &self.jobs {
+                    Some(jobs) => jobs,
+                    None => return false,
+                };
+                in_flight.insert(path.clone(), InFlight::Waiting);
+                if let Err(e) = jobs.send(path) {
+                    error!(log, "Upload workers gone, {:?} not uploaded", e.0);
+                    in_flight.remove(&e.0);
+                    return false;
+                }
+            }
+            Some(InFlight::Uploading) => {
+                trace!(log, "{:?} changed while uploading, going again after", path);
+                in_flight.insert(path, InFlight::Again);
+            }
+            Some(InFlight::Waiting) | Some(InFlight::Again) => {}
+        }
+        true
+    }
+}
+
+///Finish what was submitted, then stop the workers
+impl Drop for UploadPool {
+    fn drop(&mut self) {
+        self.jobs.take();
+        for worker in self.workers.drain(..) {
+            if worker.join().is_err() {
+                error!(log, "Upload worker panicked");
+            }
+        }
+    }
+}
+
+fn work(
+    client: &dyn CloudClient,
+    jobs: &Mutex<Receiver<PathBuf>>,
+    in_flight: &Mutex<HashMap<PathBuf, InFlight>>,
+    job: &UploadJob,
+) {
+    loop {
+        //only held while waiting, so the other workers can take the next one
+        let path = match jobs.lock().unwrap_or_else(|e| e.into_inner()).recv() {
+            Ok(path) => path,
+            Err(_) => return,
+        };
+        in_flight
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(path.clone(), InFlight::Uploading);
+        loop {
+            match path.to_str() {
+                //a job that panics loses that upload, not the worker
+                Some(p) => {
+                    if catch_unwind(AssertUnwindSafe(|| job(client, p))).is_err() {
+                        error!(log, "Upload of {} panicked", p);
+                    }
+                }
+                None => warn!(log, "Cannot upload {:?}, not valid UTF-8", path),
+            }
+            let mut in_flight = in_flight.lock().unwrap_or_else(|e| e.into_inner());
+            if in_flight.get(&path) == Some(&InFlight::Again) {
+                in_flight.insert(path.clone(), InFlight::Uploading);
+            } else {
+                in_flight.remove(&path);
+                break;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_fixture::LocalFixture;
+    use crate::upload_pool::*;
+
+    ///Each job reports it started, then waits for the test to let it finish
+    fn gated_pool(workers: usize) -> (UploadPool, Receiver<String>, Sender<()>, LocalFixture) {
+        let fixture = LocalFixture::new();
+        let clients = (0..workers)
+            .map(|_| Box::new(fixture.client()) as Box<dyn CloudClient>)
+            .collect();
+        let (started_tx, started) = channel();
+        let (gate, gate_rx) = channel();
+        let (started_tx, gate_rx) = (Mutex::new(started_tx), Mutex::new(gate_rx));
+        let job = move |_client: &dyn CloudClient, path: &str| {
+            started_tx.lock().unwrap().send(path.to_owned()).unwrap();
+            gate_rx.lock().unwrap().recv().unwrap();
+        };
+        (
+            UploadPool::new(clients, Arc::new(job)),
+            started,
+            gate,
+            fixture,
+        )
+    }
+
+    #[test]
+    fn test_upload_pool_concurrent() {
+        let (pool, started, gate, _fixture) = gated_pool(2);
+        pool.submit("/w/vi1.mp4".into());
+        pool.submit("/w/im1.jpg".into());
+        //both are under way before either is let finish
+        let mut both = vec![started.recv().unwrap(), started.recv().unwrap()];
+        both.sort();
+        assert_eq!(vec!["/w/im1.jpg", "/w/vi1.mp4"], both);
+        gate.send(()).unwrap();
+        gate.send(()).unwrap();
+        drop(pool);
+    }
+
+    #[test]
+    fn test_upload_pool_coalesces() {
+        let (pool, started, gate, _fixture) = gated_pool(1);
+        pool.submit("/w/vi1.mp4".into());
+        assert_eq!("/w/vi1.mp4", started.recv().unwrap());
+        //changed mid upload, and a file waiting its turn submitted twice
+        pool.submit("/w/vi1.mp4".into());
+        pool.submit("/w/vi1.mp4".into());
+        pool.submit("/w/im1.jpg".into());
+        pool.submit("/w/im1.jpg".into());
+        for _ in 0..3 {
+            gate.send(()).unwrap();
+        }
+        drop(pool);
+        assert_eq!(
+            vec!["/w/vi1.mp4", "/w/im1.jpg"],
+            started.try_iter().collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_upload_pool_survives_panics() {
+        let (done_tx, done) = channel();
+        let done_tx = Mutex::new(done_tx);
+        let job = move |_client: &dyn CloudClient, path: &str| {
+            if path == "/w/bad.jpg" {
+                panic!("upload of {} blew up", path);
+            }
+            done_tx.lock().unwrap().send(path.to_owned()).unwrap();
+        };
+        let fixture = LocalFixture::new();
+        let client = Box::new(fixture.client()) as Box<dyn CloudClient>;
+        let pool = UploadPool::new(vec![client], Arc::new(job));
+        assert!(pool.submit("/w/bad.jpg".into()));
+        assert!(pool.submit("/w/im1.jpg".into()));
+        assert_eq!("/w/im1.jpg", done.recv().unwrap());
+        //the panicked one is not left in flight, so it can be submitted again
+        assert!(pool.submit("/w/bad.jpg".into()));
+        drop(pool);
+
+        //no workers left to take it
+        let pool = UploadPool::new(vec![], Arc::new(|_: &dyn CloudClient, _: &str| {}));
+        assert!(!pool.submit("/w/im1.jpg".into()));
+        assert!(pool.in_flight.lock().unwrap().is_empty());
+    }
+}
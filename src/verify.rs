@@ -0,0 +1,304 @@
+//!Checks the backend holds what the roots do. Local and remote files are matched by pi_sync_id,
+//!and what does not match up is reported, and with repair uploaded again
+use crate::cloud_client::{CloudClient, RemoteFile};
+use crate::common::LOG as log;
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::scanner;
+use crate::state_db::{md5_hex, StateDb};
+use crate::upload_handler::{FileOperations, Roots};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case", tag = "problem")]
+pub enum Problem {
+    ///Here but not on the backend
+    MissingRemote,
+    ///On the backend, but neither here nor in our records
+    Orphan,
+    SizeMismatch {
+        local: u64,
+        remote: u64,
+    },
+    Md5Mismatch {
+        local: String,
+        remote: String,
+    },
+    ///More than one remote file has the same pi_sync_id
+    Duplicate {
+        remote_ids: Vec<String>,
+    },
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Finding {
+    #[serde(flatten)]
+    pub problem: Problem,
+    pub cloud_path: PathBuf,
+    pub local_path: Option<String>,
+    pub remote_id: Option<String>,
+}
+
+impl Finding {
+    ///Uploading the local file again puts it right
+    fn repairable(&self) -> bool {
+        match self.problem {
+            Problem::MissingRemote | Problem::SizeMismatch { .. } | Problem::Md5Mismatch { .. } => {
+                self.local_path.is_some()
+            }
+            Problem::Orphan | Problem::Duplicate { .. } => false,
+        }
+    }
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let detail = match &self.problem {
+            Problem::MissingRemote => self.local_path.clone().unwrap_or_default(),
+            Problem::Orphan => self.remote_id.clone().unwrap_or_default(),
+            Problem::SizeMismatch { local, remote } => {
+                format!("local {} remote {}", local, remote)
+            }
+            Problem::Md5Mismatch { local, remote } => format!("local {} remote {}", local, remote),
+            Problem::Duplicate { remote_ids } => remote_ids.join(","),
+        };
+        let problem = match self.problem {
+            Problem::MissingRemote => "missing_remote",
+            Problem::Orphan => "orphan",
+            Problem::SizeMismatch { .. } => "size_mismatch",
+            Problem::Md5Mismatch { .. } => "md5_mismatch",
+            Problem::Duplicate { .. } => "duplicate",
+        };
+        write!(f, "{}\t{}\t{}", problem, self.cloud_path.display(), detail)
+    }
+}
+
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct Report {
+    pub local_files: usize,
+    pub remote_files: usize,
+    ///Here and on the backend with the same content
+    pub matched: usize,
+    ///On the backend only, we synced them and they have since gone from here, e.g. by retention
+    pub remote_only: usize,
+    ///Uploaded again by repair
+    pub repaired: usize,
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    ///Anything that repair has not put right
+    pub fn clean(&self) -> bool {
+        self.findings.len() == self.repaired
+    }
+}
+
+///List the backend and compare it with the roots
+pub fn verify(client: &dyn CloudClient, roots: &Roots, state: &StateDb) -> PiSyncResult<Report> {
+    compare(client.list_remote()?, roots, state)
+}
+
+fn compare(remote: Vec<RemoteFile>, roots: &Roots, state: &StateDb) -> PiSyncResult<Report> {
+    let mut report = Report {
+        remote_files: remote.len(),
+        ..Default::default()
+    };
+    let mut by_id: BTreeMap<String, Vec<RemoteFile>> = BTreeMap::new();
+    for file in remote {
+        by_id.entry(file.pi_sync_id.clone()).or_default().push(file);
+    }
+    for files in by_id.values().filter(|files| files.len() > 1) {
+        report.findings.push(Finding {
+            problem: Problem::Duplicate {
+                remote_ids: files.iter().map(|f| f.remote_id.clone()).collect(),
+            },
+            cloud_path: files[0].cloud_path.clone(),
+            local_path: None,
+            remote_id: None,
+        });
+    }
+
+    for path in scanner::local_files(roots) {
+        let local_path = path.to_str().unwrap_or_default();
+        report.local_files += 1;
+        let s = roots.file(local_path);
+        let cloud_path = s.cloud_path()?;
+        let remote = match by_id.remove(&s.get_unique_id()?) {
+            Some(mut files) => files.swap_remove(0),
+            None => {
+                report.findings.push(Finding {
+                    problem: Problem::MissingRemote,
+                    cloud_path,
+                    local_path: Some(local_path.to_owned()),
+                    remote_id: None,
+                });
+                continue;
+            }
+        };
+        match compare_file(local_path, &remote) {
+            Ok(None) => report.matched += 1,
+            Ok(Some(problem)) => report.findings.push(Finding {
+                problem,
+                cloud_path,
+                local_path: Some(local_path.to_owned()),
+                remote_id: Some(remote.remote_id),
+            }),
+            Err(e) => warn!(log, "Cannot verify {}, it may be gone {}", local_path, e),
+        }
+    }
+
+    //what is left is on the backend only
+    for (pi_sync_id, files) in by_id {
+        if state.get(&pi_sync_id)?.is_some() {
+            report.remote_only += files.len();
+            continue;
+        }
+        for file in files {
+            report.findings.push(Finding {
+                problem: Problem::Orphan,
+                cloud_path: file.cloud_path,
+                local_path: None,
+                remote_id: Some(file.remote_id),
+            });
+        }
+    }
+    report
+        .findings
+        .sort_by(|a, b| a.cloud_path.cmp(&b.cloud_path));
+    Ok(report)
+}
+
+///The md5 is only worked out when the backend has one to compare with
+fn compare_file(local_path: &str, remote: &RemoteFile) -> PiSyncResult<Option<Problem>> {
+    let size = std::fs::metadata(local_path)
+        .map_err(|_e| SyncerErrors::InvalidPathError)?
+        .len();
+    if size != remote.size {
+        return Ok(Some(Problem::SizeMismatch {
+            local: size,
+            remote: remote.size,
+        }));
+    }
+    let remote_md5 = match &remote.md5 {
+        Some(md5) => md5,
+        None => return Ok(None),
+    };
+    let md5 = md5_hex(local_path)?;
+    if &md5 != remote_md5 {
+        return Ok(Some(Problem::Md5Mismatch {
+            local: md5,
+            remote: remote_md5.clone(),
+        }));
+    }
+    Ok(None)
+}
+
+///Upload again every file that is missing from the backend or does not match it
+pub fn repair(client: &dyn CloudClient, state: &StateDb, report: &mut Report) {
+    for finding in report.findings.iter().filter(|f| f.repairable()) {
+        let local_path = finding.local_path.as_deref().unwrap_or_default();
+        match state.hashed_upload(local_path, || client.upload_file(local_path)) {
+            Ok(_id) => {
+                info!(log, "Repaired {}", finding);
+                report.repaired += 1;
+            }
+            Err(e) => warn!(log, "Cannot repair {} {}", finding, e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::local_fixture::LocalFixture;
+    use crate::verify::*;
+    use chrono::Utc;
+    use std::path::Path;
+
+    #[test]
+    fn test_verify_and_repair() {
+        let s = LocalFixture::new();
+        let (lb, roots, state) = (&s.lb, &s.roots, &s.state);
+        let remote = s.target.path().join("RpiCamera");
+        for name in &["im1.jpg", "im2.jpg", "im3.jpg", "im4.jpg"] {
+            lb.upload_file(&s.write(name, b"jpeg")).unwrap();
+        }
+        let missing = s.write("im5.jpg", b"jpeg");
+        std::fs::write(remote.join("im2.jpg"), b"jpeg, longer").unwrap();
+        std::fs::write(remote.join("im3.jpg"), b"JPEG").unwrap();
+        std::fs::remove_file(s.local.path().join("im4.jpg")).unwrap();
+        std::fs::write(remote.join("im6.jpg"), b"jpeg").unwrap();
+
+        let mut report = verify(lb, roots, state).unwrap();
+        let found = report
+            .findings
+            .iter()
+            .map(|f| {
+                f.to_string()
+                    .split('\t')
+                    .take(2)
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            vec![
+                "size_mismatch RpiCamera/im2.jpg",
+                "md5_mismatch RpiCamera/im3.jpg",
+                "missing_remote RpiCamera/im5.jpg",
+                "orphan RpiCamera/im6.jpg",
+            ],
+            found
+        );
+        assert_eq!(
+            (4, 5, 1, 1),
+            (
+                report.local_files,
+                report.remote_files,
+                report.matched,
+                report.remote_only
+            )
+        );
+        assert_eq!(Some(missing), report.findings[2].local_path);
+        let json = serde_json::to_value(&report.findings[0]).unwrap();
+        assert_eq!("size_mismatch", json["problem"]);
+        assert_eq!(12, json["remote"]);
+
+        repair(lb, state, &mut report);
+        assert_eq!(3, report.repaired);
+        assert!(!report.clean());
+        let report = verify(lb, roots, state).unwrap();
+        assert_eq!(4, report.matched);
+        assert_eq!(1, report.findings.len());
+    }
+
+    #[test]
+    fn test_verify_duplicates() {
+        let s = LocalFixture::new();
+        s.write("im1.jpg", b"jpeg");
+        let copy = |id: &str| {
+            RemoteFile::new(
+                id.into(),
+                base64::encode("RpiCamera/im1.jpg"),
+                Path::new("RpiCamera/im1.jpg").into(),
+                4,
+                None,
+                Utc::now(),
+            )
+        };
+
+        let report = compare(vec![copy("id-1"), copy("id-2")], &s.roots, &s.state).unwrap();
+        assert_eq!(1, report.matched);
+        assert_eq!(
+            vec![Problem::Duplicate {
+                remote_ids: vec!["id-1".into(), "id-2".into()]
+            }],
+            report
+                .findings
+                .into_iter()
+                .map(|f| f.problem)
+                .collect::<Vec<_>>()
+        );
+    }
+}
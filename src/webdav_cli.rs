@@ -0,0 +1,717 @@
+use crate::cloud_client::{CloudClient, TRASH_DIR};
+use crate::common::{uri_encode, LOG as log};
+use crate::pi_err::{PiSyncResult, SyncerErrors};
+use crate::scanner::moved_children;
+use crate::state_db::StateDb;
+use crate::throttle::{Bandwidth, Throttled};
+use crate::upload_handler::{FileOperations, Roots, SyncableFile};
+use hyper::client::{Body, Response};
+use hyper::header::{Authorization, Basic, ContentType, Headers};
+use hyper::method::Method;
+use hyper::status::StatusCode;
+use regex::Regex;
+use std::io::Read;
+use std::path::Path;
+use std::sync::Arc;
+
+lazy_static::lazy_static! {
+    static ref PI_SYNC_PROP: Regex = Regex::new(
+        r"<(?:[A-Za-z0-9_.-]+:)?pi_sync_id(?:\s[^>]*)?>([^<]*)</(?:[A-Za-z0-9_.-]+:)?pi_sync_id>"
+    )
+    .unwrap();
+}
+
+///Our dead property lives in its own namespace so it cannot clash with the server's
+const PI_SYNC_NS: &str = "https://github.com/codecrunchers/pi_drive_sync";
+
+///Where the DAV root is, e.g. https://cloud.example.com/remote.php/dav/files/pi/ for Nextcloud
+#[derive(new, Clone, Debug)]
+pub struct WebDavOptions {
+    url: String,
+    username: String,
+    ///Shared by every upload, see throttle
+    bandwidth: Arc<Bandwidth>,
+}
+
+///Sync to a WebDAV server such as Nextcloud or ownCloud, files are tagged with their
+///pi_sync_id as a dead property via PROPPATCH so id() can tell our files from anyone else's
+pub struct WebDavClient {
+    base_url: String,
+    auth: Option<Authorization<Basic>>,
+    roots: Roots,
+    client: hyper::Client,
+    state: Arc<StateDb>,
+    bandwidth: Arc<Bandwidth>,
+}
+
+impl WebDavClient {
+    ///The password is taken from WEBDAV_PASSWORD, use an app password for Nextcloud
+    pub fn new(opts: WebDavOptions, roots: Roots, state: Arc<StateDb>) -> Self {
+        let auth = std::env::var("WEBDAV_PASSWORD").ok().map(|password| {
+            Authorization(Basic {
+                username: opts.username.clone(),
+                password: Some(password),
+            })
+        });
+        if auth.is_none() {
+            warn!(
+                log,
+                "WEBDAV_PASSWORD not set, connecting to {} anonymously", opts.url
+            );
+        }
+
+        WebDavClient {
+            base_url: format!("{}/", opts.url.trim_end_matches('/')),
+            auth,
+            roots,
+            client: hyper::Client::with_connector(hyper::net::HttpsConnector::new(
+                hyper_rustls::TlsClient::new(),
+            )),
+            state,
+            bandwidth: opts.bandwidth,
+        }
+    }
+
+    fn href(&self, syncable: &SyncableFile) -> PiSyncResult<String> {
+        let cp = syncable.cloud_path()?;
+        let cp = cp.to_str().ok_or(SyncerErrors::InvalidPathError)?;
+        Ok(format!(
+            "{}{}",
+            self.base_url,
+            uri_encode(cp.trim_end_matches('/'), false)
+        ))
+    }
+
+    fn send<'a>(
+        &'a self,
+        method: &str,
+        href: &str,
+        extra_headers: &[(&str, &str)],
+        body: Option<Body<'a>>,
+    ) -> PiSyncResult<Response> {
+        trace!(log, "WebDAV {} {}", method, href);
+        let mut headers = Headers::new();
+        if let Some(auth) = &self.auth {
+            headers.set(auth.clone());
+        }
+        for (name, value) in extra_headers {
+            headers.set_raw(name.to_string(), vec![value.as_bytes().to_vec()]);
+        }
+
+        //only the property requests carry XML, a PUT body is the file itself
+        let xml = method == "PROPFIND" || method == "PROPPATCH";
+        let method = method
+            .parse::<Method>()
+            .map_err(|_e| SyncerErrors::ProviderError)?;
+        let req = self.client.request(method, href).headers(headers);
+        let req = match (body, xml) {
+            (Some(b), true) => req
+                .header(ContentType(
+                    "application/xml; charset=utf-8".parse().unwrap(),
+                ))
+                .body(b),
+            (Some(b), false) => req
+                .header(ContentType("application/octet-stream".parse().unwrap()))
+                .body(b),
+            (None, _) => req,
+        };
+        req.send().map_err(|e| {
+            error!(log, "WebDAV request to {} failed {}", href, e);
+            SyncerErrors::ProviderError
+        })
+    }
+
+    fn expect_success(mut res: Response, what: &str) -> PiSyncResult<Response> {
+        if res.status.is_success() {
+            Ok(res)
+        } else {
+            let mut err = String::new();
+            let _ = res.read_to_string(&mut err);
+            error!(log, "WebDAV {} failed {} {}", what, res.status, err);
+            Err(SyncerErrors::ProviderError)
+        }
+    }
+
+    ///Set the pi_sync_id dead property on a resource
+    fn tag(&self, href: &str, uid: &str) -> PiSyncResult<()> {
+        let body = proppatch_xml(uid);
+        let res = self.send(
+            "PROPPATCH",
+            href,
+            &[],
+            Some(Body::BufBody(body.as_bytes(), body.len())),
+        )?;
+        let mut xml = String::new();
+        let _ = Self::expect_success(res, "PROPPATCH")?.read_to_string(&mut xml);
+        //a 207 can still carry a failed propstat
+        if !xml.contains("HTTP/1.1 4") && !xml.contains("HTTP/1.1 5") {
+            Ok(())
+        } else {
+            error!(log, "PROPPATCH of {} refused {}", href, xml);
+            Err(SyncerErrors::ProviderError)
+        }
+    }
+
+    ///MKCOL every missing ancestor of the file, WebDAV will not create them for us
+    fn create_path(&self, syncable: &SyncableFile) -> PiSyncResult<bool> {
+        let root = syncable.root()?;
+        let mut ancestors = vec![];
+        let mut parent = syncable.parent_path()?;
+        while let Some(p) = parent.to_str() {
+            if root.file(p).cloud_path().is_err() {
+                break;
+            }
+            ancestors.push(p.to_owned());
+            if !parent.pop() {
+                break;
+            }
+        }
+
+        for dir in ancestors.iter().rev() {
+            if self.state.remote_id(&self.roots.file(dir))?.is_some() {
+                debug!(log, "State db hit for {:?}, not creating dir", dir);
+                continue;
+            }
+            self.create_dir(dir, None)?;
+        }
+        Ok(true)
+    }
+
+    ///MKCOL each collection down to cloud_dir, those that exist already are fine
+    fn mkcol_all(&self, cloud_dir: &Path) -> PiSyncResult<()> {
+        let mut href = self.base_url.clone();
+        for dir in cloud_dir.iter() {
+            let dir = dir.to_str().ok_or(SyncerErrors::InvalidPathError)?;
+            href.push_str(&format!("{}/", uri_encode(dir, true)));
+            let res = self.send("MKCOL", &href, &[], None)?;
+            if res.status != StatusCode::MethodNotAllowed {
+                Self::expect_success(res, "MKCOL")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl CloudClient for WebDavClient {
+    ///PUT the file then tag it, returning its href
+    fn upload_file(&self, local_fs_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let href = self.href(&s)?;
+
+        self.create_path(&s)?;
+
+        let mut file = std::fs::File::open(local_fs_path).map_err(|_e| {
+            error!(log, "File deleted before we got to it");
+            SyncerErrors::ProviderError
+        })?;
+        let size = file
+            .metadata()
+            .map_err(|_e| SyncerErrors::InvalidPathError)?
+            .len();
+
+        let mut file = Throttled::new(&mut file, &self.bandwidth);
+        let res = self.send("PUT", &href, &[], Some(Body::SizedBody(&mut file, size)))?;
+        Self::expect_success(res, "PUT")?;
+        self.tag(&href, &s.get_unique_id()?)?;
+        debug!(log, "Uploaded {} to {}", local_fs_path, href);
+        Ok(self.state.recorded(&s, Some(href)))
+    }
+
+    ///MKCOL the collection, an existing one is fine as long as we can tag it. Its parents
+    ///are MKCOLed first, a remote_root such as logs/motion is more than one collection
+    fn create_dir(
+        &self,
+        local_fs_path: &str,
+        _parent_id: Option<&str>,
+    ) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_fs_path);
+        let href = self.href(&s)?;
+        let collection = format!("{}/", href);
+
+        if let Some(parent) = s.cloud_path()?.parent() {
+            self.mkcol_all(parent)?;
+        }
+
+        let res = self.send("MKCOL", &collection, &[], None)?;
+        match res.status {
+            StatusCode::MethodNotAllowed => trace!(log, "Collection {} already exists", href),
+            _ => {
+                Self::expect_success(res, "MKCOL")?;
+            }
+        }
+        self.tag(&collection, &s.get_unique_id()?)?;
+        Ok(self.state.recorded(&s, Some(href)))
+    }
+
+    ///PROPFIND the resource, it is only ours if its pi_sync_id matches
+    fn id(&self, local_path: &str) -> PiSyncResult<Option<String>> {
+        let s = self.roots.file(local_path);
+        if let Some(href) = self.state.remote_id(&s)? {
+            return Ok(Some(href));
+        }
+        let href = self.href(&s)?;
+        let uid = s.get_unique_id()?;
+
+        let body = propfind_xml();
+        let res = self.send(
+            "PROPFIND",
+            &href,
+            &[("Depth", "0")],
+            Some(Body::BufBody(body.as_bytes(), body.len())),
+        )?;
+        if res.status == StatusCode::NotFound {
+            return Ok(None);
+        }
+
+        let mut xml = String::new();
+        let _ = Self::expect_success(res, "PROPFIND")?.read_to_string(&mut xml);
+        match pi_sync_id_prop(&xml) {
+            Some(ref found) if found == &uid => Ok(self.state.recorded(&s, Some(href))),
+            found => {
+                debug!(
+                    log,
+                    "{} exists but pi_sync_id is {:?} not {}", href, found, uid
+                );
+                Ok(None)
+            }
+        }
+    }
+
+    ///DELETE takes a collection and everything in it. Trashed resources are MOVEd under
+    ///.pi_sync_trash, replacing any older copy
+    fn delete(&self, local_path: &str, trash: bool) -> PiSyncResult<()> {
+        let s = self.roots.file(local_path);
+        let href = match self.id(local_path)? {
+            Some(href) => href,
+            None => {
+                debug!(log, "{} was never synced, nothing to delete", local_path);
+                return Ok(());
+            }
+        };
+
+        let res = if trash {
+            let trashed = Path::new(TRASH_DIR).join(s.cloud_path()?);
+            if let Some(parent) = trashed.parent() {
+                self.mkcol_all(parent)?;
+            }
+            let trashed = trashed.to_str().ok_or(SyncerErrors::InvalidPathError)?;
+            let destination = format!("{}{}", self.base_url, uri_encode(trashed, false));
+            self.send(
+                "MOVE",
+                &href,
+                &[("Destination", &destination), ("Overwrite", "T")],
+                None,
+            )?
+        } else {
+            self.send("DELETE", &href, &[], None)?
+        };
+        if res.status != StatusCode::NotFound {
+            Self::expect_success(res, if trash { "MOVE" } else { "DELETE" })?;
+        }
+        self.state.forget(local_path)
+    }
+
+    ///MOVE the resource, then retag it and everything in it with their new pi_sync_ids
+    fn rename(&self, from: &str, to: &str) -> PiSyncResult<Option<String>> {
+        let old = match self.id(from)? {
+            Some(href) => href,
+            None => return Ok(None),
+        };
+        let t = self.roots.file(to);
+        let href = self.href(&t)?;
+
+        if let Some(parent) = t.cloud_path()?.parent() {
+            self.mkcol_all(parent)?;
+        }
+        let res = self.send(
+            "MOVE",
+            &old,
+            &[("Destination", &href), ("Overwrite", "T")],
+            None,
+        )?;
+        Self::expect_success(res, "MOVE")?;
+
+        let moved =
+            std::iter::once((from.to_owned(), to.to_owned())).chain(moved_children(from, to));
+        for (_old, new) in moved {
+            let n = self.roots.file(&new);
+            let new_href = self.href(&n)?;
+            if n.is_dir() {
+                self.tag(&format!("{}/", new_href), &n.get_unique_id()?)?;
+            } else {
+                self.tag(&new_href, &n.get_unique_id()?)?;
+            }
+        }
+        self.state.forget(from)?;
+        Ok(self.state.recorded(&t, Some(href)))
+    }
+
+    fn check_ready(&self) -> PiSyncResult<()> {
+        let body = propfind_xml();
+        let res = self.send(
+            "PROPFIND",
+            &self.base_url,
+            &[("Depth", "0")],
+            Some(Body::BufBody(body.as_bytes(), body.len())),
+        )?;
+        Self::expect_success(res, "PROPFIND").map(|_| ())
+    }
+}
+
+fn proppatch_xml(uid: &str) -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <d:propertyupdate xmlns:d=\"DAV:\" xmlns:ps=\"{}\">\
+         <d:set><d:prop><ps:pi_sync_id>{}</ps:pi_sync_id></d:prop></d:set>\
+         </d:propertyupdate>",
+        PI_SYNC_NS, uid
+    )
+}
+
+fn propfind_xml() -> String {
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\
+         <d:propfind xmlns:d=\"DAV:\" xmlns:ps=\"{}\">\
+         <d:prop><ps:pi_sync_id/></d:prop>\
+         </d:propfind>",
+        PI_SYNC_NS
+    )
+}
+
+///Servers pick their own prefix for our namespace, so match the property on its local name
+fn pi_sync_id_prop(xml: &str) -> Option<String> {
+    PI_SYNC_PROP
+        .captures(xml)
+        .and_then(|c| c.get(1))
+        .map(|m| m.as_str().trim().to_owned())
+        .filter(|v| !v.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cloud_client::CloudClient;
+    use crate::upload_handler::FileOperations;
+    use crate::upload_handler::RootMapping;
+    use crate::webdav_cli::*;
+    use std::collections::HashMap;
+    use std::path::Path;
+    use std::sync::Mutex;
+    use tempfile::tempdir;
+
+    ///Just enough of a WebDAV server: collections must exist before their children,
+    ///MKCOL on an existing collection is a 405, properties are kept per path
+    #[derive(Default)]
+    struct FakeDav {
+        collections: Vec<String>,
+        files: HashMap<String, Vec<u8>>,
+        props: HashMap<String, String>,
+        ///The Content-Type each file was PUT with
+        content_types: HashMap<String, String>,
+    }
+
+    impl FakeDav {
+        ///Everything at or under key, collections with their trailing /
+        fn remove_tree(&mut self, key: &str) -> Vec<(String, Option<Vec<u8>>, Option<String>)> {
+            let under = |p: &str| p == key || p.starts_with(&format!("{}/", key));
+            let mut removed = vec![];
+            for c in self
+                .collections
+                .clone()
+                .iter()
+                .filter(|c| under(c.trim_end_matches('/')))
+            {
+                self.collections.retain(|x| x != c);
+                let prop = self.props.remove(c.trim_end_matches('/'));
+                removed.push((c.clone(), None, prop));
+            }
+            for f in self
+                .files
+                .keys()
+                .filter(|f| under(f))
+                .cloned()
+                .collect::<Vec<_>>()
+            {
+                let content = self.files.remove(&f);
+                let prop = self.props.remove(&f);
+                removed.push((f, content, prop));
+            }
+            removed
+        }
+    }
+
+    fn parent_of(path: &str) -> String {
+        let trimmed = path.trim_end_matches('/');
+        format!("{}/", &trimmed[..trimmed.rfind('/').unwrap_or(0)])
+    }
+
+    fn fake_dav_server() -> (String, Arc<Mutex<FakeDav>>) {
+        let server = tiny_http::Server::http("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/dav/", server.server_addr());
+        let state = Arc::new(Mutex::new(FakeDav {
+            collections: vec!["/dav/".to_owned()],
+            ..Default::default()
+        }));
+        let shared = Arc::clone(&state);
+
+        std::thread::spawn(move || {
+            for mut req in server.incoming_requests() {
+                let mut body = vec![];
+                req.as_reader().read_to_end(&mut body).unwrap();
+                let path = req.url().to_owned();
+                let content_type = req
+                    .headers()
+                    .iter()
+                    .find(|h| h.field.equiv("Content-Type"))
+                    .map(|h| h.value.as_str().to_owned());
+                let mut dav = shared.lock().unwrap();
+                let key = path.trim_end_matches('/').to_owned();
+                let exists =
+                    dav.files.contains_key(&key) || dav.collections.contains(&format!("{}/", key));
+
+                let (status, reply) = match req.method().as_str() {
+                    "MKCOL" if exists => (405, String::new()),
+                    "MKCOL" if !dav.collections.contains(&parent_of(&path)) => (409, String::new()),
+                    "MKCOL" => {
+                        dav.collections.push(format!("{}/", key));
+                        (201, String::new())
+                    }
+                    "PUT" if !dav.collections.contains(&parent_of(&path)) => (409, String::new()),
+                    "PUT" => {
+                        if let Some(content_type) = content_type {
+                            dav.content_types.insert(key.clone(), content_type);
+                        }
+                        dav.files.insert(key, body);
+                        (201, String::new())
+                    }
+                    "PROPPATCH" | "PROPFIND" | "DELETE" | "MOVE" if !exists => (404, String::new()),
+                    "DELETE" => {
+                        dav.remove_tree(&key);
+                        (204, String::new())
+                    }
+                    "MOVE" => {
+                        let destination = req
+                            .headers()
+                            .iter()
+                            .find(|h| h.field.equiv("Destination"))
+                            .map(|h| h.value.as_str().to_owned())
+                            .unwrap();
+                        let to = &destination[destination.find("/dav/").unwrap()..];
+                        if !dav.collections.contains(&parent_of(to)) {
+                            (409, String::new())
+                        } else {
+                            dav.remove_tree(to);
+                            for (from, content, prop) in dav.remove_tree(&key) {
+                                let moved = from.replacen(&key, to, 1);
+                                if let Some(prop) = prop {
+                                    dav.props
+                                        .insert(moved.trim_end_matches('/').to_owned(), prop);
+                                }
+                                match content {
+                                    Some(content) => {
+                                        dav.files.insert(moved, content);
+                                    }
+                                    None => dav.collections.push(moved),
+                                }
+                            }
+                            (201, String::new())
+                        }
+                    }
+                    "PROPPATCH" => {
+                        let uid = pi_sync_id_prop(&String::from_utf8(body).unwrap()).unwrap();
+                        dav.props.insert(key, uid);
+                        (207, "<d:multistatus xmlns:d=\"DAV:\"><d:response><d:propstat><d:status>HTTP/1.1 200 OK</d:status></d:propstat></d:response></d:multistatus>".to_owned())
+                    }
+                    "PROPFIND" => {
+                        let prop = match dav.props.get(&key) {
+                            Some(uid) => format!(
+                                "<x1:pi_sync_id xmlns:x1=\"{}\">{}</x1:pi_sync_id>",
+                                PI_SYNC_NS, uid
+                            ),
+                            None => "<x1:pi_sync_id/>".to_owned(),
+                        };
+                        (207, format!("<d:multistatus xmlns:d=\"DAV:\"><d:response><d:href>{}</d:href><d:propstat><d:prop>{}</d:prop></d:propstat></d:response></d:multistatus>", path, prop))
+                    }
+                    _ => (400, String::new()),
+                };
+                drop(dav);
+                let _ =
+                    req.respond(tiny_http::Response::from_string(reply).with_status_code(status));
+            }
+        });
+        (url, state)
+    }
+
+    fn client(url: &str, local_root: &Path) -> WebDavClient {
+        client_at(url, local_root, "RpiCamera")
+    }
+
+    fn client_at(url: &str, local_root: &Path, remote_root: &str) -> WebDavClient {
+        WebDavClient::new(
+            WebDavOptions::new(url.to_owned(), "pi".to_owned(), Arc::default()),
+            Roots::from(RootMapping::new(
+                local_root.into(),
+                remote_root.into(),
+                vec![],
+            )),
+            Arc::new(StateDb::open_in_memory().unwrap()),
+        )
+    }
+
+    #[test]
+    fn test_webdav_pi_sync_id_prop() {
+        let xml = "<d:prop><oc:pi_sync_id xmlns:oc=\"x\">UnBpQ2FtZXJh</oc:pi_sync_id></d:prop>";
+        assert_eq!(Some("UnBpQ2FtZXJh".to_owned()), pi_sync_id_prop(xml));
+        assert_eq!(
+            Some("abc".to_owned()),
+            pi_sync_id_prop("<pi_sync_id>abc</pi_sync_id>")
+        );
+        assert_eq!(None, pi_sync_id_prop("<d:prop><x1:pi_sync_id/></d:prop>"));
+    }
+
+    #[test]
+    fn test_webdav_create_dir_and_id() {
+        let (url, state) = fake_dav_server();
+        let local = tempdir().unwrap();
+        let dav = client(&url, local.path());
+        let root = local.path().to_str().unwrap().to_owned();
+
+        assert_eq!(None, dav.id(&root).unwrap());
+        let href = dav.create_dir(&root, None).unwrap().unwrap();
+        assert_eq!(format!("{}RpiCamera", url), href);
+        assert_eq!(Some(href.clone()), dav.id(&root).unwrap());
+
+        //existing collections are adopted, not an error
+        assert_eq!(Some(href), dav.create_dir(&root, None).unwrap());
+        assert_eq!(2, state.lock().unwrap().collections.len());
+    }
+
+    #[test]
+    fn test_webdav_create_dir_nested_remote_root() {
+        let (url, state) = fake_dav_server();
+        let local = tempdir().unwrap();
+        let dav = client_at(&url, local.path(), "logs/motion");
+        let root = local.path().to_str().unwrap().to_owned();
+
+        let href = dav.create_dir(&root, None).unwrap().unwrap();
+        assert_eq!(format!("{}logs/motion", url), href);
+        assert_eq!(Some(href), dav.id(&root).unwrap());
+        assert_eq!(
+            vec!["/dav/", "/dav/logs/", "/dav/logs/motion/"],
+            state.lock().unwrap().collections
+        );
+    }
+
+    #[test]
+    fn test_webdav_upload_file() {
+        let (url, state) = fake_dav_server();
+        let root = tempdir().unwrap();
+        let dav = client(&url, root.path());
+
+        let dir = format!("{}/webdav_test/a b", root.path().to_str().unwrap());
+        std::fs::create_dir_all(&dir).unwrap();
+        let local = format!("{}/im1.jpg", dir);
+        std::fs::write(&local, b"not really a jpeg").unwrap();
+
+        let href = dav.upload_file(&local).unwrap().unwrap();
+        assert_eq!(format!("{}RpiCamera/webdav_test/a%20b/im1.jpg", url), href);
+        assert_eq!(Some(href), dav.id(&local).unwrap());
+
+        let state = state.lock().unwrap();
+        assert_eq!(
+            Some(&b"not really a jpeg".to_vec()),
+            state.files.get("/dav/RpiCamera/webdav_test/a%20b/im1.jpg")
+        );
+        assert_eq!(
+            Some(&dav.roots.file(&local).get_unique_id().unwrap()),
+            state.props.get("/dav/RpiCamera/webdav_test/a%20b/im1.jpg")
+        );
+        assert_eq!(
+            Some(&"application/octet-stream".to_owned()),
+            state
+                .content_types
+                .get("/dav/RpiCamera/webdav_test/a%20b/im1.jpg")
+        );
+    }
+
+    #[test]
+    fn test_webdav_delete() {
+        let (url, state) = fake_dav_server();
+        let root = tempdir().unwrap();
+        let dav = client(&url, root.path());
+        let dir = format!("{}/a", root.path().to_str().unwrap());
+        std::fs::create_dir_all(&dir).unwrap();
+        for f in &["im1.jpg", "im2.jpg"] {
+            let local = format!("{}/{}", dir, f);
+            std::fs::write(&local, b"jpeg").unwrap();
+            dav.upload_file(&local).unwrap();
+        }
+
+        //trash moves the whole collection, keeping its place in the tree
+        dav.delete(&dir, true).unwrap();
+        {
+            let state = state.lock().unwrap();
+            assert!(!state.collections.contains(&"/dav/RpiCamera/a/".to_owned()));
+            assert!(state
+                .files
+                .contains_key("/dav/.pi_sync_trash/RpiCamera/a/im2.jpg"));
+        }
+        assert_eq!(None, dav.id(&format!("{}/im1.jpg", dir)).unwrap());
+
+        let local = format!("{}/im3.jpg", dir);
+        std::fs::write(&local, b"jpeg").unwrap();
+        dav.upload_file(&local).unwrap();
+        dav.delete(&local, false).unwrap();
+        assert!(!state
+            .lock()
+            .unwrap()
+            .files
+            .contains_key("/dav/RpiCamera/a/im3.jpg"));
+
+        //never synced is not an error
+        dav.delete(&local, false).unwrap();
+    }
+
+    #[test]
+    fn test_webdav_rename() {
+        let (url, state) = fake_dav_server();
+        let root = tempdir().unwrap();
+        let dav = client(&url, root.path());
+        let dir = format!("{}/a", root.path().to_str().unwrap());
+        std::fs::create_dir_all(&dir).unwrap();
+        let local = format!("{}/im1.jpg", dir);
+        std::fs::write(&local, b"jpeg").unwrap();
+        dav.upload_file(&local).unwrap();
+
+        let moved = format!("{}/b", root.path().to_str().unwrap());
+        std::fs::rename(&dir, &moved).unwrap();
+        let href = dav.rename(&dir, &moved).unwrap().unwrap();
+        assert_eq!(format!("{}RpiCamera/b", url), href);
+
+        //found again by their new pi_sync_ids
+        let moved_file = format!("{}/im1.jpg", moved);
+        assert_eq!(Some(href), dav.id(&moved).unwrap());
+        assert_eq!(
+            Some(format!("{}RpiCamera/b/im1.jpg", url)),
+            dav.id(&moved_file).unwrap()
+        );
+        let state = state.lock().unwrap();
+        assert!(!state.files.contains_key("/dav/RpiCamera/a/im1.jpg"));
+        assert_eq!(
+            Some(&dav.roots.file(&moved_file).get_unique_id().unwrap()),
+            state.props.get("/dav/RpiCamera/b/im1.jpg")
+        );
+    }
+
+    #[test]
+    fn test_webdav_id_untagged_is_not_ours() {
+        let (url, state) = fake_dav_server();
+        let root = tempdir().unwrap();
+        let dav = client(&url, root.path());
+        state
+            .lock()
+            .unwrap()
+            .collections
+            .push("/dav/RpiCamera/".to_owned());
+        assert_eq!(None, dav.id(root.path().to_str().unwrap()).unwrap());
+    }
+}
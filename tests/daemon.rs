@@ -0,0 +1,68 @@
+//!Runs the daemon against the local backend and watches what it does to the target dir
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::time::{Duration, Instant};
+use tempfile::tempdir;
+
+///Killed when dropped, so a failed assert does not leave it running
+struct Daemon(Child);
+
+impl Drop for Daemon {
+    fn drop(&mut self) {
+        let _ = self.0.kill();
+        let _ = self.0.wait();
+    }
+}
+
+fn daemon(watch_dir: &Path, target_dir: &Path, state_db: &Path) -> Daemon {
+    let child = Command::new(env!("CARGO_BIN_EXE_pi_drive_sync"))
+        .args([
+            "--backend",
+            "local",
+            "--remote_root",
+            "RpiCamera",
+            "--quiet_secs",
+            "0",
+        ])
+        .arg("--watch_dir")
+        .arg(watch_dir)
+        .arg("--target_dir")
+        .arg(target_dir)
+        .arg("--state_db")
+        .arg(state_db)
+        .arg("daemon")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    Daemon(child)
+}
+
+fn wait_for(what: &str, done: impl Fn() -> bool) {
+    let give_up = Instant::now() + Duration::from_secs(20);
+    while !done() {
+        assert!(Instant::now() < give_up, "timed out waiting for {}", what);
+        std::thread::sleep(Duration::from_millis(50));
+    }
+}
+
+#[test]
+fn test_daemon_rewrite_in_place() {
+    let dir = tempdir().unwrap();
+    let (watch_dir, target_dir) = (dir.path().join("cam"), dir.path().join("target"));
+    std::fs::create_dir_all(&watch_dir).unwrap();
+    let image = watch_dir.join("im1.jpg");
+    let synced = target_dir.join("RpiCamera/im1.jpg");
+    std::fs::write(&image, b"jpeg").unwrap();
+
+    let _daemon = daemon(&watch_dir, &target_dir, &dir.path().join("state.db"));
+    wait_for("the startup scan", || {
+        std::fs::read(&synced).ok() == Some(b"jpeg".to_vec())
+    });
+
+    //opened and truncated, there is no CREATE for it, only writes and a close
+    std::fs::write(&image, b"jpeg, edited").unwrap();
+    wait_for("the rewrite", || {
+        std::fs::read(&synced).ok() == Some(b"jpeg, edited".to_vec())
+    });
+}